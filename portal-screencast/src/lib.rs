@@ -31,24 +31,35 @@
 
 use bitflags::bitflags;
 use dbus::{
-    arg::{OwnedFd, RefArg, Variant},
+    arg::{OwnedFd, RefArg},
     blocking::{Connection, Proxy},
     channel::Token,
+    message::SignalArgs,
     Message, Path,
 };
 use generated::{
-    OrgFreedesktopPortalRequestResponse, OrgFreedesktopPortalScreenCast,
-    OrgFreedesktopPortalSession,
+    OrgFreedesktopPortalRequest, OrgFreedesktopPortalRequestResponse,
+    OrgFreedesktopPortalScreenCast, OrgFreedesktopPortalSession,
 };
+use log::{debug, trace, warn};
 use std::{
     collections::HashMap,
     convert::TryInto,
     os::unix::prelude::RawFd,
-    sync::mpsc::{self, Receiver},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver},
+        Arc,
+    },
     time::Duration,
 };
 
 mod generated;
+mod options;
+
+pub use options::{
+    CreateSessionOptions, PortalOptions, SelectSourcesOptions, StartOptions, Unsupported,
+};
 
 // - - - - - - - - - - - - - - -  Public Interface - - - - - - - - - - - - - -
 
@@ -62,8 +73,16 @@ pub enum PortalError {
     DBus(dbus::Error),
     /// A problem with deserialising the response to a portal request.
     Parse,
-    /// Cancelled by the user.
+    /// Cancelled by the user, or through a `CancelToken`.
     Cancelled,
+    /// An option was given which the running portal is too old to support.
+    Unsupported {
+        option: &'static str,
+        /// The first version of the ScreenCast interface supporting it.
+        since: u32,
+        /// The version of the ScreenCast interface that is running.
+        version: u32,
+    },
 }
 
 impl std::convert::From<String> for PortalError {
@@ -86,15 +105,39 @@ impl std::fmt::Display for PortalError {
 
 impl std::error::Error for PortalError {}
 
+/// Cancels a `ScreenCast` from another thread, while it waits for the portal
+/// to answer. The portal's dialog is closed, and the waiting call fails with
+/// `PortalError::Cancelled`.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancel any current or future wait for the portal.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
 /// An un-opened screencast session. This can be queried for the supported
 /// capture source types, and used to configure which source types to prompt
 /// for. Each `ScreenCast` can be mde active once by calling `start()`.
 pub struct ScreenCast {
     state: ConnectionState,
     session: String,
+    version: u32,
     multiple: bool,
     source_types: Option<SourceType>,
     cursor_mode: Option<CursorMode>,
+    persist_mode: Option<PersistMode>,
+    restore_token: Option<String>,
 }
 
 impl ScreenCast {
@@ -102,10 +145,17 @@ impl ScreenCast {
     ///
     /// Connects to D-Bus and initaialises a ScreenCast object.
     pub fn new() -> Result<Self, PortalError> {
-        let state = ConnectionState::open_new()?;
+        Self::with_cancel(CancelToken::new())
+    }
+
+    /// Create a new ScreenCast Session which stops waiting for the portal,
+    /// both now and when started, once `cancel` is cancelled.
+    pub fn with_cancel(cancel: CancelToken) -> Result<Self, PortalError> {
+        let state = ConnectionState::open_new(cancel)?;
+        let version = OrgFreedesktopPortalScreenCast::version(&state.desktop_proxy())?;
 
         let session = {
-            let request = Request::with_handler(&state, |a| {
+            let mut request = Request::with_handler(&state, |a| {
                 a.results
                     .get("session_handle")
                     .unwrap()
@@ -114,28 +164,40 @@ impl ScreenCast {
                     .to_owned()
             })?;
             // Make the initail call to open the session.
-            let mut session_args = HashMap::<String, Variant<Box<dyn RefArg>>>::new();
-            session_args.insert(
-                "handle_token".into(),
-                Variant(Box::new(String::from(&request.handle))),
+            let options = CreateSessionOptions {
+                handle_token: Some(request.handle.clone()),
+                session_handle_token: Some(request.handle.clone()),
+            };
+            debug!(
+                "CreateSession: request {0}, portal version {1}",
+                request.handle, version
             );
-            session_args.insert(
-                "session_handle_token".into(),
-                Variant(Box::new(String::from(&request.handle))),
-            );
-            state.desktop_proxy().create_session(session_args)?;
+            let handle = state
+                .desktop_proxy()
+                .create_session(options.to_prop_map(version, Unsupported::Reject)?)?;
+            request.follow(handle);
             request.wait_response()?
         };
+        debug!("Created screen cast session {0}", session);
 
         Ok(ScreenCast {
             state,
             session,
+            version,
             multiple: false,
             source_types: None,
             cursor_mode: None,
+            persist_mode: None,
+            restore_token: None,
         })
     }
 
+    /// Get the version of the ScreenCast interface the portal implements.
+    /// Options needing a later version are dropped when starting the cast.
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
     /// Get the supported source types for this connection
     pub fn source_types(&self) -> Result<SourceType, PortalError> {
         let types = self.state.desktop_proxy().available_source_types()?;
@@ -153,6 +215,19 @@ impl ScreenCast {
         self.cursor_mode = Some(mode);
     }
 
+    /// Set how long the permission to capture the chosen sources should be
+    /// kept by the portal. When persisted a restore token is available from
+    /// `ActiveScreenCast::restore_token()` once the cast has started.
+    pub fn set_persist_mode(&mut self, mode: PersistMode) {
+        self.persist_mode = Some(mode);
+    }
+
+    /// Set a restore token from a previous `ActiveScreenCast`. This allows the
+    /// portal to re-use the previous selection without prompting the user.
+    pub fn set_restore_token(&mut self, token: &str) {
+        self.restore_token = Some(token.into());
+    }
+
     /// Enable multi-stream selection. This allows the user to choose more than
     /// one thing to share. Each will be a separate item in the
     /// `ActiveScreenCast::streams()` iterator.
@@ -166,62 +241,88 @@ impl ScreenCast {
         let desktop_proxy = self.state.desktop_proxy();
 
         {
-            let request = Request::new(&self.state)?;
+            let mut request = Request::new(&self.state)?;
             let session = dbus::Path::from(&self.session);
-            let mut select_args = HashMap::<String, Variant<Box<dyn RefArg>>>::new();
-            select_args.insert(
-                "handle_token".into(),
-                Variant(Box::new(String::from(&request.handle))),
-            );
-            select_args.insert(
-                "types".into(),
-                Variant(Box::new(match self.source_types {
-                    Some(types) => types.bits(),
-                    None => desktop_proxy.available_source_types()?,
-                })),
+            let options = SelectSourcesOptions {
+                handle_token: Some(request.handle.clone()),
+                types: Some(match self.source_types {
+                    Some(types) => types,
+                    None => self.source_types()?,
+                }),
+                multiple: Some(self.multiple),
+                cursor_mode: Some(self.cursor_mode.unwrap_or(CursorMode::HIDDEN)),
+                restore_token: self.restore_token.clone(),
+                persist_mode: self.persist_mode,
+            };
+
+            debug!(
+                "SelectSources: request {0}, types {1:?}, cursor mode {2:?}, multiple {3}, \
+                 persist mode {4:?}, restoring {5}",
+                request.handle,
+                self.source_types,
+                self.cursor_mode,
+                self.multiple,
+                self.persist_mode,
+                self.restore_token.is_some()
             );
-            select_args.insert("multiple".into(), Variant(Box::new(self.multiple)));
-            select_args.insert(
-                "cursor_mode".into(),
-                Variant(Box::new(match self.cursor_mode {
-                    Some(mode) => mode.bits(),
-                    None => CursorMode::HIDDEN.bits(),
-                })),
-            );
-
-            desktop_proxy.select_sources(session, select_args)?;
+            let handle = desktop_proxy.select_sources(
+                session,
+                options.to_prop_map(self.version, Unsupported::Drop)?,
+            )?;
+            request.follow(handle);
             request.wait_response()?;
         }
 
-        let streams = {
-            let request = Request::with_handler(&self.state, |response| {
+        let (streams, restore_token) = {
+            let mut request = Request::with_handler(&self.state, |response| {
                 if response.response != 0 {
                     return Err(PortalError::Cancelled);
                 }
-                match response.results.get("streams") {
-                    Some(streams) => match streams.as_iter() {
-                        Some(streams) => streams
-                            .flat_map(|s| {
-                                s.as_iter()
-                                    .into_iter()
-                                    .flat_map(|t| t.map(|u| u.try_into()))
-                            })
-                            .collect(),
+                let restore_token = response
+                    .results
+                    .get("restore_token")
+                    .and_then(|token| token.as_str())
+                    .map(String::from);
+                let streams: Result<Vec<ScreenCastStream>, PortalError> =
+                    match response.results.get("streams") {
+                        Some(streams) => match streams.as_iter() {
+                            Some(streams) => streams
+                                .flat_map(|s| {
+                                    s.as_iter()
+                                        .into_iter()
+                                        .flat_map(|t| t.map(|u| u.try_into()))
+                                })
+                                .collect(),
+                            None => Err(PortalError::Parse),
+                        },
                         None => Err(PortalError::Parse),
-                    },
-                    None => Err(PortalError::Parse),
-                }
+                    };
+                Ok((streams?, restore_token))
             })?;
             let session = dbus::Path::from(&self.session);
-            let mut select_args = HashMap::<String, Variant<Box<dyn RefArg>>>::new();
-            select_args.insert(
-                "handle_token".into(),
-                Variant(Box::new(String::from(&request.handle))),
-            );
-            desktop_proxy.start(session, parent_window.unwrap_or(""), select_args)?;
+            let options = StartOptions {
+                handle_token: Some(request.handle.clone()),
+            };
+            debug!("Start: request {0}", request.handle);
+            let handle = desktop_proxy.start(
+                session,
+                parent_window.unwrap_or(""),
+                options.to_prop_map(self.version, Unsupported::Reject)?,
+            )?;
+            request.follow(handle);
             request.wait_response()?
         }?;
+        debug!(
+            "Started screen cast with streams {0:?}, restore token {1}",
+            streams,
+            if restore_token.is_some() {
+                "granted"
+            } else {
+                "not granted"
+            }
+        );
 
+        debug!("OpenPipeWireRemote");
         let pipewire_fd =
             desktop_proxy.open_pipe_wire_remote(dbus::Path::from(&self.session), HashMap::new())?;
 
@@ -230,6 +331,7 @@ impl ScreenCast {
             session_path: self.session,
             pipewire_fd,
             streams,
+            restore_token,
         })
     }
 }
@@ -241,6 +343,7 @@ pub struct ActiveScreenCast {
     session_path: String,
     pipewire_fd: OwnedFd,
     streams: Vec<ScreenCastStream>,
+    restore_token: Option<String>,
 }
 
 impl ActiveScreenCast {
@@ -249,14 +352,35 @@ impl ActiveScreenCast {
         self.pipewire_fd.clone().into_fd()
     }
 
+    /// Open a new connection to PipeWire for this session. This can be used to
+    /// reconnect to the cast's streams if the connection from
+    /// `pipewire_fd()` is lost, for example if the PipeWire daemon restarts.
+    /// The caller owns the returned file descriptor.
+    pub fn open_pipewire_remote(&self) -> Result<RawFd, PortalError> {
+        debug!("OpenPipeWireRemote: reconnecting {0}", self.session_path);
+        let fd = self
+            .state
+            .desktop_proxy()
+            .open_pipe_wire_remote(dbus::Path::from(&self.session_path), HashMap::new())?;
+        Ok(fd.into_fd())
+    }
+
     /// Get the streams active in this ScreenCast.
     pub fn streams(&self) -> impl Iterator<Item = &ScreenCastStream> {
         self.streams.iter()
     }
 
+    /// Get the token which can be used to restore this cast's selection in a
+    /// future `ScreenCast`. This is only available if a persist mode was set,
+    /// and the portal supports persistence.
+    pub fn restore_token(&self) -> Option<&str> {
+        self.restore_token.as_deref()
+    }
+
     /// Close the ScreenCast session. This ends the cast.
     pub fn close(&self) -> Result<(), PortalError> {
         // Open a handle to the active session, and close it.
+        debug!("Closing screen cast session {0}", self.session_path);
         let session = Session::open(&self.state, &self.session_path)?;
         session.close()?;
         Ok(())
@@ -265,7 +389,9 @@ impl ActiveScreenCast {
 
 impl std::ops::Drop for ActiveScreenCast {
     fn drop(&mut self) {
-        let _ = self.close();
+        if let Err(err) = self.close() {
+            warn!("Could not close screen cast session: {0}", err);
+        }
     }
 }
 
@@ -373,6 +499,60 @@ bitflags! {
     }
 }
 
+/// Persist Mode
+///
+/// Controls whether the portal remembers the user's selection so that a
+/// later cast can be started with the `restore_token` without prompting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum PersistMode {
+    /// Don't persist the selection (default).
+    DoNot = 0,
+    /// Persist for as long as the application is running.
+    Application = 1,
+    /// Persist until the permission is explicitly revoked.
+    ExplicitlyRevoked = 2,
+}
+
+/// What the ScreenCast portal supports, read from its properties.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortalInfo {
+    /// The version of the ScreenCast interface.
+    pub version: u32,
+    /// The source types which can be captured.
+    pub source_types: SourceType,
+    /// The cursor modes which can be used. These were added in version 2, so
+    /// are `None` for older portals.
+    pub cursor_modes: Option<CursorMode>,
+}
+
+impl PortalInfo {
+    /// Ask the portal what it supports. Unlike `ScreenCast::new` this doesn't
+    /// create a session, so it has no effect on the desktop.
+    pub fn probe() -> Result<Self, PortalError> {
+        let state = ConnectionState::open_new(CancelToken::new())?;
+        let proxy = state.desktop_proxy();
+        let version = OrgFreedesktopPortalScreenCast::version(&proxy)?;
+        let source_types = SourceType::from_bits_truncate(proxy.available_source_types()?);
+        let cursor_modes = if version >= 2 {
+            Some(CursorMode::from_bits_truncate(
+                proxy.available_cursor_modes()?,
+            ))
+        } else {
+            None
+        };
+        debug!(
+            "Portal version {0}: source types {1:?}, cursor modes {2:?}",
+            version, source_types, cursor_modes
+        );
+        Ok(PortalInfo {
+            version,
+            source_types,
+            cursor_modes,
+        })
+    }
+}
+
 // - - - - - - - - - - - - - -  Private Implementation - - - - - - - - - - - -
 
 /// D-Bus connection state. Used to access the Desktop portal
@@ -380,11 +560,13 @@ bitflags! {
 struct ConnectionState {
     connection: Connection,
     sender_token: String,
+    /// Cancels waiting for requests made on this connection.
+    cancel: CancelToken,
 }
 
 impl ConnectionState {
     /// Open a new D-Bus connection to use for all our requests
-    pub fn open_new() -> Result<Self, dbus::Error> {
+    pub fn open_new(cancel: CancelToken) -> Result<Self, dbus::Error> {
         // Create a new session and work out our session's sender token. Portal
         // requests will send responses to paths based on this token.
         let connection = Connection::new_session()?;
@@ -392,6 +574,7 @@ impl ConnectionState {
         Ok(ConnectionState {
             connection,
             sender_token,
+            cancel,
         })
     }
 
@@ -407,15 +590,25 @@ impl ConnectionState {
 
 /// A request object. Portal requests are used to wait for responses to ongoing
 /// portal operations.
+///
+/// Responses to every request from our connection are listened for from the
+/// start, as the portal may answer before telling us which request object it
+/// used. Portals place request objects under a path based on our sender
+/// token, so only that prefix is listened to.
 struct Request<'a, Response> {
     /// A proxy connected to this reuqest object on the bus.
     proxy: Proxy<'a, &'a Connection>,
+    /// Cancels waiting for the response.
+    cancel: CancelToken,
     /// The handle for this request.
     handle: String,
-    /// The channel reciever that we can read responses from.
-    response: Receiver<Response>,
+    /// The channel reciever that we can read responses to any of our
+    /// requests from, along with the path of the request object answering.
+    responses: Receiver<(Path<'static>, OrgFreedesktopPortalRequestResponse)>,
     /// The match token to remove our D-Bus matcher.
     match_token: Token,
+    /// The handler for our request's response.
+    on_response: Box<dyn FnMut(OrgFreedesktopPortalRequestResponse) -> Response + 'a>,
 }
 
 impl<'a> Request<'a, ()> {
@@ -430,56 +623,100 @@ impl<'a, Response> Request<'a, Response> {
     /// Create a new request object with the given connection and handler. This
     /// generates a random token for the handle. The results of the handler can
     /// be retrieved by calling `wait_result()`.
-    pub fn with_handler<ResponseHandler>(
+    pub fn with_handler<Handler>(
         state: &'a ConnectionState,
-        mut on_response: ResponseHandler,
+        on_response: Handler,
     ) -> Result<Self, PortalError>
     where
-        ResponseHandler: FnMut(OrgFreedesktopPortalRequestResponse) -> Response + Send + 'static,
-        Response: Send + 'static,
+        Handler: FnMut(OrgFreedesktopPortalRequestResponse) -> Response + 'a,
     {
         let handle = format!("screencap{0}", rand::random::<usize>());
-        let resp_path = Path::new(format!(
-            "/org/freedesktop/portal/desktop/request/{0}/{1}",
-            state.sender_token, handle
-        ))?;
+        let requests = format!(
+            "/org/freedesktop/portal/desktop/request/{0}",
+            state.sender_token
+        );
+        let resp_path = Path::new(format!("{0}/{1}", requests, handle))?;
         let proxy = state.connection.with_proxy(
             "org.freedesktop.portal.Desktop",
             resp_path,
             Duration::from_secs(20),
         );
-        let (sender, response) = mpsc::channel();
-        let match_token = proxy.match_signal(
-            move |a: OrgFreedesktopPortalRequestResponse, _: &Connection, _: &Message| {
-                // FIXME: handle error responses here somehow? Currently it is
-                //        just up to the `on_response` to deal with it.
-                let res = on_response(a);
-                sender.send(res).is_ok()
+
+        let rule = OrgFreedesktopPortalRequestResponse::match_rule(
+            Some(&"org.freedesktop.portal.Desktop".into()),
+            None,
+        )
+        .static_clone()
+        .with_namespaced_path(Path::new(requests)?);
+        let (sender, responses) = mpsc::channel();
+        let match_token = state.connection.add_match(
+            rule,
+            move |a: OrgFreedesktopPortalRequestResponse, _: &Connection, message: &Message| {
+                match message.path() {
+                    Some(path) => sender.send((path.into_static(), a)).is_ok(),
+                    None => true,
+                }
             },
         )?;
         Ok(Request {
             proxy,
+            cancel: state.cancel.clone(),
             handle,
-            response,
+            responses,
             match_token,
+            on_response: Box::new(on_response),
         })
     }
 
-    pub fn wait_response(&self) -> Result<Response, PortalError> {
+    /// Check the request object `path` returned by the portal method is the
+    /// one we are waiting on. Older portals ignore the `handle_token`, so
+    /// when the path differs we wait on the returned one instead. Any
+    /// response it has already sent is waiting for us.
+    pub fn follow(&mut self, path: Path<'static>) {
+        if path == self.proxy.path {
+            return;
+        }
+        debug!("Request moved from {0} to {1}", self.proxy.path, path);
+        self.proxy = self.proxy.connection.with_proxy(
+            "org.freedesktop.portal.Desktop",
+            path,
+            Duration::from_secs(20),
+        );
+    }
+
+    pub fn wait_response(&mut self) -> Result<Response, PortalError> {
         // Pump the event loop until we receive our expected result
         loop {
-            if let Ok(data) = self.response.try_recv() {
-                return Ok(data);
-            } else {
-                self.proxy.connection.process(Duration::from_millis(100))?;
+            while let Ok((path, a)) = self.responses.try_recv() {
+                if path != self.proxy.path {
+                    debug!("Ignoring response to {0}", path);
+                    continue;
+                }
+                // FIXME: handle error responses here somehow? Currently it is
+                //        just up to the `on_response` to deal with it.
+                debug!(
+                    "Response: {0} with {1} results",
+                    a.response,
+                    a.results.len()
+                );
+                trace!("Response results: {0:?}", a.results);
+                return Ok((self.on_response)(a));
+            }
+            if self.cancel.is_cancelled() {
+                debug!("Cancelling request {0}", self.proxy.path);
+                if let Err(err) = OrgFreedesktopPortalRequest::close(&self.proxy) {
+                    warn!("Could not close request {0}: {1}", self.proxy.path, err);
+                }
+                return Err(PortalError::Cancelled);
             }
+            self.proxy.connection.process(Duration::from_millis(100))?;
         }
     }
 }
 
 impl<'a, T> std::ops::Drop for Request<'a, T> {
     fn drop(&mut self) {
-        let _ = self.proxy.match_stop(self.match_token, true);
+        let _ = self.proxy.connection.remove_match(self.match_token);
     }
 }
 
@@ -500,7 +737,7 @@ impl<'a> Session<'a> {
     }
 
     pub fn close(&self) -> Result<(), PortalError> {
-        self.proxy.close()?;
+        OrgFreedesktopPortalSession::close(&self.proxy)?;
         Ok(())
     }
 }
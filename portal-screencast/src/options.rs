@@ -0,0 +1,199 @@
+//! Typed options for the ScreenCast portal's methods. Each method takes its
+//! options as an `a{sv}` dictionary, and each option was added in some
+//! version of the interface. Portals ignore options they don't know about,
+//! so sending one to an older portal silently does nothing.
+
+use dbus::arg::{PropMap, RefArg, Variant};
+use log::warn;
+
+use crate::{CursorMode, PersistMode, PortalError, SourceType};
+
+/// What to do with options which the portal is too old to understand.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unsupported {
+    /// Leave the option out, and log a warning.
+    Drop,
+    /// Fail with `PortalError::Unsupported`.
+    Reject,
+}
+
+/// The options for a portal method.
+pub trait PortalOptions {
+    /// Each option which has been set, along with the first version of the
+    /// ScreenCast interface which understands it.
+    fn entries(&self) -> Vec<(&'static str, u32, Box<dyn RefArg>)>;
+
+    /// Convert to the dictionary sent to a portal with the given `version`
+    /// of the ScreenCast interface.
+    fn to_prop_map(&self, version: u32, unsupported: Unsupported) -> Result<PropMap, PortalError> {
+        let mut map = PropMap::new();
+        for (option, since, value) in self.entries() {
+            if version < since {
+                match unsupported {
+                    Unsupported::Drop => {
+                        warn!(
+                            "Dropping option {0}, which needs portal version {1} but \
+                             version {2} is running",
+                            option, since, version
+                        );
+                        continue;
+                    }
+                    Unsupported::Reject => {
+                        return Err(PortalError::Unsupported {
+                            option,
+                            since,
+                            version,
+                        })
+                    }
+                }
+            }
+            map.insert(option.into(), Variant(value));
+        }
+        Ok(map)
+    }
+}
+
+/// Options for `CreateSession`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CreateSessionOptions {
+    /// The token the request object's path ends with.
+    pub handle_token: Option<String>,
+    /// The token the session object's path ends with.
+    pub session_handle_token: Option<String>,
+}
+
+impl PortalOptions for CreateSessionOptions {
+    fn entries(&self) -> Vec<(&'static str, u32, Box<dyn RefArg>)> {
+        let mut entries: Vec<(_, _, Box<dyn RefArg>)> = Vec::new();
+        if let Some(token) = &self.handle_token {
+            entries.push(("handle_token", 1, Box::new(token.clone())));
+        }
+        if let Some(token) = &self.session_handle_token {
+            entries.push(("session_handle_token", 1, Box::new(token.clone())));
+        }
+        entries
+    }
+}
+
+/// Options for `SelectSources`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SelectSourcesOptions {
+    /// The token the request object's path ends with.
+    pub handle_token: Option<String>,
+    /// The source types to offer. The portal offers monitors if not set.
+    pub types: Option<SourceType>,
+    /// Allow more than one source to be selected.
+    pub multiple: Option<bool>,
+    /// How the cursor is captured. Needs version 2.
+    pub cursor_mode: Option<CursorMode>,
+    /// A token from a previous session, to restore its selection. Needs
+    /// version 4.
+    pub restore_token: Option<String>,
+    /// How long the selection is remembered. Needs version 4.
+    pub persist_mode: Option<PersistMode>,
+}
+
+impl PortalOptions for SelectSourcesOptions {
+    fn entries(&self) -> Vec<(&'static str, u32, Box<dyn RefArg>)> {
+        let mut entries: Vec<(_, _, Box<dyn RefArg>)> = Vec::new();
+        if let Some(token) = &self.handle_token {
+            entries.push(("handle_token", 1, Box::new(token.clone())));
+        }
+        if let Some(types) = self.types {
+            entries.push(("types", 1, Box::new(types.bits())));
+        }
+        if let Some(multiple) = self.multiple {
+            entries.push(("multiple", 1, Box::new(multiple)));
+        }
+        if let Some(mode) = self.cursor_mode {
+            entries.push(("cursor_mode", 2, Box::new(mode.bits())));
+        }
+        if let Some(token) = &self.restore_token {
+            entries.push(("restore_token", 4, Box::new(token.clone())));
+        }
+        if let Some(mode) = self.persist_mode {
+            entries.push(("persist_mode", 4, Box::new(mode as u32)));
+        }
+        entries
+    }
+}
+
+/// Options for `Start`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StartOptions {
+    /// The token the request object's path ends with.
+    pub handle_token: Option<String>,
+}
+
+impl PortalOptions for StartOptions {
+    fn entries(&self) -> Vec<(&'static str, u32, Box<dyn RefArg>)> {
+        let mut entries: Vec<(_, _, Box<dyn RefArg>)> = Vec::new();
+        if let Some(token) = &self.handle_token {
+            entries.push(("handle_token", 1, Box::new(token.clone())));
+        }
+        entries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn select_sources() -> SelectSourcesOptions {
+        SelectSourcesOptions {
+            handle_token: Some("screencap1".into()),
+            types: Some(SourceType::all()),
+            multiple: Some(true),
+            cursor_mode: Some(CursorMode::EMBEDDED),
+            restore_token: Some("restore".into()),
+            persist_mode: Some(PersistMode::ExplicitlyRevoked),
+        }
+    }
+
+    #[test]
+    fn options_have_their_dbus_types() {
+        let map = select_sources()
+            .to_prop_map(4, Unsupported::Reject)
+            .unwrap();
+        assert_eq!(6, map.len());
+        assert_eq!(Some("screencap1"), map["handle_token"].0.as_str());
+        assert_eq!(Some(3), map["types"].0.as_u64());
+        assert_eq!("u", map["types"].0.signature().to_string());
+        assert_eq!("b", map["multiple"].0.signature().to_string());
+        assert_eq!(Some(2), map["cursor_mode"].0.as_u64());
+        assert_eq!(Some("restore"), map["restore_token"].0.as_str());
+        assert_eq!(Some(2), map["persist_mode"].0.as_u64());
+    }
+
+    #[test]
+    fn unset_options_are_left_out() {
+        let map = SelectSourcesOptions::default()
+            .to_prop_map(4, Unsupported::Reject)
+            .unwrap();
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn unsupported_options_are_dropped() {
+        let map = select_sources().to_prop_map(1, Unsupported::Drop).unwrap();
+        let mut keys: Vec<_> = map.keys().map(String::as_str).collect();
+        keys.sort_unstable();
+        assert_eq!(vec!["handle_token", "multiple", "types"], keys);
+
+        let map = select_sources().to_prop_map(3, Unsupported::Drop).unwrap();
+        assert!(map.contains_key("cursor_mode"));
+        assert!(!map.contains_key("restore_token"));
+    }
+
+    #[test]
+    fn unsupported_options_are_rejected() {
+        match select_sources().to_prop_map(2, Unsupported::Reject) {
+            Err(PortalError::Unsupported {
+                option: "restore_token",
+                since: 4,
+                version: 2,
+            }) => (),
+            other => panic!("unexpected result {0:?}", other),
+        }
+    }
+}
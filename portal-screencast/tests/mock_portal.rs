@@ -0,0 +1,404 @@
+//! Runs screen casts against a mock ScreenCast portal on a private bus.
+//!
+//! By default the mock behaves like older portals which ignore the
+//! `handle_token`, and answer each request on an object path of their own
+//! choosing. It waits a little before answering, as a real portal would while
+//! the user picks what to share. It can instead answer before the method call
+//! returns, or never answer at all.
+
+use dbus::{
+    arg::{OwnedFd, PropMap, RefArg, Variant},
+    blocking::Connection,
+    channel::{Channel, MatchingReceiver, Sender},
+    message::MatchRule,
+    Message, Path,
+};
+use portal_screencast::{CancelToken, PortalError, ScreenCast};
+use std::{
+    env,
+    fs::File,
+    io::{BufRead, BufReader},
+    os::unix::io::IntoRawFd,
+    process::{Child, Command, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+const SESSION_PATH: &str = "/org/freedesktop/portal/desktop/session/mock/session";
+const RESPONSE_DELAY: Duration = Duration::from_millis(100);
+
+/// A private session bus, killed when dropped.
+struct Bus {
+    daemon: Child,
+    address: String,
+}
+
+impl Bus {
+    /// Start a bus, or return `None` if there is no `dbus-daemon` to run.
+    fn start() -> Option<Self> {
+        let mut daemon = Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--print-address=1"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .ok()?;
+        let mut address = String::new();
+        BufReader::new(daemon.stdout.take()?)
+            .read_line(&mut address)
+            .ok()?;
+        Some(Bus {
+            daemon,
+            address: address.trim().into(),
+        })
+    }
+}
+
+impl Drop for Bus {
+    fn drop(&mut self) {
+        let _ = self.daemon.kill();
+        let _ = self.daemon.wait();
+    }
+}
+
+/// What the mock portal saw, and the responses it has still to send.
+#[derive(Default)]
+struct MockState {
+    calls: Vec<(String, PropMap)>,
+    /// The path of each object closed.
+    closed: Vec<String>,
+    pending: Vec<(Instant, Message)>,
+    /// How long to wait before answering requests, or `None` to never
+    /// answer them. Requests answered straight away are answered before the
+    /// method call returns.
+    delay: Option<Duration>,
+    /// Whether to answer on the path the `handle_token` asks for.
+    use_handle_token: bool,
+    requests: u32,
+}
+
+/// A ScreenCast portal answering on `bus` until dropped.
+struct MockPortal {
+    state: Arc<Mutex<MockState>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl MockPortal {
+    fn start(bus: &Bus, version: u32) -> Self {
+        Self::with_delay(bus, version, Some(RESPONSE_DELAY))
+    }
+
+    fn with_delay(bus: &Bus, version: u32, delay: Option<Duration>) -> Self {
+        Self::with_state(
+            bus,
+            version,
+            MockState {
+                delay,
+                ..MockState::default()
+            },
+        )
+    }
+
+    fn with_state(bus: &Bus, version: u32, state: MockState) -> Self {
+        let mut channel = Channel::open_private(&bus.address).unwrap();
+        channel.register().unwrap();
+        let connection = Connection::from(channel);
+        connection
+            .request_name("org.freedesktop.portal.Desktop", false, true, true)
+            .unwrap();
+
+        let state = Arc::new(Mutex::new(state));
+        let stop = Arc::new(AtomicBool::new(false));
+        let call_state = state.clone();
+        connection.start_receive(
+            MatchRule::new_method_call(),
+            Box::new(move |call, connection| {
+                let mut state = call_state.lock().unwrap();
+                let reply = answer(&call, version, &mut state);
+                for response in state.take_due() {
+                    let _ = connection.send(response);
+                }
+                let _ = connection.send(reply);
+                true
+            }),
+        );
+
+        let thread_state = state.clone();
+        let thread_stop = stop.clone();
+        let thread = thread::spawn(move || {
+            while !thread_stop.load(Ordering::SeqCst) {
+                connection.process(Duration::from_millis(10)).unwrap();
+                for response in thread_state.lock().unwrap().take_due() {
+                    let _ = connection.send(response);
+                }
+            }
+        });
+
+        MockPortal {
+            state,
+            stop,
+            thread: Some(thread),
+        }
+    }
+
+    /// The options dictionary of each call to `member`.
+    fn options(&self, member: &str) -> Vec<PropMap> {
+        let state = self.state.lock().unwrap();
+        state
+            .calls
+            .iter()
+            .filter(|(called, _)| called == member)
+            .map(|(_, options)| clone_options(options))
+            .collect()
+    }
+
+    /// The path of each object closed so far.
+    fn closed(&self) -> Vec<String> {
+        self.state.lock().unwrap().closed.clone()
+    }
+}
+
+impl Drop for MockPortal {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl MockState {
+    /// Take the responses which are due to be sent.
+    fn take_due(&mut self) -> Vec<Message> {
+        let now = Instant::now();
+        let (due, pending): (Vec<_>, Vec<_>) =
+            self.pending.drain(..).partition(|(at, _)| *at <= now);
+        self.pending = pending;
+        due.into_iter().map(|(_, response)| response).collect()
+    }
+}
+
+fn clone_options(options: &PropMap) -> PropMap {
+    options
+        .iter()
+        .map(|(key, value)| (key.clone(), Variant(value.0.box_clone())))
+        .collect()
+}
+
+/// Answer a method `call`, queueing a response signal for portal requests.
+fn answer(call: &Message, version: u32, state: &mut MockState) -> Message {
+    let member = call.member().map(|m| m.to_string()).unwrap_or_default();
+    let results = match member.as_str() {
+        "Get" => {
+            let (_, property): (String, String) = call.read2().unwrap();
+            let value: u32 = match property.as_str() {
+                "version" => version,
+                "AvailableSourceTypes" => 0b11,
+                "AvailableCursorModes" => 0b111,
+                _ => 0,
+            };
+            return call.method_return().append1(Variant(value));
+        }
+        "OpenPipeWireRemote" => {
+            let fd = File::open("/dev/null").unwrap().into_raw_fd();
+            return call.method_return().append1(unsafe { OwnedFd::new(fd) });
+        }
+        "Close" => {
+            let path = call.path().map(|path| path.to_string()).unwrap_or_default();
+            state.closed.push(path);
+            return call.method_return();
+        }
+        "CreateSession" => {
+            let options: PropMap = call.read1().unwrap();
+            state.calls.push((member, options));
+            let mut results = PropMap::new();
+            results.insert(
+                "session_handle".into(),
+                Variant(Box::new(String::from(SESSION_PATH))),
+            );
+            results
+        }
+        "SelectSources" => {
+            let (_, options): (Path, PropMap) = call.read2().unwrap();
+            state.calls.push((member, options));
+            PropMap::new()
+        }
+        "Start" => {
+            let (_, _, options): (Path, String, PropMap) = call.read3().unwrap();
+            state.calls.push((member, options));
+            let mut stream = PropMap::new();
+            stream.insert("size".into(), Variant(Box::new((1920, 1080))));
+            let mut results = PropMap::new();
+            results.insert("streams".into(), Variant(Box::new(vec![(42u32, stream)])));
+            results
+        }
+        _ => panic!("unexpected call {0:?}", call),
+    };
+
+    // Unless told to use it, ignore the handle token as portals before it
+    // was added did, but still answer under the caller's sender token.
+    state.requests += 1;
+    let handle_token = state.calls.last().and_then(|(_, options)| token(options));
+    let handle = match handle_token {
+        Some(token) if state.use_handle_token => token,
+        _ => format!("mock{0}", state.requests),
+    };
+    let request = Path::new(format!(
+        "/org/freedesktop/portal/desktop/request/{0}/{1}",
+        sender_token(call),
+        handle
+    ))
+    .unwrap();
+    let response = Message::new_signal(
+        request.to_string(),
+        "org.freedesktop.portal.Request",
+        "Response",
+    )
+    .unwrap()
+    .append2(0u32, results);
+    if let Some(delay) = state.delay {
+        state.pending.push((Instant::now() + delay, response));
+    }
+    call.method_return().append1(request)
+}
+
+/// The `handle_token` in a call's `options`.
+fn token(options: &PropMap) -> Option<String> {
+    options
+        .get("handle_token")
+        .and_then(|token| token.0.as_str())
+        .map(String::from)
+}
+
+/// The sender token of the caller, which request paths are based on.
+fn sender_token(call: &Message) -> String {
+    let sender = call.sender().unwrap();
+    sender[1..].replace('.', "_")
+}
+
+/// Run `f` on another thread, failing if it hasn't finished within a few
+/// seconds. Requests which are never answered would otherwise wait forever.
+fn with_timeout<F, T>(f: F) -> T
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || sender.send(f()));
+    receiver
+        .recv_timeout(Duration::from_secs(10))
+        .expect("timed out waiting for the portal")
+}
+
+/// Each case runs against the same bus, one after the other, as libdbus only
+/// reads the session bus address once per process.
+#[test]
+fn screen_casts_against_a_mock_portal() {
+    let bus = match Bus::start() {
+        Some(bus) => bus,
+        None => {
+            eprintln!("dbus-daemon is not available, skipping");
+            return;
+        }
+    };
+    env::set_var("DBUS_SESSION_BUS_ADDRESS", &bus.address);
+
+    requests_on_unexpected_paths_are_followed(&bus);
+    requests_answered_on_the_predicted_path_are_received(&bus);
+    requests_answered_before_returning_are_received(&bus);
+    waiting_for_the_portal_can_be_cancelled(&bus);
+}
+
+/// Start a screen cast against whichever portal is on the bus, returning the
+/// PipeWire node and size of each stream.
+fn start_screen_cast() -> Vec<(u32, (u32, u32))> {
+    with_timeout(|| {
+        let active = ScreenCast::new()?.start(None)?;
+        let streams: Vec<_> = active
+            .streams()
+            .map(|stream| (stream.pipewire_node(), stream.size()))
+            .collect();
+        Ok::<_, PortalError>(streams)
+    })
+    .unwrap()
+}
+
+fn requests_on_unexpected_paths_are_followed(bus: &Bus) {
+    // Version 3 predates restore tokens, so the one given here is dropped.
+    let portal = MockPortal::start(bus, 3);
+    let streams = with_timeout(|| {
+        let mut screen_cast = ScreenCast::new()?;
+        assert_eq!(3, screen_cast.version());
+        screen_cast.set_restore_token("restore");
+        let active = screen_cast.start(None)?;
+        let streams: Vec<_> = active
+            .streams()
+            .map(|stream| (stream.pipewire_node(), stream.size()))
+            .collect();
+        Ok::<_, portal_screencast::PortalError>(streams)
+    })
+    .unwrap();
+    assert_eq!(vec![(42, (1920, 1080))], streams);
+
+    let create_session = portal.options("CreateSession");
+    assert_eq!(1, create_session.len());
+    assert!(create_session[0].contains_key("handle_token"));
+    assert!(create_session[0].contains_key("session_handle_token"));
+
+    let select_sources = portal.options("SelectSources");
+    assert_eq!(1, select_sources.len());
+    assert_eq!(Some(3), select_sources[0]["types"].0.as_u64());
+    assert_eq!(Some(1), select_sources[0]["cursor_mode"].0.as_u64());
+    assert!(!select_sources[0].contains_key("restore_token"));
+
+    assert_eq!(1, portal.options("Start").len());
+}
+
+fn requests_answered_on_the_predicted_path_are_received(bus: &Bus) {
+    let portal = MockPortal::with_state(
+        bus,
+        4,
+        MockState {
+            delay: Some(RESPONSE_DELAY),
+            use_handle_token: true,
+            ..MockState::default()
+        },
+    );
+    assert_eq!(vec![(42, (1920, 1080))], start_screen_cast());
+    assert_eq!(1, portal.options("Start").len());
+}
+
+fn requests_answered_before_returning_are_received(bus: &Bus) {
+    // The response to each request arrives before we know which request
+    // object it is on.
+    let portal = MockPortal::with_delay(bus, 4, Some(Duration::from_secs(0)));
+    assert_eq!(vec![(42, (1920, 1080))], start_screen_cast());
+    assert_eq!(1, portal.options("Start").len());
+}
+
+fn waiting_for_the_portal_can_be_cancelled(bus: &Bus) {
+    // The portal never answers, as though the user walked away from the
+    // dialog.
+    let portal = MockPortal::with_delay(bus, 4, None);
+    let cancel = CancelToken::new();
+    let cancel_later = cancel.clone();
+    thread::spawn(move || {
+        thread::sleep(RESPONSE_DELAY);
+        cancel_later.cancel();
+    });
+    let result = with_timeout(move || ScreenCast::with_cancel(cancel).map(|_| ()));
+    match result {
+        Err(PortalError::Cancelled) => (),
+        other => panic!("unexpected result {0:?}", other),
+    }
+
+    assert_eq!(1, portal.options("CreateSession").len());
+    let closed = portal.closed();
+    assert_eq!(1, closed.len());
+    assert!(closed[0].starts_with("/org/freedesktop/portal/desktop/request/"));
+    assert!(closed[0].ends_with("/mock1"));
+}
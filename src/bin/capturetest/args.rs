@@ -0,0 +1,394 @@
+//! Command line parsing for `capturetest`.
+
+use obs_portal_screencap::capture::NodeTarget;
+use portal_screencast::{CursorMode, SourceType};
+use std::{fmt, path::PathBuf, time::Duration};
+
+use crate::dump::ImageFormat;
+
+pub const USAGE: &str = "\
+Usage: capturetest [OPTIONS]
+       capturetest list-nodes
+       capturetest probe [--json]
+
+Start a screen cast through the desktop portal and capture it with PipeWire.
+With --node, capture a PipeWire video node directly instead. The list-nodes
+command prints the video nodes which can be captured with --node. The probe
+command prints what the portal supports and which PipeWire is running,
+without starting a screen cast.
+
+Options:
+      --node <ID|NAME>         Capture the PipeWire node with this id or name,
+                               without using the portal. May be repeated
+  -s, --source <TYPE>          Source types to offer: monitor, window, or all
+  -c, --cursor <MODE>          Cursor mode: hidden, embedded, or metadata
+  -m, --multiple               Allow selecting more than one source
+      --persist                Ask the portal to remember the selection
+      --restore-token <TOKEN>  Restore a previously persisted selection
+  -n, --frames <COUNT>         Stop after receiving COUNT frames
+  -d, --duration <SECONDS>     Stop after SECONDS seconds
+      --parent-window <HANDLE> Parent window handle for the portal dialog
+  -o, --output-dir <DIR>       Write captured frames to DIR as images
+      --dump-every <N>         Only write every Nth frame (default 1)
+      --dump-first <COUNT>     Stop writing frames after COUNT images
+      --image-format <FORMAT>  Image format to write: png or ppm (default png)
+  -r, --record <FILE>          Record the raw stream to FILE in Y4M format
+      --10-bit                 Offer, and prefer, 10-bit formats for HDR
+      --json                   Print the results of probe as JSON
+      --stats <SECONDS>        Print capture stats every SECONDS seconds, or
+                               never if 0 (default 5)
+  -v, --verbose                Print more detail, repeat for even more
+  -q, --quiet                  Only print errors
+  -h, --help                   Print this help
+
+Exit status is 0 on success, 2 for invalid arguments, 3 if the user cancelled
+the screen cast, 4 for a portal error, 5 for a PipeWire error, and 6 if frames
+could not be written out.";
+
+/// How much output to produce.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    Quiet,
+    Normal,
+    Verbose,
+    Debug,
+}
+
+/// What `capturetest` has been asked to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    Capture,
+    ListNodes,
+    Probe,
+}
+
+/// The parsed command line.
+#[derive(Debug, PartialEq)]
+pub struct Args {
+    pub command: Command,
+    pub nodes: Vec<NodeTarget>,
+    pub source_types: Option<SourceType>,
+    pub cursor_mode: Option<CursorMode>,
+    pub multiple: bool,
+    pub persist: bool,
+    pub restore_token: Option<String>,
+    pub max_frames: Option<u64>,
+    pub duration: Option<Duration>,
+    pub parent_window: Option<String>,
+    pub output_dir: Option<PathBuf>,
+    pub dump_every: u64,
+    pub dump_first: Option<u64>,
+    pub image_format: ImageFormat,
+    pub record: Option<PathBuf>,
+    pub high_bit_depth: bool,
+    pub stats_interval: Option<Duration>,
+    pub json: bool,
+    pub verbosity: Verbosity,
+}
+
+impl Default for Args {
+    fn default() -> Self {
+        Args {
+            command: Command::Capture,
+            nodes: Vec::new(),
+            source_types: None,
+            cursor_mode: None,
+            multiple: false,
+            persist: false,
+            restore_token: None,
+            max_frames: None,
+            duration: None,
+            parent_window: None,
+            output_dir: None,
+            dump_every: 1,
+            dump_first: None,
+            image_format: ImageFormat::Png,
+            record: None,
+            high_bit_depth: false,
+            stats_interval: Some(Duration::from_secs(5)),
+            json: false,
+            verbosity: Verbosity::Normal,
+        }
+    }
+}
+
+/// Reasons the command line couldn't be parsed.
+#[derive(Debug, PartialEq)]
+pub enum ParseError {
+    /// Help was requested.
+    Help,
+    UnknownOption(String),
+    MissingValue(String),
+    InvalidValue(String, String),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Help => write!(f, "help requested"),
+            ParseError::UnknownOption(opt) => write!(f, "unknown option '{0}'", opt),
+            ParseError::MissingValue(opt) => write!(f, "option '{0}' needs a value", opt),
+            ParseError::InvalidValue(opt, value) => {
+                write!(f, "invalid value '{0}' for option '{1}'", value, opt)
+            }
+        }
+    }
+}
+
+impl Args {
+    /// Parse the arguments, not including the program name.
+    pub fn parse<I>(args: I) -> Result<Self, ParseError>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut parsed = Args::default();
+        let mut args = args.into_iter().peekable();
+
+        match args.peek().map(String::as_str) {
+            Some("list-nodes") => parsed.command = Command::ListNodes,
+            Some("probe") => parsed.command = Command::Probe,
+            _ => (),
+        }
+        if parsed.command != Command::Capture {
+            args.next();
+        }
+
+        while let Some(arg) = args.next() {
+            // Support both `--opt value` and `--opt=value`
+            let (opt, inline_value) = match arg.find('=') {
+                Some(idx) if arg.starts_with("--") => {
+                    (arg[..idx].to_owned(), Some(arg[idx + 1..].to_owned()))
+                }
+                _ => (arg, None),
+            };
+            let mut value = || {
+                inline_value
+                    .clone()
+                    .or_else(|| args.next())
+                    .ok_or_else(|| ParseError::MissingValue(opt.clone()))
+            };
+
+            match opt.as_str() {
+                "-h" | "--help" => return Err(ParseError::Help),
+                "--node" => parsed.nodes.push(NodeTarget::from(value()?.as_str())),
+                "-s" | "--source" => {
+                    let value = value()?;
+                    parsed.source_types = Some(match value.as_str() {
+                        "monitor" => SourceType::MONITOR,
+                        "window" => SourceType::WINDOW,
+                        "all" => SourceType::all(),
+                        _ => return Err(ParseError::InvalidValue(opt, value)),
+                    });
+                }
+                "-c" | "--cursor" => {
+                    let value = value()?;
+                    parsed.cursor_mode = Some(match value.as_str() {
+                        "hidden" => CursorMode::HIDDEN,
+                        "embedded" => CursorMode::EMBEDDED,
+                        "metadata" => CursorMode::METADATA,
+                        _ => return Err(ParseError::InvalidValue(opt, value)),
+                    });
+                }
+                "-m" | "--multiple" => parsed.multiple = true,
+                "--persist" => parsed.persist = true,
+                "--restore-token" => parsed.restore_token = Some(value()?),
+                "-n" | "--frames" => {
+                    let value = value()?;
+                    parsed.max_frames = Some(parse_count(opt, value)?);
+                }
+                "-d" | "--duration" => {
+                    let value = value()?;
+                    parsed.duration = Some(parse_seconds(opt, value)?);
+                }
+                "--parent-window" => parsed.parent_window = Some(value()?),
+                "-o" | "--output-dir" => parsed.output_dir = Some(value()?.into()),
+                "--dump-every" => {
+                    let value = value()?;
+                    parsed.dump_every = match parse_count(opt.clone(), value.clone())? {
+                        0 => return Err(ParseError::InvalidValue(opt, value)),
+                        every => every,
+                    };
+                }
+                "--dump-first" => {
+                    let value = value()?;
+                    parsed.dump_first = Some(parse_count(opt, value)?);
+                }
+                "--image-format" => {
+                    let value = value()?;
+                    parsed.image_format = match value.as_str() {
+                        "png" => ImageFormat::Png,
+                        "ppm" => ImageFormat::Ppm,
+                        _ => return Err(ParseError::InvalidValue(opt, value)),
+                    };
+                }
+                "-r" | "--record" => parsed.record = Some(value()?.into()),
+                "--10-bit" => parsed.high_bit_depth = true,
+                "--stats" => {
+                    let value = value()?;
+                    parsed.stats_interval = match parse_seconds(opt, value)? {
+                        interval if interval == Duration::default() => None,
+                        interval => Some(interval),
+                    };
+                }
+                "--json" => parsed.json = true,
+                "-v" | "--verbose" => {
+                    parsed.verbosity = match parsed.verbosity {
+                        Verbosity::Quiet | Verbosity::Normal => Verbosity::Verbose,
+                        _ => Verbosity::Debug,
+                    }
+                }
+                "-vv" => parsed.verbosity = Verbosity::Debug,
+                "-q" | "--quiet" => parsed.verbosity = Verbosity::Quiet,
+                _ => return Err(ParseError::UnknownOption(opt)),
+            }
+        }
+
+        Ok(parsed)
+    }
+}
+
+/// Parse a non-negative count for the option `opt`.
+fn parse_count(opt: String, value: String) -> Result<u64, ParseError> {
+    value
+        .parse()
+        .map_err(|_| ParseError::InvalidValue(opt, value))
+}
+
+/// Parse a non-negative, possibly fractional, number of seconds for the option
+/// `opt`. Values too large for a `Duration` are invalid.
+fn parse_seconds(opt: String, value: String) -> Result<Duration, ParseError> {
+    value
+        .parse()
+        .ok()
+        .and_then(|s: f64| Duration::try_from_secs_f64(s).ok())
+        .ok_or_else(|| ParseError::InvalidValue(opt, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args, ParseError> {
+        Args::parse(args.iter().map(|a| a.to_string()))
+    }
+
+    #[test]
+    fn no_arguments_gives_defaults() {
+        assert_eq!(Ok(Args::default()), parse(&[]));
+    }
+
+    #[test]
+    fn parse_portal_options() {
+        let args = parse(&[
+            "--source=window",
+            "-c",
+            "embedded",
+            "-m",
+            "--persist",
+            "--restore-token",
+            "abc-123",
+            "--parent-window",
+            "wayland:xyz",
+        ])
+        .unwrap();
+        assert_eq!(Some(SourceType::WINDOW), args.source_types);
+        assert_eq!(Some(CursorMode::EMBEDDED), args.cursor_mode);
+        assert!(args.multiple);
+        assert!(args.persist);
+        assert_eq!(Some("abc-123"), args.restore_token.as_deref());
+        assert_eq!(Some("wayland:xyz"), args.parent_window.as_deref());
+    }
+
+    #[test]
+    fn parse_list_nodes() {
+        assert_eq!(Command::ListNodes, parse(&["list-nodes"]).unwrap().command);
+        assert_eq!(
+            Err(ParseError::UnknownOption("list-nodes".into())),
+            parse(&["-q", "list-nodes"])
+        );
+    }
+
+    #[test]
+    fn parse_probe() {
+        let args = parse(&["probe", "--json"]).unwrap();
+        assert_eq!(Command::Probe, args.command);
+        assert!(args.json);
+        assert!(!parse(&["probe"]).unwrap().json);
+    }
+
+    #[test]
+    fn parse_nodes() {
+        let args = parse(&["--node", "42", "--node=video-test-source"]).unwrap();
+        assert_eq!(
+            vec![
+                NodeTarget::Id(42),
+                NodeTarget::Name("video-test-source".into())
+            ],
+            args.nodes
+        );
+    }
+
+    #[test]
+    fn parse_limits_and_verbosity() {
+        let args = parse(&["-n", "10", "--duration", "2.5", "--stats=1", "-v", "-v"]).unwrap();
+        assert_eq!(Some(10), args.max_frames);
+        assert_eq!(Some(Duration::from_millis(2500)), args.duration);
+        assert_eq!(Some(Duration::from_secs(1)), args.stats_interval);
+        assert_eq!(Verbosity::Debug, args.verbosity);
+        assert_eq!(None, parse(&["--stats", "0"]).unwrap().stats_interval);
+        assert!(parse(&["--10-bit"]).unwrap().high_bit_depth);
+    }
+
+    #[test]
+    fn parse_dump_options() {
+        let args = parse(&[
+            "-o",
+            "/tmp/frames",
+            "--dump-every=5",
+            "--dump-first",
+            "3",
+            "--image-format",
+            "ppm",
+            "--record=out.y4m",
+        ])
+        .unwrap();
+        assert_eq!(Some(PathBuf::from("/tmp/frames")), args.output_dir);
+        assert_eq!(5, args.dump_every);
+        assert_eq!(Some(3), args.dump_first);
+        assert_eq!(ImageFormat::Ppm, args.image_format);
+        assert_eq!(Some(PathBuf::from("out.y4m")), args.record);
+        assert_eq!(
+            Err(ParseError::InvalidValue("--dump-every".into(), "0".into())),
+            parse(&["--dump-every", "0"])
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(Err(ParseError::Help), parse(&["--help"]));
+        assert_eq!(
+            Err(ParseError::UnknownOption("--frobnicate".into())),
+            parse(&["--frobnicate"])
+        );
+        assert_eq!(
+            Err(ParseError::MissingValue("--frames".into())),
+            parse(&["--frames"])
+        );
+        assert_eq!(
+            Err(ParseError::InvalidValue("-s".into(), "desk".into())),
+            parse(&["-s", "desk"])
+        );
+        assert_eq!(
+            Err(ParseError::InvalidValue("-d".into(), "-1".into())),
+            parse(&["-d", "-1"])
+        );
+        assert_eq!(
+            Err(ParseError::InvalidValue("--duration".into(), "1e30".into())),
+            parse(&["--duration", "1e30"])
+        );
+        assert_eq!(
+            Err(ParseError::InvalidValue("-d".into(), "inf".into())),
+            parse(&["-d", "inf"])
+        );
+    }
+}
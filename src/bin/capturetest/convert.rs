@@ -0,0 +1,304 @@
+//! Conversion of captured frames into packed 8-bit RGB, for writing out as
+//! images, and into planar YUV for recording.
+
+use obs_portal_screencap::{capture::Plane, format::VideoFormat};
+
+/// Convert a frame into tightly packed 8-bit RGB. Returns `None` if the planes
+/// are too small for the given format and size.
+pub fn to_rgb(format: VideoFormat, width: u32, height: u32, planes: &[Plane]) -> Option<Vec<u8>> {
+    let (width, height) = (width as usize, height as usize);
+    let mut rgb = Vec::with_capacity(width * height * 3);
+
+    for y in 0..height {
+        for x in 0..width {
+            let pixel = match format {
+                VideoFormat::Rgba | VideoFormat::Rgbx => {
+                    let p = sample(planes.get(0)?, x * 4, y, 3)?;
+                    [p[0], p[1], p[2]]
+                }
+                VideoFormat::Bgra | VideoFormat::Bgrx => {
+                    let p = sample(planes.get(0)?, x * 4, y, 3)?;
+                    [p[2], p[1], p[0]]
+                }
+                VideoFormat::Nv12 => {
+                    let luma = sample(planes.get(0)?, x, y, 1)?[0];
+                    let chroma = sample(planes.get(1)?, (x / 2) * 2, y / 2, 2)?;
+                    yuv_to_rgb(luma, chroma[0], chroma[1])
+                }
+                VideoFormat::I420 => {
+                    let luma = sample(planes.get(0)?, x, y, 1)?[0];
+                    let u = sample(planes.get(1)?, x / 2, y / 2, 1)?[0];
+                    let v = sample(planes.get(2)?, x / 2, y / 2, 1)?[0];
+                    yuv_to_rgb(luma, u, v)
+                }
+                VideoFormat::Yuy2 => {
+                    let p = sample(planes.get(0)?, (x / 2) * 4, y, 4)?;
+                    let luma = if x % 2 == 0 { p[0] } else { p[2] };
+                    yuv_to_rgb(luma, p[1], p[3])
+                }
+                VideoFormat::Xrgb210Le => {
+                    let [r, g, b] = unpack_210(sample(planes.get(0)?, x * 4, y, 4)?);
+                    [r, g, b]
+                }
+                VideoFormat::Xbgr210Le => {
+                    let [b, g, r] = unpack_210(sample(planes.get(0)?, x * 4, y, 4)?);
+                    [r, g, b]
+                }
+            };
+            rgb.extend_from_slice(&pixel);
+        }
+    }
+
+    Some(rgb)
+}
+
+/// Chroma subsampling of planar YUV data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Chroma {
+    C420,
+    C422,
+    C444,
+}
+
+impl Chroma {
+    /// The chroma subsampling we record a given format with. Formats that are
+    /// already YUV keep their subsampling, RGB formats are recorded as 4:4:4.
+    pub fn for_format(format: VideoFormat) -> Self {
+        match format {
+            VideoFormat::Nv12 | VideoFormat::I420 => Chroma::C420,
+            VideoFormat::Yuy2 => Chroma::C422,
+            _ => Chroma::C444,
+        }
+    }
+
+    /// The size of each chroma plane for a frame of the given size.
+    pub fn plane_size(self, width: usize, height: usize) -> (usize, usize) {
+        match self {
+            Chroma::C420 => ((width + 1) / 2, (height + 1) / 2),
+            Chroma::C422 => ((width + 1) / 2, height),
+            Chroma::C444 => (width, height),
+        }
+    }
+}
+
+/// Convert a frame into planar 8-bit YUV with the subsampling given by
+/// `Chroma::for_format`. The Y, U, and V planes are tightly packed one after
+/// the other. Returns `None` if the planes are too small for the given format
+/// and size.
+pub fn to_planar_yuv(
+    format: VideoFormat,
+    width: u32,
+    height: u32,
+    planes: &[Plane],
+) -> Option<Vec<u8>> {
+    let (width, height) = (width as usize, height as usize);
+    let (chroma_width, chroma_height) = Chroma::for_format(format).plane_size(width, height);
+    let mut luma = Vec::with_capacity(width * height);
+    let mut u = Vec::with_capacity(chroma_width * chroma_height);
+    let mut v = Vec::with_capacity(chroma_width * chroma_height);
+
+    match format {
+        VideoFormat::I420 => {
+            for y in 0..height {
+                luma.extend_from_slice(sample(planes.get(0)?, 0, y, width)?);
+            }
+            for y in 0..chroma_height {
+                u.extend_from_slice(sample(planes.get(1)?, 0, y, chroma_width)?);
+                v.extend_from_slice(sample(planes.get(2)?, 0, y, chroma_width)?);
+            }
+        }
+        VideoFormat::Nv12 => {
+            for y in 0..height {
+                luma.extend_from_slice(sample(planes.get(0)?, 0, y, width)?);
+            }
+            for y in 0..chroma_height {
+                let row = sample(planes.get(1)?, 0, y, chroma_width * 2)?;
+                for pair in row.chunks(2) {
+                    u.push(pair[0]);
+                    v.push(pair[1]);
+                }
+            }
+        }
+        VideoFormat::Yuy2 => {
+            for y in 0..height {
+                let row = sample(planes.get(0)?, 0, y, chroma_width * 4)?;
+                for (x, quad) in row.chunks(4).enumerate() {
+                    luma.push(quad[0]);
+                    if x * 2 + 1 < width {
+                        luma.push(quad[2]);
+                    }
+                    u.push(quad[1]);
+                    v.push(quad[3]);
+                }
+            }
+        }
+        _ => {
+            let rgb = to_rgb(format, width as u32, height as u32, planes)?;
+            for pixel in rgb.chunks(3) {
+                let (y, cb, cr) = rgb_to_yuv(pixel[0], pixel[1], pixel[2]);
+                luma.push(y);
+                u.push(cb);
+                v.push(cr);
+            }
+        }
+    }
+
+    luma.extend_from_slice(&u);
+    luma.extend_from_slice(&v);
+    Some(luma)
+}
+
+/// Get `len` bytes at column offset `x` in row `y` of a plane.
+fn sample<'a>(plane: &'a Plane, x: usize, y: usize, len: usize) -> Option<&'a [u8]> {
+    let start = y * plane.stride as usize + x;
+    plane.data.get(start..start + len)
+}
+
+/// Unpack the three 10-bit channels of a little-endian 32-bit word, highest
+/// first, keeping the top 8 bits of each.
+fn unpack_210(word: &[u8]) -> [u8; 3] {
+    let value = u32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+    let channel = |shift: u32| ((value >> shift) & 0x3ff) as u16 >> 2;
+    [channel(20) as u8, channel(10) as u8, channel(0) as u8]
+}
+
+/// Convert a single limited range BT.601 YUV sample to RGB.
+fn yuv_to_rgb(y: u8, u: u8, v: u8) -> [u8; 3] {
+    let c = y as i32 - 16;
+    let d = u as i32 - 128;
+    let e = v as i32 - 128;
+    let clamp = |value: i32| ((value + 128) >> 8).max(0).min(255) as u8;
+    [
+        clamp(298 * c + 409 * e),
+        clamp(298 * c - 100 * d - 208 * e),
+        clamp(298 * c + 516 * d),
+    ]
+}
+
+/// Convert a single RGB sample to limited range BT.601 YUV.
+fn rgb_to_yuv(r: u8, g: u8, b: u8) -> (u8, u8, u8) {
+    let (r, g, b) = (r as i32, g as i32, b as i32);
+    let scale = |value: i32, offset: i32| (((value + 128) >> 8) + offset) as u8;
+    (
+        scale(66 * r + 129 * g + 25 * b, 16),
+        scale(-38 * r - 74 * g + 112 * b, 128),
+        scale(112 * r - 94 * g - 18 * b, 128),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn convert_bgrx_respects_stride() {
+        // 2x2 image with 4 bytes of padding at the end of each row
+        let data = [
+            1, 2, 3, 255, 4, 5, 6, 255, 0, 0, 0, 0, //
+            7, 8, 9, 255, 10, 11, 12, 255, 0, 0, 0, 0,
+        ];
+        let planes = [Plane {
+            data: &data,
+            stride: 12,
+        }];
+        assert_eq!(
+            Some(vec![3, 2, 1, 6, 5, 4, 9, 8, 7, 12, 11, 10]),
+            to_rgb(VideoFormat::Bgrx, 2, 2, &planes)
+        );
+    }
+
+    #[test]
+    fn convert_10_bit_keeps_high_bits() {
+        let xrgb: u32 = (0x3ff << 20) | (0x200 << 10) | 0x004;
+        let data = xrgb.to_le_bytes();
+        let planes = [Plane {
+            data: &data,
+            stride: 4,
+        }];
+        assert_eq!(
+            Some(vec![255, 128, 1]),
+            to_rgb(VideoFormat::Xrgb210Le, 1, 1, &planes)
+        );
+        assert_eq!(
+            Some(vec![1, 128, 255]),
+            to_rgb(VideoFormat::Xbgr210Le, 1, 1, &planes)
+        );
+    }
+
+    #[test]
+    fn convert_nv12_grey() {
+        let luma = [235; 4];
+        let chroma = [128; 2];
+        let planes = [
+            Plane {
+                data: &luma,
+                stride: 2,
+            },
+            Plane {
+                data: &chroma,
+                stride: 2,
+            },
+        ];
+        assert_eq!(
+            Some(vec![255; 12]),
+            to_rgb(VideoFormat::Nv12, 2, 2, &planes)
+        );
+    }
+
+    #[test]
+    fn convert_short_plane_fails() {
+        let data = [0; 6];
+        let planes = [Plane {
+            data: &data,
+            stride: 8,
+        }];
+        assert_eq!(None, to_rgb(VideoFormat::Rgbx, 2, 1, &planes));
+    }
+
+    #[test]
+    fn planar_nv12_is_deinterleaved() {
+        let luma = [1, 2, 3, 4, 0, 0, 5, 6, 7, 8, 0, 0];
+        let chroma = [10, 20, 11, 21, 0, 0];
+        let planes = [
+            Plane {
+                data: &luma,
+                stride: 6,
+            },
+            Plane {
+                data: &chroma,
+                stride: 6,
+            },
+        ];
+        assert_eq!(
+            Some(vec![1, 2, 3, 4, 5, 6, 7, 8, 10, 11, 20, 21]),
+            to_planar_yuv(VideoFormat::Nv12, 4, 2, &planes)
+        );
+    }
+
+    #[test]
+    fn planar_yuy2_is_422() {
+        let data = [1, 10, 2, 20, 3, 11, 4, 21];
+        let planes = [Plane {
+            data: &data,
+            stride: 8,
+        }];
+        assert_eq!(Chroma::C422, Chroma::for_format(VideoFormat::Yuy2));
+        assert_eq!(
+            Some(vec![1, 2, 3, 4, 10, 11, 20, 21]),
+            to_planar_yuv(VideoFormat::Yuy2, 4, 1, &planes)
+        );
+    }
+
+    #[test]
+    fn planar_rgb_white_and_black() {
+        let data = [255, 255, 255, 255, 0, 0, 0, 255];
+        let planes = [Plane {
+            data: &data,
+            stride: 8,
+        }];
+        assert_eq!(
+            Some(vec![235, 16, 128, 128, 128, 128]),
+            to_planar_yuv(VideoFormat::Rgbx, 2, 1, &planes)
+        );
+    }
+}
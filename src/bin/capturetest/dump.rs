@@ -0,0 +1,121 @@
+//! Writing captured frames out as image files.
+
+use obs_portal_screencap::capture::Frame;
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::convert;
+
+/// The type of image file to write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Png,
+    Ppm,
+}
+
+impl ImageFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            ImageFormat::Png => "png",
+            ImageFormat::Ppm => "ppm",
+        }
+    }
+}
+
+/// Writes a selection of frames to a directory.
+pub struct FrameDumper {
+    dir: PathBuf,
+    format: ImageFormat,
+    every: u64,
+    first: Option<u64>,
+    written: u64,
+}
+
+impl FrameDumper {
+    /// Create a dumper which writes every `every`th frame to `dir`, stopping
+    /// once `first` frames have been written.
+    pub fn new(
+        dir: &Path,
+        format: ImageFormat,
+        every: u64,
+        first: Option<u64>,
+    ) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        Ok(FrameDumper {
+            dir: dir.to_owned(),
+            format,
+            every: every.max(1),
+            first,
+            written: 0,
+        })
+    }
+
+    /// Offer a frame to the dumper. The `sequence` is the frame's index within
+    /// its stream and `timestamp` is in nanoseconds. Returns the path written
+    /// to, if this frame was selected.
+    pub fn frame(
+        &mut self,
+        stream: usize,
+        sequence: u64,
+        timestamp: i64,
+        frame: &Frame,
+    ) -> io::Result<Option<PathBuf>> {
+        if sequence % self.every != 0 || self.first.map_or(false, |first| self.written >= first) {
+            return Ok(None);
+        }
+
+        let rgb = convert::to_rgb(
+            frame.format(),
+            frame.width(),
+            frame.height(),
+            frame.planes(),
+        )
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "frame data truncated"))?;
+
+        let path = self.dir.join(format!(
+            "stream{0}-{1:06}-{2}.{3}",
+            stream,
+            sequence,
+            timestamp,
+            self.format.extension()
+        ));
+        let mut file = BufWriter::new(File::create(&path)?);
+        match self.format {
+            ImageFormat::Png => write_png(&mut file, frame.width(), frame.height(), &rgb)?,
+            ImageFormat::Ppm => write_ppm(&mut file, frame.width(), frame.height(), &rgb)?,
+        }
+        file.flush()?;
+
+        self.written += 1;
+        Ok(Some(path))
+    }
+}
+
+fn write_png<W: Write>(out: W, width: u32, height: u32, rgb: &[u8]) -> io::Result<()> {
+    let mut encoder = png::Encoder::new(out, width, height);
+    encoder.set_color(png::ColorType::RGB);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(rgb)?;
+    Ok(())
+}
+
+fn write_ppm<W: Write>(mut out: W, width: u32, height: u32, rgb: &[u8]) -> io::Result<()> {
+    write!(out, "P6\n{0} {1}\n255\n", width, height)?;
+    out.write_all(rgb)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ppm_header() {
+        let mut out = Vec::new();
+        write_ppm(&mut out, 1, 2, &[1, 2, 3, 4, 5, 6]).unwrap();
+        assert_eq!(b"P6\n1 2\n255\n\x01\x02\x03\x04\x05\x06", &out[..]);
+    }
+}
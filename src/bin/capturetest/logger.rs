@@ -0,0 +1,47 @@
+//! A logger writing the diagnostics from `capturetest` and the capture crates
+//! to standard error, filtered by the verbosity given on the command line.
+
+use log::{LevelFilter, Log, Metadata, Record};
+
+use crate::args::Verbosity;
+
+static LOGGER: StderrLogger = StderrLogger;
+
+struct StderrLogger;
+
+/// Install the logger, showing records up to the level for `verbosity`.
+pub fn init(verbosity: Verbosity) {
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(level_filter(verbosity));
+    }
+}
+
+/// Get the most verbose level of record shown at `verbosity`. Warnings are
+/// shown unless asked to be quiet, and everything with `-vv`.
+fn level_filter(verbosity: Verbosity) -> LevelFilter {
+    match verbosity {
+        Verbosity::Quiet => LevelFilter::Error,
+        Verbosity::Normal => LevelFilter::Warn,
+        Verbosity::Verbose => LevelFilter::Debug,
+        Verbosity::Debug => LevelFilter::Trace,
+    }
+}
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!(
+                "{0:<5} [{1}] {2}",
+                record.level(),
+                record.target(),
+                record.args()
+            );
+        }
+    }
+
+    fn flush(&self) {}
+}
@@ -0,0 +1,378 @@
+use args::{Args, Command, ParseError, Verbosity, USAGE};
+use dump::FrameDumper;
+use log::{debug, trace};
+use obs_portal_screencap::{
+    capture::{CaptureLimits, CaptureStream, NodeTarget, StreamStatus},
+    native_shims, registry, runtime,
+};
+use pipewire::{Context, MainLoop};
+use portal_screencast::{PersistMode, PortalError, PortalInfo, ScreenCast};
+use probe::Report;
+use record::Y4mRecorder;
+use std::{
+    cell::{Cell, RefCell},
+    env, fmt, io,
+    os::unix::io::RawFd,
+    process,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+mod args;
+mod convert;
+mod dump;
+mod logger;
+mod probe;
+mod record;
+
+const EXIT_USAGE: i32 = 2;
+const EXIT_CANCELLED: i32 = 3;
+const EXIT_PORTAL: i32 = 4;
+const EXIT_PIPEWIRE: i32 = 5;
+const EXIT_IO: i32 = 6;
+
+/// The reason a capture failed. Each maps to a distinct exit status.
+#[derive(Debug)]
+enum Failure {
+    Portal(PortalError),
+    PipeWire(String),
+    Io(io::Error),
+}
+
+impl Failure {
+    fn exit_code(&self) -> i32 {
+        match self {
+            Failure::Portal(PortalError::Cancelled) => EXIT_CANCELLED,
+            Failure::Portal(_) => EXIT_PORTAL,
+            Failure::PipeWire(_) => EXIT_PIPEWIRE,
+            Failure::Io(_) => EXIT_IO,
+        }
+    }
+}
+
+impl From<PortalError> for Failure {
+    fn from(err: PortalError) -> Self {
+        Failure::Portal(err)
+    }
+}
+
+impl From<pipewire::Error> for Failure {
+    fn from(err: pipewire::Error) -> Self {
+        Failure::PipeWire(err.to_string())
+    }
+}
+
+impl From<io::Error> for Failure {
+    fn from(err: io::Error) -> Self {
+        Failure::Io(err)
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Failure::Portal(PortalError::Cancelled) => write!(f, "screen cast cancelled"),
+            Failure::Portal(err) => write!(f, "{0}", err),
+            Failure::PipeWire(err) => write!(f, "PipeWire error: {0}", err),
+            Failure::Io(err) => write!(f, "could not write output: {0}", err),
+        }
+    }
+}
+
+fn main() {
+    let args = match Args::parse(env::args().skip(1)) {
+        Ok(args) => args,
+        Err(ParseError::Help) => {
+            println!("{0}", USAGE);
+            return;
+        }
+        Err(err) => {
+            eprintln!("capturetest: {0}\n\n{1}", err, USAGE);
+            process::exit(EXIT_USAGE);
+        }
+    };
+    logger::init(args.verbosity);
+
+    let result = match args.command {
+        Command::Capture => run(&args),
+        Command::ListNodes => list_nodes(),
+        Command::Probe => probe(&args),
+    };
+    if let Err(failure) = result {
+        eprintln!("capturetest: {0}", failure);
+        process::exit(failure.exit_code());
+    }
+}
+
+/// # Run the Test Application
+///
+/// We have two main moving parts here. First we make D-Bus calls to obtain a
+/// ScreenCast session and start it. Once we have done that we connect to
+/// the raw video using Pipewire. If nodes were given on the command line we
+/// skip the portal and connect to them directly.
+fn run(args: &Args) -> Result<(), Failure> {
+    if !args.nodes.is_empty() {
+        return with_pipewire(|| capture(args, None, &args.nodes));
+    }
+
+    // - - - - - - - - - - - - - - PORTAL - - - - - - - - - - - - - -
+
+    let mut screen_cast = ScreenCast::new()?;
+    if let Some(types) = args.source_types {
+        screen_cast.set_source_types(types);
+    }
+    if let Some(mode) = args.cursor_mode {
+        screen_cast.set_cursor_mode(mode);
+    }
+    if args.multiple {
+        screen_cast.enable_multiple();
+    }
+    if args.persist {
+        screen_cast.set_persist_mode(PersistMode::ExplicitlyRevoked);
+    }
+    if let Some(token) = &args.restore_token {
+        screen_cast.set_restore_token(token);
+    }
+    let screen_cast = screen_cast.start(args.parent_window.as_deref())?;
+
+    if args.verbosity >= Verbosity::Normal {
+        for stream in screen_cast.streams() {
+            println!(
+                "Stream: node={0} size={1:?}",
+                stream.pipewire_node(),
+                stream.size()
+            );
+        }
+        if let Some(token) = screen_cast.restore_token() {
+            println!("Restore token: {0}", token);
+        }
+    }
+
+    // - - - - - - - - - - - - - - PIPEWIRE - - - - - - - - - - - - - -
+
+    let stream_count = if args.multiple { usize::MAX } else { 1 };
+    let nodes: Vec<_> = screen_cast
+        .streams()
+        .take(stream_count)
+        .map(|stream| NodeTarget::Id(stream.pipewire_node()))
+        .collect();
+    with_pipewire(|| capture(args, Some(screen_cast.pipewire_fd()), &nodes))
+}
+
+/// Print the video nodes in the PipeWire graph which can be captured with
+/// `--node`.
+fn list_nodes() -> Result<(), Failure> {
+    let nodes = with_pipewire(|| {
+        registry::video_nodes(None, Duration::from_secs(5))
+            .map_err(|err| Failure::PipeWire(err.to_string()))
+    })?;
+
+    println!(
+        "{0:>6} {1:>8}  {2:<20} {3:<40} {4}",
+        "ID", "SERIAL", "CLASS", "NAME", "DESCRIPTION"
+    );
+    for node in nodes {
+        println!(
+            "{0:>6} {1:>8}  {2:<20} {3:<40} {4}",
+            node.id,
+            node.serial.map(|s| s.to_string()).unwrap_or_default(),
+            node.media_class,
+            node.name.as_deref().unwrap_or("-"),
+            node.description.as_deref().unwrap_or("")
+        );
+    }
+
+    Ok(())
+}
+
+/// Print what the portal supports and which PipeWire is running. Nothing
+/// here starts a session, so no dialogs are shown.
+fn probe(args: &Args) -> Result<(), Failure> {
+    let report = Report {
+        portal: PortalInfo::probe().map_err(|err| err.to_string()),
+        env: probe::ENV_VARS
+            .iter()
+            .map(|&name| (name, env::var(name).ok()))
+            .collect(),
+        pipewire_library: runtime::library_version(),
+        pipewire_daemon: with_pipewire(|| {
+            registry::daemon_version(None, Duration::from_secs(5))
+                .map_err(|err| Failure::PipeWire(err.to_string()))
+        })
+        .map_err(|err| err.to_string()),
+    };
+
+    if args.json {
+        println!("{0}", report.to_json());
+    } else {
+        print!("{0}", report.to_text());
+    }
+    Ok(())
+}
+
+/// Run `f` with the PipeWire library initialised. Every stream `f` creates
+/// must be gone by the time it returns.
+fn with_pipewire<F, T>(f: F) -> Result<T, Failure>
+where
+    F: FnOnce() -> Result<T, Failure>,
+{
+    runtime::init();
+
+    let result = f();
+
+    runtime::deinit().map_err(|err| Failure::PipeWire(err.to_string()))?;
+
+    result
+}
+
+/// Connect to each of the `nodes` and receive frames until one of the limits
+/// in `args` is reached. If `fd` is given the connection is made through that
+/// PipeWire remote, otherwise to the default PipeWire daemon.
+fn capture(args: &Args, fd: Option<RawFd>, nodes: &[NodeTarget]) -> Result<(), Failure> {
+    let pw_loop = MainLoop::new()?;
+    let pw_context = Context::new(&pw_loop)?;
+    let core = match fd {
+        Some(fd) => pw_context.connect_fd(fd, None)?,
+        None => pw_context.connect(None)?,
+    };
+
+    let verbosity = args.verbosity;
+    let core_error = Rc::new(RefCell::new(None));
+    let listener_error = core_error.clone();
+    let _listener = core
+        .add_listener_local()
+        .info(|i| trace!("Core info: {0:#?}", i))
+        .error(move |e, f, g, h| {
+            *listener_error.borrow_mut() = Some(format!("{0},{1},{2},{3}", e, f, g, h))
+        })
+        .done(|d, e| trace!("Core done: {0},{1}", d, e))
+        .register();
+
+    let dumper = match &args.output_dir {
+        Some(dir) => Some(FrameDumper::new(
+            dir,
+            args.image_format,
+            args.dump_every,
+            args.dump_first,
+        )?),
+        None => None,
+    };
+    let dumper = Rc::new(RefCell::new(dumper));
+    let output_error = Rc::new(RefCell::new(None));
+    let mut recorders = Vec::new();
+
+    let limits = CaptureLimits {
+        high_bit_depth: args.high_bit_depth,
+        ..CaptureLimits::default()
+    };
+    let started = Instant::now();
+    let mut last_stats = started;
+    let frames = Rc::new(Cell::new(0u64));
+    let streams = nodes
+        .iter()
+        .enumerate()
+        .map(|(idx, node)| {
+            let frames = frames.clone();
+            let dumper = dumper.clone();
+            let output_error = output_error.clone();
+            let recorder = Rc::new(RefCell::new(
+                args.record.as_ref().map(|path| Y4mRecorder::new(path, idx)),
+            ));
+            recorders.push(recorder.clone());
+            let mut sequence = 0;
+            CaptureStream::connect(&core, node, limits, move |frame| {
+                frames.set(frames.get() + 1);
+                let timestamp = frame
+                    .pts()
+                    .unwrap_or_else(|| started.elapsed().as_nanos() as i64);
+                if let Some(dumper) = dumper.borrow_mut().as_mut() {
+                    match dumper.frame(idx, sequence, timestamp, frame) {
+                        Ok(Some(path)) if verbosity >= Verbosity::Normal => {
+                            println!("Wrote {0}", path.display())
+                        }
+                        Ok(_) => (),
+                        Err(err) => *output_error.borrow_mut() = Some(err),
+                    }
+                }
+                if let Some(recorder) = recorder.borrow_mut().as_mut() {
+                    match recorder.frame(timestamp, frame) {
+                        Ok(Some(path)) if verbosity >= Verbosity::Normal => {
+                            println!("Recording to {0}", path.display())
+                        }
+                        Ok(_) => (),
+                        Err(err) => *output_error.borrow_mut() = Some(err),
+                    }
+                }
+                sequence += 1;
+                debug!(
+                    "Got frame: stream={0} {1:?} {2}x{3} planes={4:?}",
+                    idx,
+                    frame.format(),
+                    frame.width(),
+                    frame.height(),
+                    frame.planes()
+                );
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut formats = vec![None; streams.len()];
+    loop {
+        if let Some(err) = core_error.borrow_mut().take() {
+            return Err(Failure::PipeWire(err));
+        }
+        for (idx, stream) in streams.iter().enumerate() {
+            if let StreamStatus::Error(err) = stream.status() {
+                return Err(Failure::PipeWire(err));
+            }
+            let format = stream.format();
+            if format != formats[idx] {
+                if let (Some(format), true) = (format, verbosity >= Verbosity::Normal) {
+                    println!(
+                        "Format: stream={0} {1:?} {2}x{3} {4:?}",
+                        idx, format.format, format.width, format.height, format.colorimetry
+                    );
+                }
+                formats[idx] = format;
+            }
+        }
+        if let Some(err) = output_error.borrow_mut().take() {
+            return Err(Failure::Io(err));
+        }
+        if args.max_frames.map_or(false, |max| frames.get() >= max)
+            || args.duration.map_or(false, |max| started.elapsed() >= max)
+        {
+            break;
+        }
+        if let Some(interval) = args.stats_interval {
+            if verbosity >= Verbosity::Normal && last_stats.elapsed() >= interval {
+                for (idx, stream) in streams.iter().enumerate() {
+                    println!("Stats: stream={0} {1}", idx, stream.stats());
+                }
+                last_stats = Instant::now();
+            }
+        }
+        unsafe {
+            native_shims::pw_main_loop_iterate_rs(pw_loop.as_ptr(), 100);
+        }
+    }
+
+    for recorder in recorders {
+        if let Some(recorder) = recorder.borrow_mut().as_mut() {
+            recorder.finish()?;
+        }
+    }
+
+    if verbosity >= Verbosity::Normal {
+        let stats: Vec<_> = streams.iter().map(|s| s.stats()).collect();
+        println!(
+            "Captured {0} frames in {1:.1}s ({2} skipped, {3} dropped)",
+            frames.get(),
+            started.elapsed().as_secs_f64(),
+            stats.iter().map(|s| s.skipped_frames).sum::<u64>(),
+            stats.iter().map(|s| s.dropped_frames).sum::<u64>(),
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,244 @@
+//! Reporting what the desktop offers for screen casting, for the `probe`
+//! command. When nothing happens on a user's machine, this says which portal
+//! answered, what it supports, and which PipeWire is running.
+
+use portal_screencast::{CursorMode, PortalInfo, SourceType};
+use std::fmt::Write;
+
+/// The session environment variables which decide which portal backend is
+/// used, and how to reach the compositor.
+pub const ENV_VARS: &[&str] = &["XDG_CURRENT_DESKTOP", "WAYLAND_DISPLAY"];
+
+/// Everything `probe` found. Each part is probed separately, so that one
+/// failing doesn't hide the rest.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Report {
+    pub portal: Result<PortalInfo, String>,
+    /// Each of `ENV_VARS`, along with its value if it is set.
+    pub env: Vec<(&'static str, Option<String>)>,
+    pub pipewire_library: String,
+    pub pipewire_daemon: Result<String, String>,
+}
+
+impl Report {
+    /// Format the report to be read by a person.
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        let mut line = |label: &str, value: &str| {
+            let _ = writeln!(text, "{0:<21} {1}", format!("{0}:", label), value);
+        };
+
+        match &self.portal {
+            Ok(portal) => {
+                line("Portal version", &portal.version.to_string());
+                line(
+                    "Source types",
+                    &source_type_names(portal.source_types).join(", "),
+                );
+                let cursor_modes = match portal.cursor_modes {
+                    Some(modes) => cursor_mode_names(modes).join(", "),
+                    None => "(needs version 2)".into(),
+                };
+                line("Cursor modes", &cursor_modes);
+            }
+            Err(err) => line("Portal", &format!("unavailable: {0}", err)),
+        }
+        for (name, value) in &self.env {
+            line(name, value.as_deref().unwrap_or("(unset)"));
+        }
+        line("PipeWire library", &self.pipewire_library);
+        match &self.pipewire_daemon {
+            Ok(version) => line("PipeWire daemon", version),
+            Err(err) => line("PipeWire daemon", &format!("unavailable: {0}", err)),
+        }
+
+        text
+    }
+
+    /// Format the report as a JSON object. Anything which couldn't be probed
+    /// is an object with an `error` member, in place of its usual value.
+    pub fn to_json(&self) -> String {
+        let portal = match &self.portal {
+            Ok(portal) => format!(
+                "{{\"version\":{0},\"source_types\":{1},\"cursor_modes\":{2}}}",
+                portal.version,
+                json_strings(&source_type_names(portal.source_types)),
+                portal
+                    .cursor_modes
+                    .map(|modes| json_strings(&cursor_mode_names(modes)))
+                    .unwrap_or_else(|| "null".into())
+            ),
+            Err(err) => json_error(err),
+        };
+        let env: Vec<_> = self
+            .env
+            .iter()
+            .map(|(name, value)| {
+                let value = value.as_deref().map(json_string);
+                format!(
+                    "{0}:{1}",
+                    json_string(name),
+                    value.as_deref().unwrap_or("null")
+                )
+            })
+            .collect();
+        let daemon = match &self.pipewire_daemon {
+            Ok(version) => format!("{{\"version\":{0}}}", json_string(version)),
+            Err(err) => json_error(err),
+        };
+
+        format!(
+            "{{\"portal\":{0},\"env\":{{{1}}},\"pipewire\":{{\"library\":{{\"version\":{2}}},\"daemon\":{3}}}}}",
+            portal,
+            env.join(","),
+            json_string(&self.pipewire_library),
+            daemon
+        )
+    }
+}
+
+/// Get the names of each of the source `types`, as `--source` takes them.
+fn source_type_names(types: SourceType) -> Vec<&'static str> {
+    let names = [
+        (SourceType::MONITOR, "monitor"),
+        (SourceType::WINDOW, "window"),
+    ];
+    names
+        .iter()
+        .filter(|(flag, _)| types.contains(*flag))
+        .map(|(_, name)| *name)
+        .collect()
+}
+
+/// Get the names of each of the cursor `modes`, as `--cursor` takes them.
+fn cursor_mode_names(modes: CursorMode) -> Vec<&'static str> {
+    let names = [
+        (CursorMode::HIDDEN, "hidden"),
+        (CursorMode::EMBEDDED, "embedded"),
+        (CursorMode::METADATA, "metadata"),
+    ];
+    names
+        .iter()
+        .filter(|(flag, _)| modes.contains(*flag))
+        .map(|(_, name)| *name)
+        .collect()
+}
+
+fn json_error(err: &str) -> String {
+    format!("{{\"error\":{0}}}", json_string(err))
+}
+
+fn json_strings(values: &[&str]) -> String {
+    let values: Vec<_> = values.iter().map(|value| json_string(value)).collect();
+    format!("[{0}]", values.join(","))
+}
+
+/// Quote `value` as a JSON string.
+fn json_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c < ' ' => {
+                let _ = write!(quoted, "\\u{0:04x}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report() -> Report {
+        Report {
+            portal: Ok(PortalInfo {
+                version: 4,
+                source_types: SourceType::all(),
+                cursor_modes: Some(CursorMode::HIDDEN | CursorMode::METADATA),
+            }),
+            env: vec![
+                ("XDG_CURRENT_DESKTOP", Some("GNOME".into())),
+                ("WAYLAND_DISPLAY", None),
+            ],
+            pipewire_library: "0.3.65".into(),
+            pipewire_daemon: Ok("0.3.64".into()),
+        }
+    }
+
+    #[test]
+    fn json_strings_are_escaped() {
+        assert_eq!(
+            r#""say \"hi\"\\\n\u0001""#,
+            json_string("say \"hi\"\\\n\u{1}")
+        );
+    }
+
+    #[test]
+    fn report_as_json() {
+        assert_eq!(
+            concat!(
+                r#"{"portal":{"version":4,"source_types":["monitor","window"],"#,
+                r#""cursor_modes":["hidden","metadata"]},"#,
+                r#""env":{"XDG_CURRENT_DESKTOP":"GNOME","WAYLAND_DISPLAY":null},"#,
+                r#""pipewire":{"library":{"version":"0.3.65"},"daemon":{"version":"0.3.64"}}}"#
+            ),
+            report().to_json()
+        );
+    }
+
+    #[test]
+    fn failures_are_reported_in_place() {
+        let report = Report {
+            portal: Err("no portal".into()),
+            pipewire_daemon: Err("timed out".into()),
+            ..report()
+        };
+        assert!(report
+            .to_json()
+            .starts_with(r#"{"portal":{"error":"no portal"},"#));
+        assert!(report
+            .to_json()
+            .ends_with(r#""daemon":{"error":"timed out"}}}"#));
+        assert_eq!(
+            "\
+Portal:               unavailable: no portal
+XDG_CURRENT_DESKTOP:  GNOME
+WAYLAND_DISPLAY:      (unset)
+PipeWire library:     0.3.65
+PipeWire daemon:      unavailable: timed out
+",
+            report.to_text()
+        );
+    }
+
+    #[test]
+    fn old_portals_have_no_cursor_modes() {
+        let report = Report {
+            portal: Ok(PortalInfo {
+                version: 1,
+                source_types: SourceType::MONITOR,
+                cursor_modes: None,
+            }),
+            ..report()
+        };
+        assert!(report.to_text().starts_with(
+            "\
+Portal version:       1
+Source types:         monitor
+Cursor modes:         (needs version 2)
+"
+        ));
+        assert!(report
+            .to_json()
+            .contains(r#""source_types":["monitor"],"cursor_modes":null"#));
+    }
+}
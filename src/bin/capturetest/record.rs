@@ -0,0 +1,235 @@
+//! Recording captured streams to YUV4MPEG2 files.
+//!
+//! Y4M can't change frame size part way through a file, so whenever the
+//! negotiated format changes we start a new segment in a new file. Each frame
+//! carries its PipeWire timestamp as an `Xpts` frame parameter.
+
+use obs_portal_screencap::{
+    capture::Frame,
+    format::{ColorRange, VideoFormat},
+};
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::convert::{self, Chroma};
+
+/// The framerate to write when the stream's framerate is variable.
+const VARIABLE_FRAMERATE: (u32, u32) = (60, 1);
+
+/// The properties of a stream that are fixed for a single Y4M file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct SegmentFormat {
+    format: VideoFormat,
+    width: u32,
+    height: u32,
+    framerate: (u32, u32),
+    /// The range of the YUV written. YUV frames are written as they are, so
+    /// keep the producer's range. RGB frames are converted to limited range.
+    range: ColorRange,
+}
+
+impl SegmentFormat {
+    fn of(frame: &Frame) -> Self {
+        let range = if frame.format().is_yuv() {
+            frame.negotiated().colorimetry.range
+        } else {
+            ColorRange::Limited
+        };
+        SegmentFormat {
+            format: frame.format(),
+            width: frame.width(),
+            height: frame.height(),
+            framerate: frame.framerate(),
+            range,
+        }
+    }
+}
+
+struct Segment {
+    format: SegmentFormat,
+    out: BufWriter<File>,
+}
+
+/// Records the frames from a single stream.
+pub struct Y4mRecorder {
+    base: PathBuf,
+    stream: usize,
+    segments: usize,
+    current: Option<Segment>,
+}
+
+impl Y4mRecorder {
+    /// Create a recorder for the `stream`th stream. Segments are written to
+    /// files named after `base`.
+    pub fn new(base: &Path, stream: usize) -> Self {
+        Y4mRecorder {
+            base: base.to_owned(),
+            stream,
+            segments: 0,
+            current: None,
+        }
+    }
+
+    /// Record a frame, with a `timestamp` in nanoseconds. If the frame starts
+    /// a new segment then the path of the new file is returned.
+    pub fn frame(&mut self, timestamp: i64, frame: &Frame) -> io::Result<Option<PathBuf>> {
+        let format = SegmentFormat::of(frame);
+        let mut new_path = None;
+
+        if self.current.as_ref().map(|s| s.format) != Some(format) {
+            if let Some(mut previous) = self.current.take() {
+                previous.out.flush()?;
+            }
+            let path = segment_path(&self.base, self.stream, self.segments);
+            let mut out = BufWriter::new(File::create(&path)?);
+            write_stream_header(&mut out, format)?;
+            self.current = Some(Segment { format, out });
+            self.segments += 1;
+            new_path = Some(path);
+        }
+
+        let yuv = convert::to_planar_yuv(
+            frame.format(),
+            frame.width(),
+            frame.height(),
+            frame.planes(),
+        )
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "frame data truncated"))?;
+        if let Some(segment) = &mut self.current {
+            write_frame(&mut segment.out, timestamp, &yuv)?;
+        }
+
+        Ok(new_path)
+    }
+
+    /// Flush any buffered data to the current segment.
+    pub fn finish(&mut self) -> io::Result<()> {
+        match &mut self.current {
+            Some(segment) => segment.out.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Get the path for a given stream and segment. The first segment of the
+/// first stream is written to `base` itself, others get a suffix added.
+fn segment_path(base: &Path, stream: usize, segment: usize) -> PathBuf {
+    if stream == 0 && segment == 0 {
+        return base.to_owned();
+    }
+    let stem = base
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let mut name = stem;
+    if stream != 0 {
+        name.push_str(&format!("-stream{0}", stream));
+    }
+    if segment != 0 {
+        name.push_str(&format!(".{0}", segment));
+    }
+    name.push_str(".y4m");
+    base.with_file_name(name)
+}
+
+fn write_stream_header<W: Write>(mut out: W, format: SegmentFormat) -> io::Result<()> {
+    let (num, den) = match format.framerate {
+        (0, _) | (_, 0) => VARIABLE_FRAMERATE,
+        rate => rate,
+    };
+    // PipeWire's 4:2:0 formats site their chroma as MPEG-2 does.
+    let chroma = match Chroma::for_format(format.format) {
+        Chroma::C420 => "420mpeg2",
+        Chroma::C422 => "422",
+        Chroma::C444 => "444",
+    };
+    // Producers which don't say are assumed to be limited range, as is usual
+    // for YUV.
+    let range = match format.range {
+        ColorRange::Full => "FULL",
+        ColorRange::Limited | ColorRange::Unknown => "LIMITED",
+    };
+    writeln!(
+        out,
+        "YUV4MPEG2 W{0} H{1} F{2}:{3} Ip A1:1 C{4} XCOLORRANGE={5}",
+        format.width, format.height, num, den, chroma, range
+    )
+}
+
+fn write_frame<W: Write>(mut out: W, timestamp: i64, yuv: &[u8]) -> io::Result<()> {
+    writeln!(out, "FRAME Xpts={0}", timestamp)?;
+    out.write_all(yuv)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn segment_paths() {
+        let base = Path::new("/tmp/capture.y4m");
+        assert_eq!(PathBuf::from("/tmp/capture.y4m"), segment_path(base, 0, 0));
+        assert_eq!(
+            PathBuf::from("/tmp/capture.2.y4m"),
+            segment_path(base, 0, 2)
+        );
+        assert_eq!(
+            PathBuf::from("/tmp/capture-stream1.y4m"),
+            segment_path(base, 1, 0)
+        );
+        assert_eq!(
+            PathBuf::from("/tmp/capture-stream1.1.y4m"),
+            segment_path(base, 1, 1)
+        );
+    }
+
+    #[test]
+    fn stream_header_for_variable_rate_nv12() {
+        let mut out = Vec::new();
+        write_stream_header(
+            &mut out,
+            SegmentFormat {
+                format: VideoFormat::Nv12,
+                width: 1280,
+                height: 720,
+                framerate: (0, 1),
+                range: ColorRange::Unknown,
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            "YUV4MPEG2 W1280 H720 F60:1 Ip A1:1 C420mpeg2 XCOLORRANGE=LIMITED\n",
+            String::from_utf8(out).unwrap()
+        );
+    }
+
+    #[test]
+    fn stream_header_for_full_range_yuy2() {
+        let mut out = Vec::new();
+        write_stream_header(
+            &mut out,
+            SegmentFormat {
+                format: VideoFormat::Yuy2,
+                width: 640,
+                height: 480,
+                framerate: (30, 1),
+                range: ColorRange::Full,
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            "YUV4MPEG2 W640 H480 F30:1 Ip A1:1 C422 XCOLORRANGE=FULL\n",
+            String::from_utf8(out).unwrap()
+        );
+    }
+
+    #[test]
+    fn frame_has_timestamp() {
+        let mut out = Vec::new();
+        write_frame(&mut out, 1234, &[1, 2, 3]).unwrap();
+        assert_eq!(b"FRAME Xpts=1234\n\x01\x02\x03", &out[..]);
+    }
+}
@@ -0,0 +1,309 @@
+//! Capture of raw audio from a PipeWire node, such as an application's output
+//! stream or an output device. Audio is captured on its own thread, with its
+//! own connection to the default PipeWire daemon, because the remotes handed
+//! out by the portal only expose the screen cast's video nodes.
+
+use log::{debug, error, info, warn};
+use pipewire::{
+    properties,
+    spa::Direction,
+    stream::{Stream, StreamFlags, StreamListener},
+    Context, Core, MainLoop,
+};
+use std::{
+    cell::{Cell, RefCell},
+    error::Error,
+    fmt,
+    rc::Rc,
+    slice,
+    sync::mpsc::{self, Receiver, Sender, TryRecvError},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use super::{
+    frame::{self, FrameError, Mappings},
+    NodeTarget,
+};
+use crate::{
+    format::{AudioFormat, NegotiatedAudio},
+    native_shims, registry,
+    runtime::RuntimeGuard,
+};
+
+/// How long to wait for PipeWire to list its nodes, when finding out what
+/// kind of node audio is being captured from.
+const NODE_LIST_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A buffer of audio received from PipeWire. Like a `Frame`, the sample data
+/// borrows from the PipeWire buffer.
+#[derive(Debug)]
+pub struct AudioFrame<'a> {
+    format: NegotiatedAudio,
+    planes: Vec<&'a [u8]>,
+    frames: u32,
+}
+
+impl<'a> AudioFrame<'a> {
+    /// Build an audio frame from an SPA buffer. Planar formats have one data
+    /// block per channel, interleaved formats a single block.
+    ///
+    /// # Safety
+    ///
+    /// The buffer must be a valid SPA buffer, and must outlive the returned
+    /// frame.
+    pub unsafe fn from_buffer(
+        buffer: &'a libspa_sys::spa_buffer,
+        format: NegotiatedAudio,
+        mappings: &'a mut Mappings,
+    ) -> Result<Self, FrameError> {
+        let plane_count = format.plane_count();
+        if buffer.datas.is_null() || (buffer.n_datas as usize) < plane_count {
+            return Err(FrameError::Truncated);
+        }
+        let datas = slice::from_raw_parts(buffer.datas, plane_count);
+
+        for data in datas {
+            if data.type_ == libspa_sys::spa_data_type_SPA_DATA_MemFd {
+                mappings.map(data)?;
+            }
+        }
+        let mappings: &'a Mappings = mappings;
+
+        let planes = datas
+            .iter()
+            .map(|data| frame::data_bytes(data, mappings).map(|(bytes, _)| bytes))
+            .collect::<Result<Vec<_>, _>>()?;
+        let frames = planes
+            .iter()
+            .map(|plane| plane.len() / format.frame_size())
+            .min()
+            .unwrap_or(0) as u32;
+
+        Ok(AudioFrame {
+            format,
+            planes,
+            frames,
+        })
+    }
+
+    /// The sample format of this audio.
+    pub fn format(&self) -> AudioFormat {
+        self.format.format
+    }
+
+    /// Samples per second.
+    pub fn rate(&self) -> u32 {
+        self.format.rate
+    }
+
+    pub fn channels(&self) -> u32 {
+        self.format.channels
+    }
+
+    /// The number of samples of each channel in this buffer.
+    pub fn frames(&self) -> u32 {
+        self.frames
+    }
+
+    /// How long this buffer takes to play.
+    pub fn duration(&self) -> Duration {
+        Duration::from_nanos(self.frames as u64 * 1_000_000_000 / self.format.rate as u64)
+    }
+
+    /// The sample data. There is one plane per channel for planar formats, or
+    /// a single plane of interleaved samples.
+    pub fn planes(&self) -> &[&'a [u8]] {
+        &self.planes
+    }
+}
+
+/// A PipeWire stream connected to an audio node.
+///
+/// The stream is disconnected when this is dropped.
+pub struct AudioStream {
+    stream: Rc<RefCell<Stream>>,
+    _listener: StreamListener,
+}
+
+impl AudioStream {
+    /// Connect to the given PipeWire `target` node and call `on_audio` with
+    /// each buffer of audio that arrives. If the target is an output device,
+    /// given as `capture_sink`, then we capture what is being played through
+    /// it.
+    pub fn connect<F>(
+        core: &Core,
+        target: &NodeTarget,
+        capture_sink: bool,
+        mut on_audio: F,
+    ) -> Result<Self, pipewire::Error>
+    where
+        F: FnMut(&AudioFrame) + 'static,
+    {
+        let mut props = properties! {
+            "media.type" => "Audio",
+            "media.category" => "Capture"
+        };
+        if capture_sink {
+            props.insert("stream.capture.sink", "true");
+        }
+        let node = super::apply_target(&mut props, target);
+        let stream = Rc::new(RefCell::new(Stream::new(
+            core,
+            "obs-portal-screencap-audio",
+            props,
+        )?));
+
+        let format = Rc::new(Cell::new(None));
+        let mappings = Rc::new(RefCell::new(Mappings::default()));
+        let param_changed_format = format.clone();
+        let param_changed_mappings = mappings.clone();
+        let param_changed_stream = stream.clone();
+        let process_stream = stream.clone();
+
+        let listener = stream
+            .borrow_mut()
+            .add_local_listener()
+            .state_changed(|old, new| debug!("Audio state: {0:?} -> {1:?}", old, new))
+            .param_changed(move |id, param| {
+                if param.is_null() || id != libspa_sys::spa_param_type_SPA_PARAM_Format {
+                    return;
+                }
+
+                let negotiated = unsafe { NegotiatedAudio::from_pod(param) };
+                param_changed_format.set(negotiated);
+                param_changed_mappings.borrow_mut().clear();
+                info!("Audio format: {0:?}", negotiated);
+
+                if let Some(negotiated) = negotiated {
+                    let blocks = negotiated.plane_count() as u32;
+                    let param = unsafe { native_shims::build_stream_param(blocks) };
+                    if let Err(err) = param_changed_stream
+                        .borrow_mut()
+                        .update_params(&mut [param as _])
+                    {
+                        error!("could not update audio stream params: {0}", err);
+                    }
+                }
+            })
+            .process(move || {
+                let mut stream = process_stream.borrow_mut();
+                let buffer = unsafe { stream.dequeue_buffer() };
+                if buffer.is_null() {
+                    return;
+                }
+                if let Some(negotiated) = format.get() {
+                    let mut mappings = mappings.borrow_mut();
+                    let audio = unsafe {
+                        AudioFrame::from_buffer(&*(*buffer).buffer, negotiated, &mut mappings)
+                    };
+                    match audio {
+                        Ok(audio) if audio.frames() > 0 => on_audio(&audio),
+                        Ok(_) => (),
+                        Err(err) => debug!("Skipped audio: {0}", err),
+                    }
+                }
+                unsafe {
+                    stream.queue_buffer(buffer);
+                }
+            })
+            .register()?;
+
+        let param = unsafe { native_shims::build_audio_params() };
+        stream.borrow_mut().connect(
+            Direction::Input,
+            node,
+            StreamFlags::AUTOCONNECT,
+            &mut [param],
+        )?;
+
+        Ok(AudioStream {
+            stream,
+            _listener: listener,
+        })
+    }
+}
+
+impl fmt::Debug for AudioStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AudioStream")
+            .field("stream", &self.stream)
+            .finish()
+    }
+}
+
+/// Handle to an audio capture running on a background thread. The capture is
+/// stopped when this is dropped.
+pub struct AudioHandle {
+    stop: Sender<()>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl std::ops::Drop for AudioHandle {
+    fn drop(&mut self) {
+        let _ = self.stop.send(());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Start capturing audio from the `target` node on a background thread,
+/// calling `on_audio` with each buffer received. PipeWire must have been
+/// initialised with `runtime::init`.
+pub fn spawn_audio<F>(target: NodeTarget, on_audio: F) -> AudioHandle
+where
+    F: FnMut(&AudioFrame) + Send + 'static,
+{
+    let (stop, receiver) = mpsc::channel();
+    let thread = thread::spawn(move || {
+        if let Err(err) = run_audio(receiver, &target, on_audio) {
+            error!("audio capture failed: {0}", err);
+        }
+    });
+
+    AudioHandle {
+        stop,
+        thread: Some(thread),
+    }
+}
+
+fn run_audio<F>(stop: Receiver<()>, target: &NodeTarget, on_audio: F) -> Result<(), Box<dyn Error>>
+where
+    F: FnMut(&AudioFrame) + 'static,
+{
+    let _guard = RuntimeGuard::new()?;
+
+    let capture_sink = is_audio_sink(target);
+    let pw_loop = MainLoop::new()?;
+    let pw_context = Context::new(&pw_loop)?;
+    let core = pw_context.connect(None)?;
+    let _stream = AudioStream::connect(&core, target, capture_sink, on_audio)?;
+
+    loop {
+        match stop.try_recv() {
+            Ok(()) | Err(TryRecvError::Disconnected) => break,
+            Err(TryRecvError::Empty) => unsafe {
+                native_shims::pw_main_loop_iterate_rs(pw_loop.as_ptr(), 100);
+            },
+        }
+    }
+
+    Ok(())
+}
+
+/// Is the `target` an output device? A target which isn't in the graph right
+/// now is assumed to be one, as devices keep their names while application
+/// streams come and go.
+fn is_audio_sink(target: &NodeTarget) -> bool {
+    match registry::audio_nodes(None, NODE_LIST_TIMEOUT) {
+        Ok(nodes) => nodes
+            .iter()
+            .find(|node| node.is_target(target))
+            .map_or(true, |node| node.is_audio_sink()),
+        Err(err) => {
+            warn!("could not list PipeWire audio nodes: {0}", err);
+            true
+        }
+    }
+}
@@ -0,0 +1,491 @@
+//! Access to the video data within PipeWire buffers. We don't ask PipeWire to
+//! map buffers for us, instead each data block is inspected and accessed
+//! according to its type.
+
+use std::{collections::HashMap, fmt, ptr, slice};
+
+use super::Crop;
+use crate::{
+    format::{NegotiatedFormat, VideoFormat},
+    native_shims,
+};
+
+/// A single plane of video data within a frame.
+pub struct Plane<'a> {
+    pub data: &'a [u8],
+    pub stride: u32,
+}
+
+impl<'a> fmt::Debug for Plane<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Plane")
+            .field("len", &self.data.len())
+            .field("stride", &self.stride)
+            .finish()
+    }
+}
+
+/// The reason a buffer couldn't be presented as a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameError {
+    /// The producer marked the buffer's data as corrupted.
+    Corrupted,
+    /// The buffer holds a type of data we can't read from the CPU.
+    UnsupportedData(u32),
+    /// A memfd in the buffer couldn't be mapped.
+    MapFailed,
+    /// The buffer doesn't contain enough data for the negotiated format.
+    Truncated,
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::Corrupted => write!(f, "buffer marked as corrupted"),
+            FrameError::UnsupportedData(ty) => write!(f, "unsupported data type {0}", ty),
+            FrameError::MapFailed => write!(f, "could not map buffer memory"),
+            FrameError::Truncated => write!(f, "buffer too small for format"),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+/// A frame of video received from PipeWire. The plane data borrows from the
+/// PipeWire buffer, so a frame is only valid for the duration of the callback
+/// it is passed to.
+#[derive(Debug)]
+pub struct Frame<'a> {
+    format: NegotiatedFormat,
+    planes: Vec<Plane<'a>>,
+    header: Option<libspa_sys::spa_meta_header>,
+    /// The size of this frame before the node scaled it.
+    source_size: (u32, u32),
+}
+
+impl<'a> Frame<'a> {
+    /// Build a frame from planes of video already in memory, such as frames
+    /// being replayed. The frame has no timestamp or sequence number.
+    pub fn from_planes(format: NegotiatedFormat, planes: Vec<Plane<'a>>) -> Self {
+        Frame {
+            format,
+            planes,
+            header: None,
+            source_size: (format.width, format.height),
+        }
+    }
+
+    /// Build a frame from an SPA buffer. Planes can either be sent as one data
+    /// block each, or packed one after the other in a single block. Any memfd
+    /// blocks are mapped using, and cached in, `mappings`.
+    ///
+    /// # Safety
+    ///
+    /// The buffer must be a valid SPA buffer, and must outlive the returned
+    /// frame.
+    pub unsafe fn from_buffer(
+        buffer: &'a libspa_sys::spa_buffer,
+        format: NegotiatedFormat,
+        mappings: &'a mut Mappings,
+    ) -> Result<Self, FrameError> {
+        if buffer.datas.is_null() || buffer.n_datas == 0 {
+            return Err(FrameError::Truncated);
+        }
+        let datas = slice::from_raw_parts(buffer.datas, buffer.n_datas as usize);
+        let plane_count = format.format.plane_count();
+
+        for data in datas.iter().take(plane_count) {
+            if data.type_ == libspa_sys::spa_data_type_SPA_DATA_MemFd {
+                mappings.map(data)?;
+            }
+        }
+        let mappings: &'a Mappings = mappings;
+
+        let planes = if datas.len() >= plane_count {
+            let mut planes = Vec::with_capacity(plane_count);
+            for (data, layout) in datas.iter().zip(format.planes()) {
+                let (bytes, stride) = data_bytes(data, mappings)?;
+                let stride = if stride == 0 {
+                    // Only the first plane has a well-known default stride,
+                    // subsequent ones we estimate from the data size.
+                    match planes.len() {
+                        0 => format.format.default_stride(format.width),
+                        _ => (bytes.len() / layout.height.max(1) as usize) as u32,
+                    }
+                } else {
+                    stride
+                };
+                // Check the plane holds every row, as `pack_planes` reads
+                // them, so short chunks are skipped rather than delivered.
+                let row = layout.stride as usize;
+                if layout.height > 0
+                    && ((stride as usize) < row
+                        || bytes.len() < stride as usize * (layout.height as usize - 1) + row)
+                {
+                    return Err(FrameError::Truncated);
+                }
+                planes.push(Plane {
+                    data: bytes,
+                    stride,
+                });
+            }
+            planes
+        } else if datas.len() == 1 {
+            let (bytes, stride) = data_bytes(&datas[0], mappings)?;
+            let stride = if stride == 0 {
+                format.format.default_stride(format.width)
+            } else {
+                stride
+            };
+            let mut planes = Vec::with_capacity(plane_count);
+            let mut offset = 0;
+            for layout in format.format.planes(stride, format.height) {
+                let end = offset + layout.size();
+                planes.push(Plane {
+                    data: bytes.get(offset..end).ok_or(FrameError::Truncated)?,
+                    stride: layout.stride,
+                });
+                offset = end;
+            }
+            planes
+        } else {
+            return Err(FrameError::Truncated);
+        };
+
+        let header = native_shims::spa_buffer_find_meta_header_rs(buffer)
+            .as_ref()
+            .copied();
+        if let Some(header) = header {
+            if header.flags & libspa_sys::SPA_META_HEADER_FLAG_CORRUPTED != 0 {
+                return Err(FrameError::Corrupted);
+            }
+        }
+
+        Ok(Frame {
+            format,
+            planes,
+            header,
+            source_size: (format.width, format.height),
+        })
+    }
+
+    /// Record that this frame was scaled down from `size` by the node.
+    pub(super) fn with_source_size(self, size: (u32, u32)) -> Self {
+        Frame {
+            source_size: size,
+            ..self
+        }
+    }
+
+    /// The format of this frame. The size is that of the frame, which is
+    /// smaller than the stream's if it has been cropped.
+    pub fn negotiated(&self) -> NegotiatedFormat {
+        self.format
+    }
+
+    /// The pixel format of this frame.
+    pub fn format(&self) -> VideoFormat {
+        self.format.format
+    }
+
+    pub fn width(&self) -> u32 {
+        self.format.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.format.height
+    }
+
+    /// The size of this frame before it was scaled, which crops are given in
+    /// terms of. This is the frame's own size if it wasn't scaled.
+    pub fn source_size(&self) -> (u32, u32) {
+        self.source_size
+    }
+
+    /// The negotiated framerate as a `(numerator, denominator)` pair. A
+    /// numerator of `0` denotes a variable framerate.
+    pub fn framerate(&self) -> (u32, u32) {
+        self.format.framerate
+    }
+
+    /// The presentation timestamp of this frame in nanoseconds, if the
+    /// producer sent one.
+    pub fn pts(&self) -> Option<i64> {
+        self.header.map(|header| header.pts)
+    }
+
+    /// The producer's sequence number for this frame, if it sent one.
+    pub fn sequence(&self) -> Option<u64> {
+        self.header.map(|header| header.seq)
+    }
+
+    /// Get the planes of this frame. There will be one for each of the planes
+    /// in the frame's `format()`.
+    pub fn planes(&self) -> &[Plane<'a>] {
+        &self.planes
+    }
+
+    /// Narrow this frame to the region left by `crop`. No data is copied,
+    /// the planes are offset to the start of the region and keep their
+    /// strides.
+    pub fn cropped(&self, crop: &Crop) -> Frame<'a> {
+        let format = self.format.format;
+        let size = (self.width(), self.height());
+        let (x, y, width, height) = crop.region(size.0, size.1, format.alignment());
+        let planes = self
+            .planes
+            .iter()
+            .enumerate()
+            .map(|(i, plane)| {
+                let start = format.offset(i, x, y, plane.stride).min(plane.data.len());
+                Plane {
+                    data: &plane.data[start..],
+                    stride: plane.stride,
+                }
+            })
+            .collect();
+
+        let scale =
+            |value: u32, from: u32, to: u32| (value as u64 * to as u64 / from.max(1) as u64) as u32;
+        Frame {
+            format: NegotiatedFormat {
+                width,
+                height,
+                ..self.format
+            },
+            planes,
+            header: self.header,
+            source_size: (
+                scale(width, size.0, self.source_size.0),
+                scale(height, size.1, self.source_size.1),
+            ),
+        }
+    }
+
+    /// Narrow this frame to the region left by `crop`, which is given in
+    /// pixels of the frame's source size.
+    pub fn cropped_from_source(&self, crop: &Crop) -> Frame<'a> {
+        let size = (self.width(), self.height());
+        self.cropped(&crop.scaled(self.source_size, size))
+    }
+}
+
+/// Get the valid bytes within a data block, along with its stride. The valid
+/// region is described by the block's chunk.
+pub(super) unsafe fn data_bytes<'a>(
+    data: &'a libspa_sys::spa_data,
+    mappings: &'a Mappings,
+) -> Result<(&'a [u8], u32), FrameError> {
+    let chunk = data.chunk.as_ref().ok_or(FrameError::Truncated)?;
+    if chunk.flags as u32 & libspa_sys::SPA_CHUNK_FLAG_CORRUPTED != 0 {
+        return Err(FrameError::Corrupted);
+    }
+
+    let base = match data.type_ {
+        libspa_sys::spa_data_type_SPA_DATA_MemPtr => data.data as *const u8,
+        libspa_sys::spa_data_type_SPA_DATA_MemFd => {
+            mappings.get(data).ok_or(FrameError::MapFailed)?
+        }
+        other => return Err(FrameError::UnsupportedData(other)),
+    };
+    if base.is_null() || data.maxsize == 0 {
+        return Err(FrameError::Truncated);
+    }
+
+    let offset = chunk.offset % data.maxsize;
+    let size = chunk.size.min(data.maxsize - offset);
+    let bytes = slice::from_raw_parts(base.add(offset as usize), size as usize);
+    Ok((bytes, chunk.stride.max(0) as u32))
+}
+
+/// A memfd mapped into our address space.
+struct Mapping {
+    ptr: *mut libc::c_void,
+    len: usize,
+    /// Offset from `ptr` to the start of the data. The mapping has to start
+    /// on a page boundary, but the data's `mapoffset` need not.
+    data_offset: usize,
+    maxsize: u32,
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr, self.len);
+        }
+    }
+}
+
+/// Memfd mappings, keyed by file descriptor and offset. The planes of a
+/// buffer usually share one memfd, each at its own `mapoffset`, so each is
+/// mapped separately. Buffers are reused by PipeWire so we keep each mapping
+/// around until its buffer is removed, rather than mapping on each frame.
+#[derive(Default)]
+pub struct Mappings {
+    maps: HashMap<(i64, u32), Mapping>,
+}
+
+impl Mappings {
+    /// Drop all the mappings. Called when buffers are renegotiated.
+    pub fn clear(&mut self) {
+        self.maps.clear();
+    }
+
+    /// Drop the mappings of each memfd in `buffer`. Called as buffers are
+    /// added and removed, as a new buffer can reuse the fd numbers of one
+    /// which has gone.
+    ///
+    /// # Safety
+    ///
+    /// The buffer must be a valid SPA buffer.
+    pub unsafe fn forget_buffer(&mut self, buffer: &libspa_sys::spa_buffer) {
+        if buffer.datas.is_null() {
+            return;
+        }
+        for data in slice::from_raw_parts(buffer.datas, buffer.n_datas as usize) {
+            if data.type_ == libspa_sys::spa_data_type_SPA_DATA_MemFd {
+                self.maps.remove(&(data.fd, data.mapoffset));
+            }
+        }
+    }
+
+    /// Ensure the memfd of `data` is mapped.
+    pub(super) unsafe fn map(&mut self, data: &libspa_sys::spa_data) -> Result<(), FrameError> {
+        let key = (data.fd, data.mapoffset);
+        if let Some(existing) = self.maps.get(&key) {
+            if existing.maxsize == data.maxsize {
+                return Ok(());
+            }
+        }
+
+        let page_size = libc::sysconf(libc::_SC_PAGESIZE).max(1) as u32;
+        let map_start = data.mapoffset - data.mapoffset % page_size;
+        let data_offset = (data.mapoffset - map_start) as usize;
+        let len = data.maxsize as usize + data_offset;
+        let ptr = libc::mmap(
+            ptr::null_mut(),
+            len,
+            libc::PROT_READ,
+            libc::MAP_SHARED,
+            data.fd as libc::c_int,
+            map_start as libc::off_t,
+        );
+        if ptr == libc::MAP_FAILED {
+            self.maps.remove(&key);
+            return Err(FrameError::MapFailed);
+        }
+
+        self.maps.insert(
+            key,
+            Mapping {
+                ptr,
+                len,
+                data_offset,
+                maxsize: data.maxsize,
+            },
+        );
+        Ok(())
+    }
+
+    /// Get a pointer to the start of the data for a mapped memfd.
+    fn get(&self, data: &libspa_sys::spa_data) -> Option<*const u8> {
+        self.maps
+            .get(&(data.fd, data.mapoffset))
+            .map(|mapping| unsafe { (mapping.ptr as *const u8).add(mapping.data_offset) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::Colorimetry;
+    use std::mem;
+
+    /// Describe `size` bytes at `offset` in the memfd `fd`.
+    fn memfd_data(
+        fd: i64,
+        offset: u32,
+        size: u32,
+        chunk: &mut libspa_sys::spa_chunk,
+    ) -> libspa_sys::spa_data {
+        chunk.offset = 0;
+        chunk.size = size;
+        let mut data: libspa_sys::spa_data = unsafe { mem::zeroed() };
+        data.type_ = libspa_sys::spa_data_type_SPA_DATA_MemFd;
+        data.fd = fd;
+        data.mapoffset = offset;
+        data.maxsize = size;
+        data.chunk = chunk;
+        data
+    }
+
+    #[test]
+    fn planes_sharing_a_memfd_are_mapped_separately() {
+        let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u32;
+        let fd = unsafe { libc::memfd_create(b"planes\0".as_ptr() as *const _, 0) };
+        assert!(fd >= 0);
+        let mut contents = vec![1u8; page as usize];
+        contents.extend(vec![2u8; 16]);
+        assert_eq!(contents.len() as isize, unsafe {
+            libc::write(fd, contents.as_ptr() as *const _, contents.len())
+        });
+
+        let mut chunks: [libspa_sys::spa_chunk; 2] = unsafe { mem::zeroed() };
+        let [luma_chunk, chroma_chunk] = &mut chunks;
+        let mut datas = [
+            memfd_data(fd as i64, 0, 16, luma_chunk),
+            memfd_data(fd as i64, page, 16, chroma_chunk),
+        ];
+        let mut mappings = Mappings::default();
+        unsafe {
+            for data in &datas {
+                mappings.map(data).unwrap();
+            }
+            assert_eq!(vec![1; 16], data_bytes(&datas[0], &mappings).unwrap().0);
+            assert_eq!(vec![2; 16], data_bytes(&datas[1], &mappings).unwrap().0);
+
+            let mut buffer: libspa_sys::spa_buffer = mem::zeroed();
+            buffer.n_datas = datas.len() as u32;
+            buffer.datas = datas.as_mut_ptr();
+            mappings.forget_buffer(&buffer);
+            assert!(mappings.maps.is_empty());
+            libc::close(fd);
+        }
+    }
+
+    #[test]
+    fn short_planes_are_truncated() {
+        let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u32;
+        let fd = unsafe { libc::memfd_create(b"short\0".as_ptr() as *const _, 0) };
+        assert!(fd >= 0);
+        let contents = vec![0u8; page as usize + 4];
+        assert_eq!(contents.len() as isize, unsafe {
+            libc::write(fd, contents.as_ptr() as *const _, contents.len())
+        });
+
+        // A 4x2 NV12 frame needs a 4 byte row of chroma, but gets 2 bytes.
+        let format = NegotiatedFormat {
+            format: VideoFormat::Nv12,
+            width: 4,
+            height: 2,
+            framerate: (30, 1),
+            colorimetry: Colorimetry::default(),
+        };
+        let mut chunks: [libspa_sys::spa_chunk; 2] = unsafe { mem::zeroed() };
+        let [luma_chunk, chroma_chunk] = &mut chunks;
+        let mut datas = [
+            memfd_data(fd as i64, 0, 8, luma_chunk),
+            memfd_data(fd as i64, page, 4, chroma_chunk),
+        ];
+        luma_chunk.stride = 4;
+        chroma_chunk.stride = 4;
+        chroma_chunk.size = 2;
+        let mut buffer: libspa_sys::spa_buffer = unsafe { mem::zeroed() };
+        buffer.n_datas = datas.len() as u32;
+        buffer.datas = datas.as_mut_ptr();
+        let mut mappings = Mappings::default();
+        let frame = unsafe { Frame::from_buffer(&buffer, format, &mut mappings) };
+        assert!(matches!(frame, Err(FrameError::Truncated)));
+        unsafe { libc::close(fd) };
+    }
+}
@@ -0,0 +1,236 @@
+//! A latest-frame-wins handoff between two threads. Frames arrive on the
+//! PipeWire thread, but are consumed on another, such as OBS's graphics
+//! thread, and neither side should wait on the other.
+//!
+//! This is a triple buffer: the writer fills a back slot while the reader
+//! holds a front slot, and a middle slot is swapped atomically between them.
+//! Publishing a frame the reader hasn't taken yet replaces it, and counts it
+//! as superseded.
+
+use log::warn;
+use std::{
+    cell::UnsafeCell,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use super::{
+    ratelimit::{RateLimit, LOG_INTERVAL},
+    sink::{FrameSink, OwnedFrame},
+    stats, Frame, FrameError,
+};
+
+/// Set in the shared state when the middle slot holds a frame which hasn't
+/// been taken. The rest of the state is the middle slot's index.
+const FRESH: usize = 0b100;
+const INDEX: usize = 0b011;
+
+struct Shared<T> {
+    slots: [UnsafeCell<Option<T>>; 3],
+    middle: AtomicUsize,
+    superseded: AtomicU64,
+}
+
+// Each slot is only ever accessed by whichever side currently owns its index,
+// and ownership only changes hands through `middle`.
+unsafe impl<T: Send> Sync for Shared<T> {}
+
+/// Create a mailbox, returning its two ends.
+pub fn mailbox<T: Send>() -> (MailboxWriter<T>, MailboxReader<T>) {
+    let shared = Arc::new(Shared {
+        slots: [
+            UnsafeCell::new(None),
+            UnsafeCell::new(None),
+            UnsafeCell::new(None),
+        ],
+        middle: AtomicUsize::new(1),
+        superseded: AtomicU64::new(0),
+    });
+    let writer = MailboxWriter {
+        shared: shared.clone(),
+        back: 0,
+    };
+    let reader = MailboxReader { shared, front: 2 };
+    (writer, reader)
+}
+
+/// The end of a mailbox frames are published to.
+pub struct MailboxWriter<T> {
+    shared: Arc<Shared<T>>,
+    back: usize,
+}
+
+impl<T> MailboxWriter<T> {
+    /// Fill the back slot with `fill`, and publish it if that succeeds. The
+    /// slot holds whatever was last written to it, if anything, so its
+    /// allocations can be reused.
+    pub fn write<F, E>(&mut self, fill: F) -> Result<(), E>
+    where
+        F: FnOnce(&mut Option<T>) -> Result<(), E>,
+    {
+        let slot = unsafe { &mut *self.shared.slots[self.back].get() };
+        fill(slot)?;
+
+        let previous = self.shared.middle.swap(self.back | FRESH, Ordering::AcqRel);
+        self.back = previous & INDEX;
+        if previous & FRESH != 0 {
+            self.shared.superseded.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+}
+
+/// The end of a mailbox frames are taken from.
+pub struct MailboxReader<T> {
+    shared: Arc<Shared<T>>,
+    front: usize,
+}
+
+impl<T> MailboxReader<T> {
+    /// Take the most recently published frame, if one has been published
+    /// since the last call. The frame is complete, and stays valid until the
+    /// next call.
+    pub fn take(&mut self) -> Option<&T> {
+        if self.shared.middle.load(Ordering::Acquire) & FRESH == 0 {
+            return None;
+        }
+        let previous = self.shared.middle.swap(self.front, Ordering::AcqRel);
+        self.front = previous & INDEX;
+        unsafe { &*self.shared.slots[self.front].get() }.as_ref()
+    }
+
+    /// The number of frames replaced before they were taken.
+    pub fn superseded(&self) -> u64 {
+        self.shared.superseded.load(Ordering::Relaxed)
+    }
+}
+
+/// A frame copied into a mailbox, along with when it arrived on the
+/// monotonic clock in nanoseconds.
+#[derive(Debug, Clone)]
+pub struct ReceivedFrame {
+    pub frame: OwnedFrame,
+    pub received: i64,
+}
+
+/// A sink copying each frame into a mailbox. Once the mailbox's slots have
+/// been filled, copying reuses their buffers rather than allocating.
+pub struct MailboxSink {
+    writer: MailboxWriter<ReceivedFrame>,
+    copy_failures: RateLimit,
+}
+
+impl MailboxSink {
+    /// Create a sink, and the reader for its mailbox.
+    pub fn new() -> (Self, MailboxReader<ReceivedFrame>) {
+        let (writer, reader) = mailbox();
+        (
+            MailboxSink {
+                writer,
+                copy_failures: RateLimit::new(LOG_INTERVAL),
+            },
+            reader,
+        )
+    }
+}
+
+impl FrameSink for MailboxSink {
+    fn frame(&mut self, frame: &Frame) {
+        let received = stats::monotonic_now();
+        let copied = self.writer.write(|slot| -> Result<(), FrameError> {
+            match slot {
+                Some(latest) => {
+                    latest.frame.copy_from(frame)?;
+                    latest.received = received;
+                }
+                None => {
+                    *slot = Some(ReceivedFrame {
+                        frame: OwnedFrame::copy(frame)?,
+                        received,
+                    })
+                }
+            }
+            Ok(())
+        });
+        if let Err(err) = copied {
+            if let Some(suppressed) = self.copy_failures.allow(Instant::now()) {
+                warn!(
+                    "could not copy frame: {0} ({1} more not logged)",
+                    err, suppressed
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn publish(writer: &mut MailboxWriter<u32>, value: u32) {
+        writer
+            .write(|slot| -> Result<(), ()> {
+                *slot = Some(value);
+                Ok(())
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn reader_sees_latest_frame_once() {
+        let (mut writer, mut reader) = mailbox();
+        assert_eq!(None, reader.take());
+
+        publish(&mut writer, 1);
+        publish(&mut writer, 2);
+        publish(&mut writer, 3);
+        assert_eq!(Some(&3), reader.take());
+        assert_eq!(None, reader.take());
+        assert_eq!(2, reader.superseded());
+
+        publish(&mut writer, 4);
+        assert_eq!(Some(&4), reader.take());
+        assert_eq!(2, reader.superseded());
+    }
+
+    #[test]
+    fn failed_writes_are_not_published() {
+        let (mut writer, mut reader) = mailbox::<u32>();
+        assert_eq!(Err(()), writer.write(|_| Err(())));
+        assert_eq!(None, reader.take());
+    }
+
+    #[test]
+    fn frames_are_never_torn() {
+        let (mut writer, mut reader) = mailbox::<Vec<u32>>();
+        let writes = thread::spawn(move || {
+            for i in 0..10_000 {
+                writer
+                    .write(|slot| -> Result<(), ()> {
+                        let frame = slot.get_or_insert_with(Vec::new);
+                        frame.clear();
+                        frame.extend(std::iter::repeat(i).take(64));
+                        Ok(())
+                    })
+                    .unwrap();
+            }
+        });
+
+        let mut taken = 0;
+        let mut last = None;
+        while last != Some(9_999) {
+            if let Some(frame) = reader.take() {
+                assert!(frame.iter().all(|&value| value == frame[0]));
+                assert!(last.map_or(true, |last| frame[0] > last));
+                last = Some(frame[0]);
+                taken += 1;
+            }
+        }
+        writes.join().unwrap();
+        assert_eq!(10_000, taken + reader.superseded());
+    }
+}
@@ -0,0 +1,744 @@
+//! Capture of raw video from a PipeWire node. The `CaptureStream` handles
+//! negotiating a format with the node and presents each buffer it receives as
+//! a `Frame`. The `spawn` function runs a whole capture on a background
+//! thread, either of a portal screen cast or of a node chosen directly, and
+//! feeds its frames to any number of `FrameSink`s.
+//! Audio can be captured alongside with `spawn_audio`, and video published
+//! to other PipeWire clients with `spawn_output`.
+
+use log::{debug, error, info};
+use pipewire::{
+    properties,
+    spa::Direction,
+    stream::{Stream, StreamFlags, StreamListener, StreamState},
+    Core, Properties,
+};
+use std::{
+    cell::{Cell, RefCell},
+    fmt,
+    os::unix::io::RawFd,
+    rc::Rc,
+    time::Instant,
+};
+
+use crate::{format::NegotiatedFormat, native_shims};
+
+mod audio;
+mod frame;
+mod mailbox;
+mod output;
+mod ratelimit;
+mod recovery;
+mod runner;
+mod sink;
+mod stats;
+
+pub use audio::{spawn_audio, AudioFrame, AudioHandle, AudioStream};
+pub use frame::{Frame, FrameError, Mappings, Plane};
+pub use mailbox::{mailbox, MailboxReader, MailboxSink, MailboxWriter, ReceivedFrame};
+pub use output::{pack_planes, spawn_output, OutputHandle, OutputStream};
+pub use ratelimit::{RateLimit, LOG_INTERVAL};
+pub use recovery::CaptureEvent;
+pub use runner::{spawn, CaptureHandle};
+pub use sink::{ChannelSink, CollectorSink, Fanout, FrameSink, OwnedFrame, SinkEvent};
+pub use stats::CaptureStats;
+
+use stats::StatsTracker;
+
+/// The PipeWire node to capture from.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum NodeTarget {
+    /// A node with the given global id.
+    Id(u32),
+    /// A node with the given `node.name`, or object serial. PipeWire's session
+    /// manager resolves this when the stream is linked.
+    Name(String),
+}
+
+impl From<&str> for NodeTarget {
+    fn from(target: &str) -> Self {
+        match target.parse() {
+            Ok(id) => NodeTarget::Id(id),
+            Err(_) => NodeTarget::Name(target.to_owned()),
+        }
+    }
+}
+
+impl fmt::Display for NodeTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NodeTarget::Id(id) => write!(f, "{0}", id),
+            NodeTarget::Name(name) => write!(f, "{0}", name),
+        }
+    }
+}
+
+/// Drop the memfd mappings of a stream `buffer` which is being added or
+/// removed.
+unsafe fn forget_buffer(mappings: &RefCell<Mappings>, buffer: *mut pipewire_sys::pw_buffer) {
+    if !buffer.is_null() && !(*buffer).buffer.is_null() {
+        mappings.borrow_mut().forget_buffer(&*(*buffer).buffer);
+    }
+}
+
+/// Point a stream's properties at `target`. Returns the node id to pass to
+/// `Stream::connect`, if the target has one.
+fn apply_target(props: &mut Properties, target: &NodeTarget) -> Option<u32> {
+    // Nodes can only be passed to `connect` by id. Names are resolved by the
+    // session manager from the stream's target properties instead. Older
+    // versions of PipeWire only understand `node.target`.
+    match target {
+        NodeTarget::Id(id) => Some(*id),
+        NodeTarget::Name(name) => {
+            props.insert("target.object", name.as_str());
+            props.insert("node.target", name.as_str());
+            None
+        }
+    }
+}
+
+/// Where a background capture gets its video from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CaptureSource {
+    /// Start a screen cast through the desktop portal, and capture the first
+    /// stream the user picks. The user's selection is saved, and given a
+    /// `restore_token` from a previous capture the portal reuses it rather
+    /// than prompting again.
+    Portal { restore_token: Option<String> },
+    /// Capture a PipeWire node directly. If `fd` is given then the connection
+    /// is made through that remote, otherwise to the default PipeWire daemon.
+    /// The capture takes ownership of the `fd`.
+    Node {
+        fd: Option<RawFd>,
+        target: NodeTarget,
+    },
+}
+
+/// Pixels to remove from each edge of the captured video.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Crop {
+    pub left: u32,
+    pub top: u32,
+    pub right: u32,
+    pub bottom: u32,
+}
+
+impl Crop {
+    pub fn is_empty(&self) -> bool {
+        *self == Crop::default()
+    }
+
+    /// Get the region left of a `width` by `height` frame once cropped, as
+    /// `(x, y, width, height)`. The position is rounded down to a multiple of
+    /// `alignment`, and at least `alignment` pixels are always left.
+    pub fn region(&self, width: u32, height: u32, alignment: u32) -> (u32, u32, u32, u32) {
+        let alignment = alignment.max(1);
+        let start = |offset: u32, size: u32| {
+            let offset = offset.min(size.saturating_sub(alignment));
+            offset - offset % alignment
+        };
+        let x = start(self.left, width);
+        let y = start(self.top, height);
+        let remaining = |start: u32, end: u32, size: u32| {
+            let available = size - start;
+            available.saturating_sub(end).max(alignment.min(available))
+        };
+        (
+            x,
+            y,
+            remaining(x, self.right, width),
+            remaining(y, self.bottom, height),
+        )
+    }
+
+    /// Scale this crop, given for a `from` sized frame, to the same area of a
+    /// `to` sized one.
+    pub fn scaled(&self, from: (u32, u32), to: (u32, u32)) -> Crop {
+        let scale =
+            |value: u32, from: u32, to: u32| (value as u64 * to as u64 / from.max(1) as u64) as u32;
+        Crop {
+            left: scale(self.left, from.0, to.0),
+            top: scale(self.top, from.1, to.1),
+            right: scale(self.right, from.0, to.0),
+            bottom: scale(self.bottom, from.1, to.1),
+        }
+    }
+}
+
+/// Limits on the video to negotiate with the node, and how to crop and scale
+/// it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CaptureLimits {
+    /// The largest frame to accept, as `(width, height)`.
+    pub max_size: (u32, u32),
+    /// The highest framerate to accept, in frames per second.
+    pub max_framerate: u32,
+    /// The area to crop from each frame, in pixels of the unscaled video.
+    pub crop: Crop,
+    /// The size to ask the node for, as a percentage of the size it first
+    /// offers. Not every compositor can scale its screen casts.
+    pub scale: u32,
+    /// Whether to offer, and prefer, 10-bit formats.
+    pub high_bit_depth: bool,
+}
+
+impl Default for CaptureLimits {
+    fn default() -> Self {
+        CaptureLimits {
+            max_size: (4096, 4096),
+            max_framerate: 144,
+            crop: Crop::default(),
+            scale: 100,
+            high_bit_depth: false,
+        }
+    }
+}
+
+impl CaptureLimits {
+    /// Get the raw parameters used to build our `EnumFormat` POD. We prefer
+    /// 1080p at 60fps, if that is within the limits.
+    pub fn video_params(&self) -> native_shims::VideoParams {
+        let (max_width, max_height) = (self.max_size.0.max(1), self.max_size.1.max(1));
+        let max_framerate = self.max_framerate.max(1);
+        native_shims::VideoParams {
+            default_size: libspa_sys::spa_rectangle {
+                width: max_width.min(1920),
+                height: max_height.min(1080),
+            },
+            min_size: libspa_sys::spa_rectangle {
+                width: 1,
+                height: 1,
+            },
+            max_size: libspa_sys::spa_rectangle {
+                width: max_width,
+                height: max_height,
+            },
+            default_framerate: libspa_sys::spa_fraction {
+                num: max_framerate.min(60),
+                denom: 1,
+            },
+            min_framerate: libspa_sys::spa_fraction { num: 0, denom: 1 },
+            max_framerate: libspa_sys::spa_fraction {
+                num: max_framerate,
+                denom: 1,
+            },
+            high_bit_depth: self.high_bit_depth as u32,
+        }
+    }
+
+    /// Get the size to renegotiate to, if the video is to be scaled down from
+    /// its `source` size.
+    pub fn scaled_size(&self, source: (u32, u32)) -> Option<(u32, u32)> {
+        if self.scale >= 100 {
+            return None;
+        }
+        let scale = |size: u32| (size as u64 * self.scale as u64 / 100).max(1) as u32;
+        Some((scale(source.0), scale(source.1)))
+    }
+
+    /// Get the raw parameters to renegotiate to exactly `size`.
+    pub fn scaled_params(&self, size: (u32, u32)) -> native_shims::VideoParams {
+        let mut params = CaptureLimits {
+            max_size: size,
+            ..*self
+        }
+        .video_params();
+        params.default_size = params.max_size;
+        params
+    }
+
+    /// Does changing from these limits to `other` need a renegotiation? Only
+    /// the crop can change without one.
+    fn needs_renegotiation(&self, other: &CaptureLimits) -> bool {
+        CaptureLimits {
+            crop: other.crop,
+            ..*self
+        } != *other
+    }
+}
+
+/// The state of a `CaptureStream`'s connection to its node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StreamStatus {
+    Unconnected,
+    Connecting,
+    Paused,
+    Streaming,
+    Error(String),
+}
+
+impl From<StreamState> for StreamStatus {
+    fn from(state: StreamState) -> Self {
+        match state {
+            StreamState::Unconnected => StreamStatus::Unconnected,
+            StreamState::Connecting => StreamStatus::Connecting,
+            StreamState::Paused => StreamStatus::Paused,
+            StreamState::Streaming => StreamStatus::Streaming,
+            StreamState::Error(err) => StreamStatus::Error(err),
+        }
+    }
+}
+
+/// A PipeWire stream connected to a video node.
+///
+/// The stream is disconnected when this is dropped.
+pub struct CaptureStream {
+    stream: Rc<RefCell<Stream>>,
+    status: Rc<RefCell<StreamStatus>>,
+    stats: Rc<RefCell<StatsTracker>>,
+    format: Rc<Cell<Option<NegotiatedFormat>>>,
+    limits: Rc<Cell<CaptureLimits>>,
+    sizes: Rc<Cell<ScaledSizes>>,
+    _listener: StreamListener,
+}
+
+/// The sizes involved in scaling a capture.
+#[derive(Debug, Clone, Copy, Default)]
+struct ScaledSizes {
+    /// The size the node offered before we asked it to scale.
+    source: Option<(u32, u32)>,
+    /// The scaled size we have asked the node for.
+    requested: Option<(u32, u32)>,
+    /// The node renegotiated to the size it offered, rather than the one we
+    /// asked for, so we have stopped asking it to scale.
+    refused: bool,
+}
+
+impl ScaledSizes {
+    /// Record a newly negotiated `size`. Anything larger than we asked for
+    /// means the source itself has changed size, unless it is the size the
+    /// node offered before, which means it can't scale. Returns the size to
+    /// scale to, if we need to renegotiate.
+    fn negotiated(&mut self, size: (u32, u32), limits: &CaptureLimits) -> Option<(u32, u32)> {
+        match self.requested {
+            Some(requested) if size.0 <= requested.0 && size.1 <= requested.1 => None,
+            Some(_) if self.source == Some(size) => {
+                self.requested = None;
+                self.refused = true;
+                None
+            }
+            _ if self.refused => {
+                self.source = Some(size);
+                None
+            }
+            _ => {
+                self.source = Some(size);
+                self.requested = limits.scaled_size(size);
+                self.requested
+            }
+        }
+    }
+
+    /// The size a frame of `size` was scaled down from, if it was.
+    fn source_size(&self, size: (u32, u32)) -> (u32, u32) {
+        match self.requested {
+            Some(_) => self.source.unwrap_or(size),
+            None => size,
+        }
+    }
+}
+
+impl CaptureStream {
+    /// Connect to the given PipeWire `target` node and call `on_frame` with
+    /// each frame that arrives. The format negotiated will be within `limits`,
+    /// and frames are cropped and scaled as they ask.
+    pub fn connect<F>(
+        core: &Core,
+        target: &NodeTarget,
+        limits: CaptureLimits,
+        mut on_frame: F,
+    ) -> Result<Self, pipewire::Error>
+    where
+        F: FnMut(&Frame) + 'static,
+    {
+        let mut props = properties! {
+            "media.type" => "Video",
+            "media.category" => "Capture",
+            "media.role" => "Screen"
+        };
+        let node = apply_target(&mut props, target);
+        let stream = Rc::new(RefCell::new(Stream::new(
+            core,
+            "obs-portal-screencap",
+            props,
+        )?));
+
+        let format = Rc::new(Cell::new(None));
+        let mappings = Rc::new(RefCell::new(Mappings::default()));
+        let stats = Rc::new(RefCell::new(StatsTracker::new(Instant::now())));
+        let status = Rc::new(RefCell::new(StreamStatus::Unconnected));
+        let limits = Rc::new(Cell::new(limits));
+        let sizes = Rc::new(Cell::new(ScaledSizes::default()));
+        let state_changed_status = status.clone();
+        let param_changed_format = format.clone();
+        let process_format = format.clone();
+        let param_changed_mappings = mappings.clone();
+        let add_buffer_mappings = mappings.clone();
+        let remove_buffer_mappings = mappings.clone();
+        let param_changed_stream = stream.clone();
+        let param_changed_limits = limits.clone();
+        let param_changed_sizes = sizes.clone();
+        let process_stream = stream.clone();
+        let process_stats = stats.clone();
+        let mut skip_log = RateLimit::new(LOG_INTERVAL);
+        let process_limits = limits.clone();
+        let process_sizes = sizes.clone();
+
+        let listener = stream
+            .borrow_mut()
+            .add_local_listener()
+            .state_changed(move |old, new| {
+                debug!("State: {0:?} -> {1:?}", old, new);
+                *state_changed_status.borrow_mut() = StreamStatus::from(new);
+            })
+            .param_changed(move |id, param| {
+                if id != libspa_sys::spa_param_type_SPA_PARAM_Format {
+                    return;
+                }
+
+                // Every format change, such as a shared window being resized,
+                // is a renegotiation. The old buffers, and their mappings, go
+                // away and new ones are allocated for the new format.
+                param_changed_mappings.borrow_mut().clear();
+                if param.is_null() {
+                    param_changed_format.set(None);
+                    return;
+                }
+                let negotiated = unsafe { NegotiatedFormat::from_pod(param) };
+                param_changed_format.set(negotiated);
+                info!("Format: {0:?}", negotiated);
+
+                if let Some(negotiated) = negotiated {
+                    let limits = param_changed_limits.get();
+                    let mut sizes = param_changed_sizes.get();
+                    let size = (negotiated.width, negotiated.height);
+                    if let Some(scaled) = sizes.negotiated(size, &limits) {
+                        info!("Scaling {0:?} to {1:?}", size, scaled);
+                        let params = limits.scaled_params(scaled);
+                        let param = unsafe { native_shims::build_video_params(&params) };
+                        if let Err(err) = param_changed_stream
+                            .borrow_mut()
+                            .update_params(&mut [param as _])
+                        {
+                            error!("could not scale stream: {0}", err);
+                        }
+                    }
+                    param_changed_sizes.set(sizes);
+
+                    let blocks = negotiated.format.plane_count() as u32;
+                    let (param, meta) = unsafe {
+                        (
+                            native_shims::build_video_buffers_param(
+                                blocks,
+                                negotiated.frame_size() as u32,
+                                negotiated.largest_plane_size() as u32,
+                                negotiated.stride(),
+                            ),
+                            native_shims::build_meta_header_param(),
+                        )
+                    };
+                    if let Err(err) = param_changed_stream
+                        .borrow_mut()
+                        .update_params(&mut [param as _, meta as _])
+                    {
+                        error!("could not update stream params: {0}", err);
+                    }
+                }
+            })
+            .add_buffer(move |buffer| {
+                // A new buffer can reuse the fd numbers of a removed one.
+                unsafe { forget_buffer(&add_buffer_mappings, buffer) };
+            })
+            .remove_buffer(move |buffer| {
+                unsafe { forget_buffer(&remove_buffer_mappings, buffer) };
+            })
+            .process(move || {
+                let started = Instant::now();
+                let mut stream = process_stream.borrow_mut();
+                let buffer = unsafe { stream.dequeue_buffer() };
+                if buffer.is_null() {
+                    return;
+                }
+                if let Some(negotiated) = process_format.get() {
+                    let mut mappings = mappings.borrow_mut();
+                    let frame = unsafe {
+                        Frame::from_buffer(&*(*buffer).buffer, negotiated, &mut mappings)
+                    };
+                    let limits = process_limits.get();
+                    let frame = frame.map(|frame| {
+                        let size = (frame.width(), frame.height());
+                        let frame = frame.with_source_size(process_sizes.get().source_size(size));
+                        if limits.crop.is_empty() {
+                            frame
+                        } else {
+                            frame.cropped_from_source(&limits.crop)
+                        }
+                    });
+                    match frame {
+                        Ok(frame) => {
+                            let now = stats::monotonic_now();
+                            on_frame(&frame);
+                            process_stats.borrow_mut().delivered(
+                                frame.sequence(),
+                                frame.pts(),
+                                now,
+                                started.elapsed(),
+                            );
+                        }
+                        Err(err) => {
+                            process_stats.borrow_mut().skipped();
+                            if let Some(suppressed) = skip_log.allow(started) {
+                                debug!("Skipped frame: {0} ({1} more not logged)", err, suppressed);
+                            }
+                        }
+                    }
+                }
+                unsafe {
+                    stream.queue_buffer(buffer);
+                }
+            })
+            .register()?;
+
+        let params = limits.video_params();
+        let param = unsafe { native_shims::build_video_params(&params) };
+        stream.borrow_mut().connect(
+            Direction::Input,
+            node,
+            StreamFlags::AUTOCONNECT,
+            &mut [param as *const _],
+        )?;
+
+        Ok(CaptureStream {
+            stream,
+            status,
+            stats,
+            format,
+            limits,
+            sizes,
+            _listener: listener,
+        })
+    }
+
+    /// The stream's current state. A stream which has gone back to
+    /// `Unconnected`, or into `Error`, won't receive any more frames.
+    pub fn status(&self) -> StreamStatus {
+        self.status.borrow().clone()
+    }
+
+    /// The format currently negotiated with the node, if there is one. This
+    /// changes whenever the node renegotiates, such as when a shared window
+    /// is resized.
+    pub fn format(&self) -> Option<NegotiatedFormat> {
+        self.format.get()
+    }
+
+    /// Has the node refused to scale, leaving the video at full size?
+    pub fn scaling_refused(&self) -> bool {
+        self.sizes.get().refused
+    }
+
+    /// Have we asked the node to scale, without it having done so yet? A
+    /// stream failing while this is true most likely failed to scale.
+    pub fn awaiting_scale(&self) -> bool {
+        match (self.sizes.get().requested, self.format.get()) {
+            (Some(requested), Some(format)) => {
+                format.width > requested.0 || format.height > requested.1
+            }
+            (Some(_), None) => true,
+            (None, _) => false,
+        }
+    }
+
+    /// Take a snapshot of the stream's statistics. This starts a new interval
+    /// for the rates and timings in the stats.
+    pub fn stats(&self) -> CaptureStats {
+        self.stats.borrow_mut().snapshot(Instant::now())
+    }
+
+    /// Change the limits on the negotiated video. This triggers a
+    /// renegotiation with the node, unless only the crop has changed.
+    pub fn set_limits(&self, limits: CaptureLimits) -> Result<(), pipewire::Error> {
+        let old = self.limits.replace(limits);
+        if !old.needs_renegotiation(&limits) {
+            return Ok(());
+        }
+
+        // Scale from the size the node first offered, rather than whatever
+        // we are currently scaled to.
+        // A new scale is worth asking for, even if the node refused the last.
+        let mut sizes = self.sizes.get();
+        if limits.scale != old.scale {
+            sizes.refused = false;
+        }
+        sizes.requested = if sizes.refused {
+            None
+        } else {
+            sizes.source.and_then(|source| limits.scaled_size(source))
+        };
+        self.sizes.set(sizes);
+        let params = match sizes.requested {
+            Some(scaled) => limits.scaled_params(scaled),
+            None => limits.video_params(),
+        };
+        let param = unsafe { native_shims::build_video_params(&params) };
+        self.stream.borrow_mut().update_params(&mut [param as _])
+    }
+
+    /// Pause or resume the stream. A paused stream stays connected to its
+    /// node, and keeps its negotiated format, but no frames are delivered.
+    pub fn set_active(&self, active: bool) -> Result<(), pipewire::Error> {
+        self.stream.borrow().set_active(active)
+    }
+}
+
+impl fmt::Debug for CaptureStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CaptureStream")
+            .field("stream", &self.stream)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn node_target_from_str() {
+        assert_eq!(NodeTarget::Id(42), NodeTarget::from("42"));
+        assert_eq!(
+            NodeTarget::Name("v4l2_input.pci-0000_00_14.0-usb-0_1_1.0".into()),
+            NodeTarget::from("v4l2_input.pci-0000_00_14.0-usb-0_1_1.0")
+        );
+        assert_eq!(NodeTarget::Name("-1".into()), NodeTarget::from("-1"));
+    }
+
+    #[test]
+    fn crop_region_is_aligned_and_never_empty() {
+        let crop = Crop {
+            left: 101,
+            top: 50,
+            right: 20,
+            bottom: 30,
+        };
+        assert_eq!((101, 50, 1799, 1000), crop.region(1920, 1080, 1));
+        assert_eq!((100, 50, 1800, 1000), crop.region(1920, 1080, 2));
+
+        let everything = Crop {
+            left: 5000,
+            top: 0,
+            right: 5000,
+            bottom: 0,
+        };
+        assert_eq!((1918, 0, 2, 1080), everything.region(1920, 1080, 2));
+    }
+
+    #[test]
+    fn crop_follows_scaling() {
+        let limits = CaptureLimits {
+            crop: Crop {
+                left: 100,
+                top: 100,
+                right: 0,
+                bottom: 200,
+            },
+            scale: 50,
+            ..CaptureLimits::default()
+        };
+        let mut sizes = ScaledSizes::default();
+
+        assert_eq!(Some((960, 540)), sizes.negotiated((1920, 1080), &limits));
+        assert_eq!(None, sizes.negotiated((960, 540), &limits));
+        let source = sizes.source_size((960, 540));
+        assert_eq!((1920, 1080), source);
+        assert_eq!(
+            Crop {
+                left: 50,
+                top: 50,
+                right: 0,
+                bottom: 100,
+            },
+            limits.crop.scaled(source, (960, 540))
+        );
+
+        // The source growing is a new source size to scale from.
+        assert_eq!(Some((1280, 720)), sizes.negotiated((2560, 1440), &limits));
+    }
+
+    #[test]
+    fn refused_scaling_falls_back_to_full_size() {
+        let limits = CaptureLimits {
+            scale: 50,
+            ..CaptureLimits::default()
+        };
+        let mut sizes = ScaledSizes::default();
+
+        assert_eq!(Some((960, 540)), sizes.negotiated((1920, 1080), &limits));
+        // The node comes back with the size it offered in the first place.
+        assert_eq!(None, sizes.negotiated((1920, 1080), &limits));
+        assert!(sizes.refused);
+        assert_eq!((1920, 1080), sizes.source_size((1920, 1080)));
+
+        // And isn't asked again when the source changes size.
+        assert_eq!(None, sizes.negotiated((2560, 1440), &limits));
+        assert_eq!(Some((2560, 1440)), sizes.source);
+    }
+
+    #[test]
+    fn only_crop_changes_skip_renegotiation() {
+        let limits = CaptureLimits::default();
+        let cropped = CaptureLimits {
+            crop: Crop {
+                left: 10,
+                ..Crop::default()
+            },
+            ..limits
+        };
+        let scaled = CaptureLimits {
+            scale: 50,
+            ..limits
+        };
+
+        assert!(!limits.needs_renegotiation(&cropped));
+        assert!(limits.needs_renegotiation(&scaled));
+    }
+
+    #[test]
+    fn default_limits_prefer_1080p60() {
+        let params = CaptureLimits::default().video_params();
+        assert_eq!(
+            (1920, 1080),
+            (params.default_size.width, params.default_size.height)
+        );
+        assert_eq!(
+            (4096, 4096),
+            (params.max_size.width, params.max_size.height)
+        );
+        assert_eq!(60, params.default_framerate.num);
+        assert_eq!(144, params.max_framerate.num);
+        assert_eq!(0, params.high_bit_depth);
+    }
+
+    #[test]
+    fn defaults_are_clamped_to_limits() {
+        let params = CaptureLimits {
+            max_size: (1280, 5120),
+            max_framerate: 5,
+            ..CaptureLimits::default()
+        }
+        .video_params();
+        assert_eq!(
+            (1280, 1080),
+            (params.default_size.width, params.default_size.height)
+        );
+        assert_eq!(
+            (1280, 5120),
+            (params.max_size.width, params.max_size.height)
+        );
+        assert_eq!(
+            (5, 1),
+            (params.default_framerate.num, params.default_framerate.denom)
+        );
+        assert_eq!(0, params.min_framerate.num);
+    }
+}
@@ -0,0 +1,437 @@
+//! Publishing raw video to PipeWire. This is the reverse of `CaptureStream`:
+//! an `OutputStream` is a producer, offering a single fixed format to any
+//! consumer, such as a browser, that links to its node. The `spawn_output`
+//! function runs one on a background thread and feeds it frames.
+//!
+//! The stream drives its consumers: each frame triggers a graph cycle, and is
+//! copied into a PipeWire buffer in the stream's `process` callback. The
+//! frames' memory is passed back to the `OutputHandle` to be reused.
+
+use log::{debug, error};
+use pipewire::{
+    properties,
+    spa::Direction,
+    stream::{Stream, StreamFlags, StreamListener},
+    Context, Core, MainLoop,
+};
+use std::{
+    cell::{Cell, RefCell},
+    error::Error,
+    fmt, ptr,
+    rc::Rc,
+    sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, SyncSender, TrySendError},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use libspa_sys::{spa_fraction, spa_rectangle};
+
+use super::{FrameError, Plane, StreamStatus};
+use crate::{
+    format::{NegotiatedFormat, PlaneLayout},
+    native_shims,
+    runtime::RuntimeGuard,
+};
+
+/// How many frames may wait for the PipeWire thread before we start dropping
+/// them. OBS must never block on a slow consumer.
+const FRAME_QUEUE: usize = 2;
+
+/// How long the output thread waits for a frame before servicing PipeWire.
+const FRAME_WAIT: Duration = Duration::from_millis(10);
+
+/// Copy the `planes` of a frame into `packed`, laid out one after another as
+/// described by `layout`. Rows are copied one at a time, so the source planes
+/// may have any stride of at least the row width.
+pub fn pack_planes(
+    planes: &[Plane],
+    layout: &[PlaneLayout],
+    packed: &mut Vec<u8>,
+) -> Result<(), FrameError> {
+    if planes.len() < layout.len() {
+        return Err(FrameError::Truncated);
+    }
+
+    packed.clear();
+    for (plane, layout) in planes.iter().zip(layout) {
+        let row = layout.stride as usize;
+        let src_stride = plane.stride as usize;
+        if layout.height > 0 && src_stride < row {
+            return Err(FrameError::Truncated);
+        }
+        for y in 0..layout.height as usize {
+            let start = y * src_stride;
+            let data = plane
+                .data
+                .get(start..start + row)
+                .ok_or(FrameError::Truncated)?;
+            packed.extend_from_slice(data);
+        }
+    }
+    Ok(())
+}
+
+/// A packed frame waiting for the stream's next cycle.
+struct PendingFrame {
+    packed: Vec<u8>,
+    pts: i64,
+}
+
+/// A PipeWire stream publishing video as a `Video/Source` node.
+///
+/// Frames are handed over packed into a single block, as produced by
+/// `pack_planes`. The node is removed when this is dropped.
+pub struct OutputStream {
+    stream: Rc<RefCell<Stream>>,
+    status: Rc<RefCell<StreamStatus>>,
+    format: NegotiatedFormat,
+    /// The latest frame, until it is copied into a buffer by `process`.
+    pending: Rc<RefCell<Option<PendingFrame>>>,
+    recycle: Sender<Vec<u8>>,
+    _listener: StreamListener,
+}
+
+impl OutputStream {
+    /// Create a node called `name` offering video in `format`. Consumers
+    /// link to the node themselves, so we don't connect to any target. The
+    /// memory of each frame pushed is sent to `recycle` once it is published
+    /// or superseded.
+    pub fn connect(
+        core: &Core,
+        name: &str,
+        format: NegotiatedFormat,
+        recycle: Sender<Vec<u8>>,
+    ) -> Result<Self, pipewire::Error> {
+        let props = properties! {
+            "media.type" => "Video",
+            "media.category" => "Source",
+            "media.role" => "Camera",
+            "media.class" => "Video/Source",
+            "node.name" => name,
+            "node.description" => name
+        };
+        let stream = Rc::new(RefCell::new(Stream::new(core, name, props)?));
+
+        let stride = format.stride();
+        let size = format.frame_size();
+
+        let status = Rc::new(RefCell::new(StreamStatus::Unconnected));
+        let pending = Rc::new(RefCell::new(None));
+        let sequence = Cell::new(0);
+        let state_changed_status = status.clone();
+        let param_changed_stream = stream.clone();
+        let process_stream = stream.clone();
+        let process_pending = pending.clone();
+        let process_recycle = recycle.clone();
+
+        let listener = stream
+            .borrow_mut()
+            .add_local_listener()
+            .state_changed(move |old, new| {
+                debug!("Output state: {0:?} -> {1:?}", old, new);
+                *state_changed_status.borrow_mut() = new.into();
+            })
+            .param_changed(move |id, param| {
+                if param.is_null() || id != libspa_sys::spa_param_type_SPA_PARAM_Format {
+                    return;
+                }
+
+                let (buffers, meta) = unsafe {
+                    (
+                        native_shims::build_output_buffers_param(size as u32, stride),
+                        native_shims::build_meta_header_param(),
+                    )
+                };
+                if let Err(err) = param_changed_stream
+                    .borrow_mut()
+                    .update_params(&mut [buffers as _, meta as _])
+                {
+                    error!("could not update output stream params: {0}", err);
+                }
+            })
+            .process(move || {
+                let mut stream = process_stream.borrow_mut();
+                let frame = match process_pending.borrow_mut().take() {
+                    Some(frame) => frame,
+                    None => return,
+                };
+                let buffer = unsafe { stream.dequeue_buffer() };
+                if !buffer.is_null() {
+                    let seq = sequence.get();
+                    sequence.set(seq + 1);
+                    unsafe {
+                        fill_buffer(&mut *(*buffer).buffer, &frame, stride, seq);
+                        stream.queue_buffer(buffer);
+                    }
+                }
+                let _ = process_recycle.send(frame.packed);
+            })
+            .register()?;
+
+        let size = spa_rectangle {
+            width: format.width,
+            height: format.height,
+        };
+        let framerate = spa_fraction {
+            num: format.framerate.0,
+            denom: format.framerate.1,
+        };
+        let param =
+            unsafe { native_shims::build_output_format(format.format.to_spa(), &size, &framerate) };
+
+        // We write straight into the buffers, so unlike captures we need
+        // PipeWire to map them for us whatever their type.
+        stream.borrow_mut().connect(
+            Direction::Output,
+            None,
+            StreamFlags::DRIVER | StreamFlags::MAP_BUFFERS,
+            &mut [param],
+        )?;
+
+        Ok(OutputStream {
+            stream,
+            status,
+            format,
+            pending,
+            recycle,
+            _listener: listener,
+        })
+    }
+
+    /// The format this stream publishes.
+    pub fn format(&self) -> NegotiatedFormat {
+        self.format
+    }
+
+    /// The state of the stream, as of the last state change.
+    pub fn status(&self) -> StreamStatus {
+        self.status.borrow().clone()
+    }
+
+    /// Publish a `packed` frame, with presentation timestamp `pts` in
+    /// nanoseconds, on the stream's next cycle. A frame still waiting from
+    /// before is dropped. Until a consumer is linked the stream isn't
+    /// driving a graph, and frames wait to be superseded.
+    pub fn push(&self, packed: Vec<u8>, pts: i64) {
+        let superseded = self
+            .pending
+            .borrow_mut()
+            .replace(PendingFrame { packed, pts });
+        if let Some(frame) = superseded {
+            let _ = self.recycle.send(frame.packed);
+        }
+
+        // `process` may run as soon as we trigger it, so the stream mustn't
+        // be borrowed while we do.
+        let stream = self.stream.borrow().as_ptr();
+        unsafe {
+            pipewire_sys::pw_stream_trigger_process(stream);
+        }
+    }
+}
+
+/// Copy `frame` into the first data of `buffer`, and stamp its header.
+unsafe fn fill_buffer(
+    buffer: &mut libspa_sys::spa_buffer,
+    frame: &PendingFrame,
+    stride: u32,
+    sequence: u64,
+) {
+    if buffer.n_datas > 0 && !buffer.datas.is_null() {
+        let data = &mut *buffer.datas;
+        if !data.data.is_null() && !data.chunk.is_null() {
+            let size = frame.packed.len().min(data.maxsize as usize);
+            ptr::copy_nonoverlapping(frame.packed.as_ptr(), data.data as *mut u8, size);
+            let chunk = &mut *data.chunk;
+            chunk.offset = 0;
+            chunk.size = size as u32;
+            chunk.stride = stride as i32;
+        }
+    }
+
+    let header = native_shims::spa_buffer_find_meta_header_rs(buffer);
+    if !header.is_null() {
+        (*header).flags = 0;
+        (*header).pts = frame.pts;
+        (*header).dts_offset = 0;
+        (*header).seq = sequence;
+    }
+}
+
+impl fmt::Debug for OutputStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OutputStream")
+            .field("stream", &self.stream)
+            .field("format", &self.format)
+            .finish()
+    }
+}
+
+/// Messages sent from an `OutputHandle` to its output thread.
+enum Message {
+    Frame { packed: Vec<u8>, pts: i64 },
+    Stop,
+}
+
+/// Handle to an output running on a background thread. The node is removed
+/// when this is dropped.
+pub struct OutputHandle {
+    format: NegotiatedFormat,
+    layout: Vec<PlaneLayout>,
+    frames: SyncSender<Message>,
+    /// Memory from frames which have been published, to pack new ones into.
+    spare: Receiver<Vec<u8>>,
+    recycle: Sender<Vec<u8>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl OutputHandle {
+    /// The format frames must be sent in.
+    pub fn format(&self) -> NegotiatedFormat {
+        self.format
+    }
+
+    /// Copy the `planes` of a frame and queue them for publishing. Never
+    /// blocks: if the output thread is behind then the frame is dropped, and
+    /// `false` returned.
+    pub fn send(&self, planes: &[Plane], pts: i64) -> Result<bool, FrameError> {
+        let mut packed = self.spare.try_recv().unwrap_or_default();
+        if let Err(err) = pack_planes(planes, &self.layout, &mut packed) {
+            let _ = self.recycle.send(packed);
+            return Err(err);
+        }
+        match self.frames.try_send(Message::Frame { packed, pts }) {
+            Ok(()) => Ok(true),
+            Err(TrySendError::Full(Message::Frame { packed, .. })) => {
+                let _ = self.recycle.send(packed);
+                Ok(false)
+            }
+            Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => Ok(false),
+        }
+    }
+}
+
+impl std::ops::Drop for OutputHandle {
+    fn drop(&mut self) {
+        // The queue may be full, so wait for space rather than lose the stop.
+        let _ = self.frames.send(Message::Stop);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Start publishing video in `format` as a PipeWire node called `name`, on a
+/// background thread connected to the default PipeWire daemon. PipeWire must
+/// have been initialised with `runtime::init`. Waits for the node to be
+/// created, returning the error if it can't be.
+pub fn spawn_output(
+    name: String,
+    format: NegotiatedFormat,
+) -> Result<OutputHandle, Box<dyn Error>> {
+    let (frames, receiver) = mpsc::sync_channel(FRAME_QUEUE);
+    let (recycle, spare) = mpsc::channel();
+    let (ready, setup) = mpsc::channel();
+    let thread_recycle = recycle.clone();
+    let thread = thread::spawn(move || {
+        if let Err(err) = run_output(receiver, thread_recycle, &ready, &name, format) {
+            // Setup failures are returned from `spawn_output` instead.
+            if ready.send(Err(err.to_string())).is_err() {
+                error!("output failed: {0}", err);
+            }
+        }
+    });
+
+    match setup.recv() {
+        Ok(Ok(())) => (),
+        Ok(Err(err)) => {
+            let _ = thread.join();
+            return Err(err.into());
+        }
+        Err(_) => {
+            let _ = thread.join();
+            return Err("the output thread exited".into());
+        }
+    }
+
+    Ok(OutputHandle {
+        format,
+        layout: format.planes(),
+        frames,
+        spare,
+        recycle,
+        thread: Some(thread),
+    })
+}
+
+/// Publish frames from `frames` until stopped, sending on `ready` once the
+/// node has been created.
+fn run_output(
+    frames: Receiver<Message>,
+    recycle: Sender<Vec<u8>>,
+    ready: &Sender<Result<(), String>>,
+    name: &str,
+    format: NegotiatedFormat,
+) -> Result<(), Box<dyn Error>> {
+    let _guard = RuntimeGuard::new()?;
+
+    let pw_loop = MainLoop::new()?;
+    let pw_context = Context::new(&pw_loop)?;
+    let core = pw_context.connect(None)?;
+    let stream = OutputStream::connect(&core, name, format, recycle)?;
+    let _ = ready.send(Ok(()));
+
+    loop {
+        match frames.recv_timeout(FRAME_WAIT) {
+            Ok(Message::Frame { packed, pts }) => stream.push(packed, pts),
+            Ok(Message::Stop) | Err(RecvTimeoutError::Disconnected) => break,
+            Err(RecvTimeoutError::Timeout) => (),
+        }
+        unsafe {
+            native_shims::pw_main_loop_iterate_rs(pw_loop.as_ptr(), 0);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::VideoFormat;
+
+    #[test]
+    fn pack_planes_drops_padding() {
+        let y = [1, 2, 0, 0, 3, 4, 0, 0];
+        let uv = [5, 6, 0, 0];
+        let planes = [
+            Plane {
+                data: &y,
+                stride: 4,
+            },
+            Plane {
+                data: &uv,
+                stride: 4,
+            },
+        ];
+        let layout = VideoFormat::Nv12.planes(2, 2);
+
+        let mut packed = Vec::new();
+        pack_planes(&planes, &layout, &mut packed).unwrap();
+
+        assert_eq!(vec![1, 2, 3, 4, 5, 6], packed);
+    }
+
+    #[test]
+    fn pack_planes_rejects_short_planes() {
+        let y = [1, 2, 3];
+        let planes = [Plane {
+            data: &y,
+            stride: 2,
+        }];
+        let layout = VideoFormat::Rgba.planes(2, 2);
+
+        let mut packed = Vec::new();
+        assert!(pack_planes(&planes, &layout, &mut packed).is_err());
+    }
+}
@@ -0,0 +1,58 @@
+//! Limiting how often messages logged for every frame are written. A stream
+//! which can't handle its frames would otherwise log sixty times a second.
+
+use std::time::{Duration, Instant};
+
+/// How often a message repeated for every frame is logged.
+pub const LOG_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Limits a repeated message to once per interval, counting the messages held
+/// back in between.
+#[derive(Debug)]
+pub struct RateLimit {
+    interval: Duration,
+    last: Option<Instant>,
+    suppressed: u64,
+}
+
+impl RateLimit {
+    pub fn new(interval: Duration) -> Self {
+        RateLimit {
+            interval,
+            last: None,
+            suppressed: 0,
+        }
+    }
+
+    /// Should the message be logged `now`? If so, returns the number of
+    /// messages held back since the last one logged.
+    pub fn allow(&mut self, now: Instant) -> Option<u64> {
+        match self.last {
+            Some(last) if now.saturating_duration_since(last) < self.interval => {
+                self.suppressed += 1;
+                None
+            }
+            _ => {
+                self.last = Some(now);
+                Some(std::mem::replace(&mut self.suppressed, 0))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeats_are_counted_until_the_interval_passes() {
+        let start = Instant::now();
+        let mut limit = RateLimit::new(Duration::from_secs(5));
+        assert_eq!(Some(0), limit.allow(start));
+        assert_eq!(None, limit.allow(start + Duration::from_secs(1)));
+        assert_eq!(None, limit.allow(start + Duration::from_secs(4)));
+        assert_eq!(Some(2), limit.allow(start + Duration::from_secs(5)));
+        assert_eq!(None, limit.allow(start + Duration::from_secs(6)));
+        assert_eq!(Some(1), limit.allow(start + Duration::from_secs(60)));
+    }
+}
@@ -0,0 +1,124 @@
+//! Recovering a background capture when its stream fails or PipeWire goes
+//! away. The runner reports what it is doing as `CaptureEvent`s, and spaces
+//! out its attempts to reconnect with a `Backoff`.
+
+use std::{fmt, time::Duration};
+
+use crate::format::NegotiatedFormat;
+
+/// A change in the state of a background capture.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CaptureEvent {
+    /// Connecting to PipeWire and the node.
+    Connecting,
+    /// Connected, and frames are being received.
+    Streaming,
+    /// Connected, but paused with `CaptureHandle::set_active`.
+    Paused,
+    /// The portal saved the user's selection under this restore token. Pass
+    /// it in the next `CaptureSource::Portal` to capture the same thing.
+    RestoreToken(String),
+    /// A new format was negotiated with the node, for example because the
+    /// shared window was resized. Later frames will be in this format.
+    FormatChanged(NegotiatedFormat),
+    /// The node can't scale its video, so it is captured at full size.
+    ScalingUnsupported,
+    /// The connection was lost. Another attempt will be made after
+    /// `retry_in`.
+    Disconnected { reason: String, retry_in: Duration },
+    /// The capture can't be recovered and has stopped.
+    Failed(String),
+}
+
+impl fmt::Display for CaptureEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CaptureEvent::Connecting => write!(f, "Connecting to PipeWire"),
+            CaptureEvent::Streaming => write!(f, "Capturing"),
+            CaptureEvent::Paused => write!(f, "Paused"),
+            CaptureEvent::RestoreToken(_) => write!(f, "Saved the screen cast selection"),
+            CaptureEvent::FormatChanged(format) => write!(
+                f,
+                "Capturing {0}x{1} {2:?}",
+                format.width, format.height, format.format
+            ),
+            CaptureEvent::ScalingUnsupported => {
+                write!(f, "The source can't be scaled, capturing at full size")
+            }
+            CaptureEvent::Disconnected { reason, retry_in } => write!(
+                f,
+                "Disconnected ({0}), retrying in {1}s",
+                reason,
+                retry_in.as_secs_f32()
+            ),
+            CaptureEvent::Failed(reason) => write!(f, "Capture failed: {0}", reason),
+        }
+    }
+}
+
+/// Exponential backoff between attempts to reconnect.
+#[derive(Debug)]
+pub(crate) struct Backoff {
+    initial: Duration,
+    max: Duration,
+    next: Duration,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Backoff {
+            initial,
+            max,
+            next: initial,
+        }
+    }
+
+    /// Get the delay before the next attempt. Each call doubles the delay, up
+    /// to the maximum.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (self.next * 2).min(self.max);
+        delay
+    }
+
+    /// Go back to the initial delay, once a connection has succeeded.
+    pub fn reset(&mut self) {
+        self.next = self.initial;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let mut backoff = Backoff::new(Duration::from_millis(500), Duration::from_secs(3));
+        let delays: Vec<_> = (0..5).map(|_| backoff.next_delay()).collect();
+        assert_eq!(
+            vec![
+                Duration::from_millis(500),
+                Duration::from_secs(1),
+                Duration::from_secs(2),
+                Duration::from_secs(3),
+                Duration::from_secs(3),
+            ],
+            delays
+        );
+
+        backoff.reset();
+        assert_eq!(Duration::from_millis(500), backoff.next_delay());
+    }
+
+    #[test]
+    fn events_as_status_text() {
+        assert_eq!(
+            "Disconnected (stream error: node gone), retrying in 1.5s",
+            CaptureEvent::Disconnected {
+                reason: "stream error: node gone".into(),
+                retry_in: Duration::from_millis(1500),
+            }
+            .to_string()
+        );
+    }
+}
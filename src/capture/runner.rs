@@ -0,0 +1,443 @@
+//! Running a capture on a background thread. The runner owns the PipeWire
+//! connection, and rebuilds it if the stream fails or the daemon goes away.
+
+use log::{error, info, warn};
+use pipewire::{Context, MainLoop};
+use portal_screencast::{ActiveScreenCast, CancelToken, PersistMode, ScreenCast};
+use std::{
+    cell::RefCell,
+    error::Error,
+    io,
+    os::unix::io::RawFd,
+    rc::Rc,
+    sync::{
+        mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use super::{
+    recovery::{Backoff, CaptureEvent},
+    sink::{Fanout, FrameSink},
+    CaptureLimits, CaptureSource, CaptureStats, CaptureStream, Frame, NodeTarget, StreamStatus,
+};
+use crate::{native_shims, runtime::RuntimeGuard};
+
+/// How often the capture thread publishes stats to its `CaptureHandle`.
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The delay before the first attempt to reconnect, and the most we will
+/// back off to after repeated failures.
+const RETRY_INITIAL: Duration = Duration::from_millis(500);
+const RETRY_MAX: Duration = Duration::from_secs(30);
+
+/// Commands sent from a `CaptureHandle` to its capture thread.
+enum Command {
+    SetLimits(CaptureLimits),
+    SetActive(bool),
+    Stop,
+}
+
+/// Handle to a capture running on a background thread. The capture is
+/// stopped, and any screen cast closed, when this is dropped.
+pub struct CaptureHandle {
+    commands: Sender<Command>,
+    /// Stops the capture thread waiting on the portal, which it does before
+    /// it starts handling commands.
+    cancel: CancelToken,
+    events: Receiver<CaptureEvent>,
+    stats: Arc<Mutex<CaptureStats>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl CaptureHandle {
+    /// Change the limits on the negotiated video of the running capture.
+    pub fn set_limits(&self, limits: CaptureLimits) {
+        let _ = self.commands.send(Command::SetLimits(limits));
+    }
+
+    /// Pause or resume the capture. Pausing keeps the connection, and any
+    /// screen cast, open so that resuming doesn't prompt the user again.
+    pub fn set_active(&self, active: bool) {
+        let _ = self.commands.send(Command::SetActive(active));
+    }
+
+    /// Get the most recent stats published by the capture thread. These are
+    /// updated about once a second.
+    pub fn stats(&self) -> CaptureStats {
+        *self.stats.lock().unwrap()
+    }
+
+    /// Take the next event sent by the capture thread, if there is one.
+    pub fn try_event(&self) -> Option<CaptureEvent> {
+        self.events.try_recv().ok()
+    }
+}
+
+impl std::ops::Drop for CaptureHandle {
+    fn drop(&mut self) {
+        let _ = self.commands.send(Command::Stop);
+        self.cancel.cancel();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Start capturing from `source` on a background thread, handing each frame
+/// received to every one of the `sinks`. For `CaptureSource::Portal` this
+/// prompts the user to choose what to share. PipeWire must have been
+/// initialised with `runtime::init`.
+///
+/// If the stream fails, or the connection to PipeWire is lost, the capture
+/// reconnects with an increasing delay between attempts. Progress is reported
+/// through `CaptureHandle::try_event`. The sinks are told the stream has ended
+/// once the capture stops or fails for good.
+pub fn spawn(
+    source: CaptureSource,
+    limits: CaptureLimits,
+    sinks: Vec<Box<dyn FrameSink>>,
+) -> CaptureHandle {
+    let (commands, command_receiver) = mpsc::channel();
+    let (event_sender, events) = mpsc::channel();
+    let stats = Arc::new(Mutex::new(CaptureStats::default()));
+    let cancel = CancelToken::new();
+    let thread_stats = stats.clone();
+    let thread_cancel = cancel.clone();
+    let thread = thread::spawn(move || {
+        let mut runner = Runner {
+            commands: command_receiver,
+            events: event_sender,
+            stats: thread_stats,
+            limits,
+            unscaled: false,
+            active: true,
+            backoff: Backoff::new(RETRY_INITIAL, RETRY_MAX),
+        };
+        let fanout = Rc::new(RefCell::new(Fanout::new(sinks)));
+        match runner.run(source, thread_cancel.clone(), fanout.clone()) {
+            // Being stopped while waiting for the portal isn't a failure.
+            Err(_) if thread_cancel.is_cancelled() => (),
+            Err(err) => {
+                error!("capture failed: {0}", err);
+                let _ = runner.events.send(CaptureEvent::Failed(err.to_string()));
+            }
+            Ok(()) => (),
+        }
+        fanout.borrow_mut().end_of_stream();
+    });
+
+    CaptureHandle {
+        commands,
+        cancel,
+        events,
+        stats,
+        thread: Some(thread),
+    }
+}
+
+/// Why a connection ended.
+enum Outcome {
+    /// The handle asked us to stop.
+    Stopped,
+    /// The connection failed, for the given reason.
+    Lost(String),
+}
+
+/// The state of the capture thread.
+struct Runner {
+    commands: Receiver<Command>,
+    events: Sender<CaptureEvent>,
+    stats: Arc<Mutex<CaptureStats>>,
+    limits: CaptureLimits,
+    /// Whether a stream failed while scaling, so later streams are left at
+    /// full size until the scale is changed.
+    unscaled: bool,
+    /// Whether the stream should be delivering frames. Kept across
+    /// reconnections, so a paused capture stays paused.
+    active: bool,
+    backoff: Backoff,
+}
+
+impl Runner {
+    /// Capture from `source` until stopped, reconnecting whenever the
+    /// connection is lost. Only returns an error if we can't reconnect.
+    fn run(
+        &mut self,
+        source: CaptureSource,
+        cancel: CancelToken,
+        fanout: Rc<RefCell<Fanout>>,
+    ) -> Result<(), Box<dyn Error>> {
+        let _guard = RuntimeGuard::new()?;
+
+        let mut remote = Remote::open(source, cancel)?;
+        if let Some(token) = remote.restore_token() {
+            self.send(CaptureEvent::RestoreToken(token));
+        }
+
+        let pw_loop = MainLoop::new()?;
+
+        loop {
+            self.send(CaptureEvent::Connecting);
+            let fd = remote.connection_fd()?;
+            let fanout = fanout.clone();
+            let reason = match self.capture(&pw_loop, fd, &remote.target, move |frame| {
+                fanout.borrow_mut().frame(frame)
+            }) {
+                Ok(Outcome::Stopped) => return Ok(()),
+                Ok(Outcome::Lost(reason)) => reason,
+                Err(err) => err.to_string(),
+            };
+
+            let retry_in = self.backoff.next_delay();
+            warn!("capture lost: {0}, retrying in {1:?}", reason, retry_in);
+            self.send(CaptureEvent::Disconnected { reason, retry_in });
+            if !self.wait(retry_in) {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Connect to the `target` node and capture from it until the connection
+    /// is lost or we are asked to stop.
+    fn capture<F>(
+        &mut self,
+        pw_loop: &MainLoop,
+        fd: Option<RawFd>,
+        target: &NodeTarget,
+        on_frame: F,
+    ) -> Result<Outcome, Box<dyn Error>>
+    where
+        F: FnMut(&Frame) + 'static,
+    {
+        let pw_context = Context::new(pw_loop)?;
+        let core = match fd {
+            Some(fd) => pw_context.connect_fd(fd, None)?,
+            None => pw_context.connect(None)?,
+        };
+
+        // Errors on the core itself mean the connection to the daemon is
+        // broken, for example because it has restarted.
+        let core_error = Rc::new(RefCell::new(None));
+        let listener_error = core_error.clone();
+        let _listener = core
+            .add_listener_local()
+            .error(move |id, _seq, res, message| {
+                if id == pipewire_sys::PW_ID_CORE {
+                    *listener_error.borrow_mut() =
+                        Some(format!("PipeWire error: {0} ({1})", message, res));
+                }
+            })
+            .register();
+
+        let stream = CaptureStream::connect(&core, target, self.stream_limits(), on_frame)?;
+        if !self.active {
+            stream.set_active(false)?;
+        }
+
+        let mut status = stream.status();
+        let mut format = None;
+        let mut scaling_refused = false;
+        let mut last_stats = Instant::now();
+        let reason = loop {
+            if let Some(err) = core_error.borrow_mut().take() {
+                break err;
+            }
+
+            let new_status = stream.status();
+            if new_status != status {
+                match &new_status {
+                    StreamStatus::Streaming => {
+                        self.backoff.reset();
+                        self.send(CaptureEvent::Streaming);
+                    }
+                    StreamStatus::Paused if status == StreamStatus::Streaming => {
+                        self.send(CaptureEvent::Paused)
+                    }
+                    StreamStatus::Error(err) => break format!("stream error: {0}", err),
+                    StreamStatus::Unconnected => break "stream disconnected".into(),
+                    StreamStatus::Connecting | StreamStatus::Paused => (),
+                }
+                status = new_status;
+            }
+
+            let new_format = stream.format();
+            if new_format != format {
+                if let Some(new_format) = new_format {
+                    self.send(CaptureEvent::FormatChanged(new_format));
+                    // Say again why the new format isn't scaled, as this is
+                    // shown in place of the last event.
+                    if scaling_refused || (self.unscaled && self.limits.scale < 100) {
+                        self.send(CaptureEvent::ScalingUnsupported);
+                    }
+                }
+                format = new_format;
+            }
+
+            if stream.scaling_refused() != scaling_refused {
+                scaling_refused = !scaling_refused;
+                if scaling_refused {
+                    warn!("the node can't scale, capturing at full size");
+                    self.send(CaptureEvent::ScalingUnsupported);
+                }
+            }
+
+            if last_stats.elapsed() >= STATS_INTERVAL {
+                *self.stats.lock().unwrap() = stream.stats();
+                last_stats = Instant::now();
+            }
+
+            match self.commands.try_recv() {
+                Ok(Command::SetLimits(limits)) => {
+                    self.set_limits(limits);
+                    if let Err(err) = stream.set_limits(self.stream_limits()) {
+                        error!("could not update capture limits: {0}", err);
+                    }
+                }
+                Ok(Command::SetActive(active)) => {
+                    self.active = active;
+                    if let Err(err) = stream.set_active(active) {
+                        error!("could not set capture active: {0}", err);
+                    }
+                }
+                Ok(Command::Stop) | Err(TryRecvError::Disconnected) => return Ok(Outcome::Stopped),
+                Err(TryRecvError::Empty) => unsafe {
+                    native_shims::pw_main_loop_iterate_rs(pw_loop.as_ptr(), 100);
+                },
+            }
+        };
+
+        // Nodes which can't scale may fail the renegotiation outright, rather
+        // than offering their full size again.
+        if stream.awaiting_scale() && !self.unscaled {
+            warn!("stream failed while scaling, capturing at full size");
+            self.unscaled = true;
+            self.send(CaptureEvent::ScalingUnsupported);
+        }
+        Ok(Outcome::Lost(reason))
+    }
+
+    /// Change the limits, giving scaling another try if the scale changed.
+    fn set_limits(&mut self, limits: CaptureLimits) {
+        if limits.scale != self.limits.scale {
+            self.unscaled = false;
+        }
+        self.limits = limits;
+    }
+
+    /// The limits to connect streams with, which leave out any scaling if it
+    /// has failed before.
+    fn stream_limits(&self) -> CaptureLimits {
+        if self.unscaled {
+            CaptureLimits {
+                scale: 100,
+                ..self.limits
+            }
+        } else {
+            self.limits
+        }
+    }
+
+    /// Wait for `delay` before reconnecting, while still handling commands.
+    /// Returns `false` if we were asked to stop while waiting.
+    fn wait(&mut self, delay: Duration) -> bool {
+        let deadline = Instant::now() + delay;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match self.commands.recv_timeout(remaining) {
+                Ok(Command::SetLimits(limits)) => self.set_limits(limits),
+                Ok(Command::SetActive(active)) => self.active = active,
+                Ok(Command::Stop) | Err(RecvTimeoutError::Disconnected) => return false,
+                Err(RecvTimeoutError::Timeout) => return true,
+            }
+        }
+    }
+
+    fn send(&self, event: CaptureEvent) {
+        let _ = self.events.send(event);
+    }
+}
+
+/// Where the runner connects to. For portal captures this keeps the screen
+/// cast open for as long as the runner is running.
+struct Remote {
+    screen_cast: Option<ActiveScreenCast>,
+    fd: Option<RawFd>,
+    target: NodeTarget,
+    connections: u32,
+}
+
+impl Remote {
+    /// Open the remote for `source`. For a portal capture this starts the
+    /// screen cast, prompting the user to choose what to share unless the
+    /// portal can restore a previous choice. Waiting for the portal stops
+    /// once `cancel` is cancelled.
+    fn open(source: CaptureSource, cancel: CancelToken) -> Result<Self, Box<dyn Error>> {
+        Ok(match source {
+            CaptureSource::Portal { restore_token } => {
+                let mut screen_cast = ScreenCast::with_cancel(cancel)?;
+                screen_cast.set_persist_mode(PersistMode::ExplicitlyRevoked);
+                if let Some(token) = &restore_token {
+                    screen_cast.set_restore_token(token);
+                }
+                let screen_cast = screen_cast.start(None)?;
+                let node = screen_cast
+                    .streams()
+                    .next()
+                    .ok_or("screen cast has no streams")?
+                    .pipewire_node();
+                info!("Screen cast started, capturing node {0}", node);
+                Remote {
+                    screen_cast: Some(screen_cast),
+                    fd: None,
+                    target: NodeTarget::Id(node),
+                    connections: 0,
+                }
+            }
+            CaptureSource::Node { fd, target } => Remote {
+                screen_cast: None,
+                fd,
+                target,
+                connections: 0,
+            },
+        })
+    }
+
+    /// The token the portal saved the user's selection under, if any.
+    fn restore_token(&self) -> Option<String> {
+        self.screen_cast
+            .as_ref()
+            .and_then(|screen_cast| screen_cast.restore_token())
+            .map(String::from)
+    }
+
+    /// Get the file descriptor for a new connection, or `None` to connect to
+    /// the default PipeWire daemon. PipeWire closes the file descriptor with
+    /// the connection, so each connection needs its own. For a portal capture
+    /// reconnecting asks the portal for a new remote on the existing session.
+    fn connection_fd(&mut self) -> Result<Option<RawFd>, Box<dyn Error>> {
+        let first = self.connections == 0;
+        self.connections += 1;
+        Ok(match (&self.screen_cast, self.fd) {
+            (Some(screen_cast), _) if first => Some(screen_cast.pipewire_fd()),
+            (Some(screen_cast), _) => Some(screen_cast.open_pipewire_remote()?),
+            (None, Some(fd)) => match unsafe { libc::dup(fd) } {
+                -1 => return Err(io::Error::last_os_error().into()),
+                fd => Some(fd),
+            },
+            (None, None) => None,
+        })
+    }
+}
+
+impl std::ops::Drop for Remote {
+    fn drop(&mut self) {
+        if let Some(fd) = self.fd {
+            unsafe {
+                libc::close(fd);
+            }
+        }
+    }
+}
@@ -0,0 +1,425 @@
+//! Consumers of captured video. A capture feeds any number of `FrameSink`s,
+//! each told about the format of the frames before receiving them, and told
+//! when the capture has ended for good.
+//!
+//! Frames borrow from PipeWire's buffers, so sinks which keep frames beyond
+//! the call copy them into an `OwnedFrame`.
+
+use log::warn;
+use std::{
+    collections::VecDeque,
+    sync::{
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+};
+
+use super::{pack_planes, Frame, FrameError, Plane};
+use crate::format::NegotiatedFormat;
+
+/// A consumer of the frames from a capture.
+pub trait FrameSink: Send {
+    /// Called before the first frame, and whenever the format of the frames
+    /// changes.
+    fn format_changed(&mut self, _format: NegotiatedFormat) {}
+
+    /// Called with each frame. The frame is only valid for this call.
+    fn frame(&mut self, frame: &Frame);
+
+    /// Called once the capture has ended, and no more frames will follow.
+    fn end_of_stream(&mut self) {}
+}
+
+impl<F> FrameSink for F
+where
+    F: FnMut(&Frame) + Send,
+{
+    fn frame(&mut self, frame: &Frame) {
+        self(frame)
+    }
+}
+
+/// Hands each frame to several sinks, telling them about format changes as
+/// the frames come in.
+#[derive(Default)]
+pub struct Fanout {
+    sinks: Vec<Box<dyn FrameSink>>,
+    format: Option<NegotiatedFormat>,
+}
+
+impl Fanout {
+    pub fn new(sinks: Vec<Box<dyn FrameSink>>) -> Self {
+        Fanout {
+            sinks,
+            format: None,
+        }
+    }
+
+    /// Add a sink. If frames have already been seen, it is told their format
+    /// straight away.
+    pub fn push(&mut self, mut sink: Box<dyn FrameSink>) {
+        if let Some(format) = self.format {
+            sink.format_changed(format);
+        }
+        self.sinks.push(sink);
+    }
+
+    /// The format of the most recent frame.
+    pub fn format(&self) -> Option<NegotiatedFormat> {
+        self.format
+    }
+}
+
+impl FrameSink for Fanout {
+    fn format_changed(&mut self, format: NegotiatedFormat) {
+        self.format = Some(format);
+        for sink in &mut self.sinks {
+            sink.format_changed(format);
+        }
+    }
+
+    fn frame(&mut self, frame: &Frame) {
+        let format = frame.negotiated();
+        if self.format != Some(format) {
+            self.format_changed(format);
+        }
+        for sink in &mut self.sinks {
+            sink.frame(frame);
+        }
+    }
+
+    fn end_of_stream(&mut self) {
+        for sink in &mut self.sinks {
+            sink.end_of_stream();
+        }
+    }
+}
+
+/// A frame copied out of PipeWire's buffers, with its planes packed one
+/// after another.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OwnedFrame {
+    pub format: NegotiatedFormat,
+    pub data: Vec<u8>,
+    /// The presentation timestamp in nanoseconds, if the producer sent one.
+    pub pts: Option<i64>,
+}
+
+impl OwnedFrame {
+    /// Copy `frame`, packing its planes.
+    pub fn copy(frame: &Frame) -> Result<Self, FrameError> {
+        let format = frame.negotiated();
+        let mut data = Vec::with_capacity(format.frame_size());
+        pack_planes(frame.planes(), &format.planes(), &mut data)?;
+        Ok(OwnedFrame {
+            format,
+            data,
+            pts: frame.pts(),
+        })
+    }
+
+    /// Replace this frame with a copy of `frame`, reusing its buffer.
+    pub fn copy_from(&mut self, frame: &Frame) -> Result<(), FrameError> {
+        self.format = frame.negotiated();
+        self.pts = frame.pts();
+        pack_planes(frame.planes(), &self.format.planes(), &mut self.data)
+    }
+
+    /// Get the planes of this frame, laid out as in its format.
+    pub fn planes(&self) -> Vec<Plane> {
+        let mut offset = 0;
+        self.format
+            .planes()
+            .iter()
+            .map(|layout| {
+                let start = offset.min(self.data.len());
+                offset += layout.size();
+                Plane {
+                    data: &self.data[start..offset.min(self.data.len())],
+                    stride: layout.stride,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Everything a sink can be told, for sinks which record or forward it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SinkEvent {
+    FormatChanged(NegotiatedFormat),
+    Frame(OwnedFrame),
+    EndOfStream,
+}
+
+/// A sink keeping a copy of everything it is told, for tests. Clones share
+/// what has been collected, so keep one to look at what a capture produced.
+#[derive(Debug, Clone, Default)]
+pub struct CollectorSink {
+    events: Arc<Mutex<Vec<SinkEvent>>>,
+}
+
+impl CollectorSink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Everything collected so far, in the order it arrived.
+    pub fn events(&self) -> Vec<SinkEvent> {
+        self.events.lock().unwrap().clone()
+    }
+
+    /// The frames collected so far.
+    pub fn frames(&self) -> Vec<OwnedFrame> {
+        self.events
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|event| match event {
+                SinkEvent::Frame(frame) => Some(frame.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn push(&self, event: SinkEvent) {
+        self.events.lock().unwrap().push(event);
+    }
+}
+
+impl FrameSink for CollectorSink {
+    fn format_changed(&mut self, format: NegotiatedFormat) {
+        self.push(SinkEvent::FormatChanged(format));
+    }
+
+    fn frame(&mut self, frame: &Frame) {
+        match OwnedFrame::copy(frame) {
+            Ok(frame) => self.push(SinkEvent::Frame(frame)),
+            Err(err) => warn!("could not collect frame: {0}", err),
+        }
+    }
+
+    fn end_of_stream(&mut self) {
+        self.push(SinkEvent::EndOfStream);
+    }
+}
+
+/// A sink sending copies of frames to another thread, without ever blocking
+/// the capture. Frames are dropped while the channel is full. Format changes
+/// and the end of the stream wait in an overflow instead, and are sent ahead
+/// of the next frame once there is room. Receivers should treat the channel
+/// disconnecting as the end of the stream too, as one still waiting when the
+/// sink is dropped is lost.
+pub struct ChannelSink {
+    sender: SyncSender<SinkEvent>,
+    /// Events which didn't fit in the channel, oldest first.
+    overflow: VecDeque<SinkEvent>,
+    dropped: u64,
+}
+
+impl ChannelSink {
+    /// Create a sink, and the receiver for its events. At most `bound`
+    /// events wait in the channel.
+    pub fn new(bound: usize) -> (Self, Receiver<SinkEvent>) {
+        let (sender, receiver) = mpsc::sync_channel(bound);
+        let sink = ChannelSink {
+            sender,
+            overflow: VecDeque::new(),
+            dropped: 0,
+        };
+        (sink, receiver)
+    }
+
+    /// The number of frames dropped because the channel was full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Queue a format change or the end of the stream, and send as much of
+    /// the overflow as fits. Only the latest of several format changes in a
+    /// row is kept.
+    fn send_control(&mut self, event: SinkEvent) {
+        if let (Some(SinkEvent::FormatChanged(_)), SinkEvent::FormatChanged(_)) =
+            (self.overflow.back(), &event)
+        {
+            self.overflow.pop_back();
+        }
+        self.overflow.push_back(event);
+        self.send_overflow();
+    }
+
+    /// Send as much of the overflow as fits in the channel. Returns whether
+    /// it has all been sent, or the receiver has gone.
+    fn send_overflow(&mut self) -> bool {
+        while let Some(event) = self.overflow.pop_front() {
+            match self.sender.try_send(event) {
+                Ok(()) => (),
+                Err(TrySendError::Full(event)) => {
+                    self.overflow.push_front(event);
+                    return false;
+                }
+                Err(TrySendError::Disconnected(_)) => self.overflow.clear(),
+            }
+        }
+        true
+    }
+}
+
+impl FrameSink for ChannelSink {
+    fn format_changed(&mut self, format: NegotiatedFormat) {
+        self.send_control(SinkEvent::FormatChanged(format));
+    }
+
+    fn frame(&mut self, frame: &Frame) {
+        // Frames can't overtake a format change still waiting to be sent.
+        if !self.send_overflow() {
+            self.dropped += 1;
+            return;
+        }
+        let frame = match OwnedFrame::copy(frame) {
+            Ok(frame) => frame,
+            Err(err) => {
+                warn!("could not send frame: {0}", err);
+                return;
+            }
+        };
+        if let Err(TrySendError::Full(_)) = self.sender.try_send(SinkEvent::Frame(frame)) {
+            self.dropped += 1;
+        }
+    }
+
+    fn end_of_stream(&mut self) {
+        self.send_control(SinkEvent::EndOfStream);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{Colorimetry, VideoFormat};
+
+    fn format(width: u32) -> NegotiatedFormat {
+        NegotiatedFormat {
+            format: VideoFormat::Bgrx,
+            width,
+            height: 1,
+            framerate: (30, 1),
+            colorimetry: Colorimetry::default(),
+        }
+    }
+
+    fn frame(format: NegotiatedFormat, data: &[u8]) -> Frame {
+        Frame::from_planes(
+            format,
+            vec![Plane {
+                data,
+                stride: format.stride(),
+            }],
+        )
+    }
+
+    #[test]
+    fn fanout_announces_formats_before_frames() {
+        let collector = CollectorSink::new();
+        let mut fanout = Fanout::new(vec![Box::new(collector.clone())]);
+        let data = [1, 2, 3, 4, 5, 6, 7, 8];
+
+        fanout.frame(&frame(format(1), &data[..4]));
+        fanout.frame(&frame(format(1), &data[4..]));
+        fanout.frame(&frame(format(2), &data));
+        fanout.end_of_stream();
+
+        let owned = |width, data: &[u8]| {
+            SinkEvent::Frame(OwnedFrame {
+                format: format(width),
+                data: data.to_vec(),
+                pts: None,
+            })
+        };
+        assert_eq!(
+            vec![
+                SinkEvent::FormatChanged(format(1)),
+                owned(1, &data[..4]),
+                owned(1, &data[4..]),
+                SinkEvent::FormatChanged(format(2)),
+                owned(2, &data),
+                SinkEvent::EndOfStream,
+            ],
+            collector.events()
+        );
+    }
+
+    #[test]
+    fn late_sinks_are_told_the_current_format() {
+        let mut fanout = Fanout::default();
+        fanout.frame(&frame(format(1), &[0; 4]));
+
+        let collector = CollectorSink::new();
+        fanout.push(Box::new(collector.clone()));
+        assert_eq!(
+            vec![SinkEvent::FormatChanged(format(1))],
+            collector.events()
+        );
+    }
+
+    #[test]
+    fn owned_frames_are_packed_and_reuse_their_buffer() {
+        let mut owned = OwnedFrame::copy(&frame(format(1), &[1, 2, 3, 4])).unwrap();
+        let padded = NegotiatedFormat {
+            height: 2,
+            ..format(1)
+        };
+        let data = [5, 6, 7, 8, 0, 0, 9, 10, 11, 12];
+        let padded_frame = Frame::from_planes(
+            padded,
+            vec![Plane {
+                data: &data,
+                stride: 6,
+            }],
+        );
+        owned.copy_from(&padded_frame).unwrap();
+
+        assert_eq!(padded, owned.format);
+        let planes = owned.planes();
+        assert_eq!(1, planes.len());
+        assert_eq!(&[5, 6, 7, 8, 9, 10, 11, 12][..], planes[0].data);
+        assert_eq!(4, planes[0].stride);
+    }
+
+    #[test]
+    fn channel_drops_frames_when_full() {
+        let (mut sink, receiver) = ChannelSink::new(1);
+        sink.frame(&frame(format(1), &[0; 4]));
+        sink.frame(&frame(format(1), &[1; 4]));
+        assert_eq!(1, sink.dropped());
+
+        match receiver.try_recv() {
+            Ok(SinkEvent::Frame(frame)) => assert_eq!(vec![0; 4], frame.data),
+            other => panic!("unexpected event {0:?}", other),
+        }
+    }
+
+    #[test]
+    fn channel_keeps_format_changes_in_order_without_blocking() {
+        let (mut sink, receiver) = ChannelSink::new(1);
+        sink.format_changed(format(1));
+        sink.format_changed(format(2));
+        sink.format_changed(format(3));
+        sink.frame(&frame(format(3), &[0; 12]));
+        assert_eq!(1, sink.dropped());
+
+        assert_eq!(
+            SinkEvent::FormatChanged(format(1)),
+            receiver.try_recv().unwrap()
+        );
+        sink.frame(&frame(format(3), &[1; 12]));
+        assert_eq!(2, sink.dropped());
+
+        assert_eq!(
+            SinkEvent::FormatChanged(format(3)),
+            receiver.try_recv().unwrap()
+        );
+        sink.end_of_stream();
+        assert_eq!(SinkEvent::EndOfStream, receiver.try_recv().unwrap());
+        assert!(receiver.try_recv().is_err());
+    }
+}
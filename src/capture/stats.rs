@@ -0,0 +1,236 @@
+//! Statistics about the frames passing through a capture stream. These help
+//! track down where stutter comes from: gaps in the producer's sequence
+//! numbers point at the compositor or PipeWire dropping frames, high buffer
+//! ages at delivery point at scheduling, and high processing times at our own
+//! copy path.
+
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+/// A snapshot of a capture stream's statistics. Totals are counted from when
+/// the stream connected. Rates and timings cover the interval since the
+/// previous snapshot.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CaptureStats {
+    /// Total frames delivered to the consumer.
+    pub frames: u64,
+    /// Total buffers received which couldn't be presented as frames.
+    pub skipped_frames: u64,
+    /// Total frames missing from the producer's sequence numbers.
+    pub dropped_frames: u64,
+    /// Total frames replaced in a latest-frame mailbox before the consumer
+    /// took them. The stream doesn't know about mailboxes, so this is filled
+    /// in by their owner.
+    pub superseded_frames: u64,
+    /// Frames delivered per second over the interval.
+    pub fps: f64,
+    /// Mean and maximum age of buffers when we dequeued them, measured from
+    /// the producer's timestamp. Zero if the producer doesn't send timestamps.
+    pub mean_latency: Duration,
+    pub max_latency: Duration,
+    /// Mean and maximum time spent handling each frame, including the
+    /// consumer's callback.
+    pub mean_processing: Duration,
+    pub max_processing: Duration,
+}
+
+impl fmt::Display for CaptureStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "fps={0:.1} frames={1} dropped={2} skipped={3} superseded={4} latency={5:.1}/{6:.1}ms processing={7:.1}/{8:.1}ms",
+            self.fps,
+            self.frames,
+            self.dropped_frames,
+            self.skipped_frames,
+            self.superseded_frames,
+            millis(self.mean_latency),
+            millis(self.max_latency),
+            millis(self.mean_processing),
+            millis(self.max_processing),
+        )
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// Accumulates statistics as frames are received.
+#[derive(Debug)]
+pub(crate) struct StatsTracker {
+    frames: u64,
+    skipped_frames: u64,
+    dropped_frames: u64,
+    last_sequence: Option<u64>,
+    interval_start: Instant,
+    interval_frames: u64,
+    latency: Timings,
+    processing: Timings,
+}
+
+/// Mean and max of a set of durations.
+#[derive(Debug, Default)]
+struct Timings {
+    count: u32,
+    total: Duration,
+    max: Duration,
+}
+
+impl Timings {
+    fn add(&mut self, duration: Duration) {
+        self.count += 1;
+        self.total += duration;
+        self.max = self.max.max(duration);
+    }
+
+    fn mean(&self) -> Duration {
+        match self.count {
+            0 => Duration::default(),
+            count => self.total / count,
+        }
+    }
+}
+
+impl StatsTracker {
+    pub fn new(now: Instant) -> Self {
+        StatsTracker {
+            frames: 0,
+            skipped_frames: 0,
+            dropped_frames: 0,
+            last_sequence: None,
+            interval_start: now,
+            interval_frames: 0,
+            latency: Timings::default(),
+            processing: Timings::default(),
+        }
+    }
+
+    /// Record a buffer we couldn't present as a frame.
+    pub fn skipped(&mut self) {
+        self.skipped_frames += 1;
+    }
+
+    /// Record a frame delivered to the consumer. The `sequence` and `pts` come
+    /// from the buffer's header, if it has one. The `now` is the current time
+    /// on the same clock as the `pts`.
+    pub fn delivered(
+        &mut self,
+        sequence: Option<u64>,
+        pts: Option<i64>,
+        now: i64,
+        processing: Duration,
+    ) {
+        self.frames += 1;
+        self.interval_frames += 1;
+        self.processing.add(processing);
+
+        if let Some(sequence) = sequence {
+            if let Some(last) = self.last_sequence {
+                if sequence > last {
+                    self.dropped_frames += sequence - last - 1;
+                }
+            }
+            self.last_sequence = Some(sequence);
+        }
+
+        if let Some(pts) = pts.filter(|pts| *pts > 0 && *pts <= now) {
+            self.latency.add(Duration::from_nanos((now - pts) as u64));
+        }
+    }
+
+    /// Take a snapshot of the stats, and start a new interval.
+    pub fn snapshot(&mut self, now: Instant) -> CaptureStats {
+        let elapsed = now
+            .saturating_duration_since(self.interval_start)
+            .as_secs_f64();
+        let stats = CaptureStats {
+            frames: self.frames,
+            skipped_frames: self.skipped_frames,
+            dropped_frames: self.dropped_frames,
+            superseded_frames: 0,
+            fps: if elapsed > 0.0 {
+                self.interval_frames as f64 / elapsed
+            } else {
+                0.0
+            },
+            mean_latency: self.latency.mean(),
+            max_latency: self.latency.max,
+            mean_processing: self.processing.mean(),
+            max_processing: self.processing.max,
+        };
+
+        self.interval_start = now;
+        self.interval_frames = 0;
+        self.latency = Timings::default();
+        self.processing = Timings::default();
+
+        stats
+    }
+}
+
+/// Get the current time of the monotonic clock, in nanoseconds. PipeWire
+/// timestamps buffers using this clock.
+pub(crate) fn monotonic_now() -> i64 {
+    let mut time = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe {
+        libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut time);
+    }
+    time.tv_sec as i64 * 1_000_000_000 + time.tv_nsec as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sequence_gaps_are_dropped_frames() {
+        let start = Instant::now();
+        let mut tracker = StatsTracker::new(start);
+        for sequence in &[1, 2, 5, 6, 10] {
+            tracker.delivered(Some(*sequence), None, 0, Duration::from_millis(1));
+        }
+        tracker.skipped();
+
+        let stats = tracker.snapshot(start + Duration::from_secs(1));
+        assert_eq!(5, stats.frames);
+        assert_eq!(5, stats.dropped_frames);
+        assert_eq!(1, stats.skipped_frames);
+        assert_eq!(5.0, stats.fps);
+        assert_eq!(Duration::from_millis(1), stats.mean_processing);
+    }
+
+    #[test]
+    fn latency_from_pts() {
+        let start = Instant::now();
+        let mut tracker = StatsTracker::new(start);
+        tracker.delivered(None, Some(1_000_000), 3_000_000, Duration::default());
+        tracker.delivered(None, Some(2_000_000), 8_000_000, Duration::default());
+        // Timestamps from the future, or missing, are ignored.
+        tracker.delivered(None, Some(9_000_000), 8_000_000, Duration::default());
+        tracker.delivered(None, None, 8_000_000, Duration::default());
+
+        let stats = tracker.snapshot(start + Duration::from_secs(2));
+        assert_eq!(2.0, stats.fps);
+        assert_eq!(Duration::from_millis(4), stats.mean_latency);
+        assert_eq!(Duration::from_millis(6), stats.max_latency);
+    }
+
+    #[test]
+    fn snapshot_starts_new_interval() {
+        let start = Instant::now();
+        let mut tracker = StatsTracker::new(start);
+        tracker.delivered(None, None, 0, Duration::from_millis(5));
+        tracker.snapshot(start + Duration::from_secs(1));
+
+        let stats = tracker.snapshot(start + Duration::from_secs(2));
+        assert_eq!(1, stats.frames);
+        assert_eq!(0.0, stats.fps);
+        assert_eq!(Duration::default(), stats.max_processing);
+    }
+}
@@ -0,0 +1,765 @@
+//! Raw video and audio formats we negotiate with PipeWire, along with the
+//! information needed to find each plane within a buffer and hand frames on
+//! to OBS.
+
+use obs_wrapper::obs_sys;
+use std::mem;
+
+use crate::native_shims;
+
+/// A raw video format that we are able to accept from a PipeWire stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VideoFormat {
+    Rgba,
+    Rgbx,
+    Bgrx,
+    Bgra,
+    /// 8-bit Y plane followed by an interleaved, 2x2 subsampled, UV plane.
+    Nv12,
+    /// 8-bit Y plane followed by 2x2 subsampled U and V planes.
+    I420,
+    /// Packed 4:2:2 YUV, in Y0-U0-Y1-V0 order.
+    Yuy2,
+    /// 10-bit RGB packed into little-endian 32-bit words, with blue in the
+    /// lowest bits and two bits of padding in the highest.
+    Xrgb210Le,
+    /// As `Xrgb210Le`, but with red in the lowest bits.
+    Xbgr210Le,
+}
+
+/// The size of a single plane within a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PlaneLayout {
+    /// The number of bytes from the start of one row to the next.
+    pub stride: u32,
+    /// The number of rows in the plane.
+    pub height: u32,
+}
+
+impl PlaneLayout {
+    /// The total size of the plane in bytes.
+    pub fn size(&self) -> usize {
+        self.stride as usize * self.height as usize
+    }
+}
+
+impl VideoFormat {
+    /// Convert a raw `spa_video_format` into one of our supported formats.
+    pub fn from_spa(format: u32) -> Option<Self> {
+        Some(match format {
+            libspa_sys::spa_video_format_SPA_VIDEO_FORMAT_RGBA => VideoFormat::Rgba,
+            libspa_sys::spa_video_format_SPA_VIDEO_FORMAT_RGBx => VideoFormat::Rgbx,
+            libspa_sys::spa_video_format_SPA_VIDEO_FORMAT_BGRx => VideoFormat::Bgrx,
+            libspa_sys::spa_video_format_SPA_VIDEO_FORMAT_BGRA => VideoFormat::Bgra,
+            libspa_sys::spa_video_format_SPA_VIDEO_FORMAT_NV12 => VideoFormat::Nv12,
+            libspa_sys::spa_video_format_SPA_VIDEO_FORMAT_I420 => VideoFormat::I420,
+            libspa_sys::spa_video_format_SPA_VIDEO_FORMAT_YUY2 => VideoFormat::Yuy2,
+            libspa_sys::spa_video_format_SPA_VIDEO_FORMAT_xRGB_210LE => VideoFormat::Xrgb210Le,
+            libspa_sys::spa_video_format_SPA_VIDEO_FORMAT_xBGR_210LE => VideoFormat::Xbgr210Le,
+            _ => return None,
+        })
+    }
+
+    /// Get the raw `spa_video_format` for this format.
+    pub fn to_spa(self) -> u32 {
+        match self {
+            VideoFormat::Rgba => libspa_sys::spa_video_format_SPA_VIDEO_FORMAT_RGBA,
+            VideoFormat::Rgbx => libspa_sys::spa_video_format_SPA_VIDEO_FORMAT_RGBx,
+            VideoFormat::Bgrx => libspa_sys::spa_video_format_SPA_VIDEO_FORMAT_BGRx,
+            VideoFormat::Bgra => libspa_sys::spa_video_format_SPA_VIDEO_FORMAT_BGRA,
+            VideoFormat::Nv12 => libspa_sys::spa_video_format_SPA_VIDEO_FORMAT_NV12,
+            VideoFormat::I420 => libspa_sys::spa_video_format_SPA_VIDEO_FORMAT_I420,
+            VideoFormat::Yuy2 => libspa_sys::spa_video_format_SPA_VIDEO_FORMAT_YUY2,
+            VideoFormat::Xrgb210Le => libspa_sys::spa_video_format_SPA_VIDEO_FORMAT_xRGB_210LE,
+            VideoFormat::Xbgr210Le => libspa_sys::spa_video_format_SPA_VIDEO_FORMAT_xBGR_210LE,
+        }
+    }
+
+    /// Is this one of the YUV formats?
+    pub fn is_yuv(self) -> bool {
+        matches!(
+            self,
+            VideoFormat::Nv12 | VideoFormat::I420 | VideoFormat::Yuy2
+        )
+    }
+
+    /// Does this format have more than 8 bits per channel?
+    pub fn is_high_bit_depth(self) -> bool {
+        matches!(self, VideoFormat::Xrgb210Le | VideoFormat::Xbgr210Le)
+    }
+
+    /// The number of separate planes a frame of this format is made of.
+    pub fn plane_count(self) -> usize {
+        match self {
+            VideoFormat::Nv12 => 2,
+            VideoFormat::I420 => 3,
+            _ => 1,
+        }
+    }
+
+    /// What positions within a frame must be a multiple of, because of chroma
+    /// subsampling.
+    pub fn alignment(self) -> u32 {
+        if self.is_yuv() {
+            2
+        } else {
+            1
+        }
+    }
+
+    /// The offset in bytes of pixel `(x, y)` within `plane`, which has the
+    /// given `stride`. The position must be a multiple of `alignment()`.
+    pub fn offset(self, plane: usize, x: u32, y: u32, stride: u32) -> usize {
+        let (column, row) = match (self, plane) {
+            (VideoFormat::Nv12, 0) | (VideoFormat::I420, 0) => (x, y),
+            (VideoFormat::Nv12, _) => (x, y / 2),
+            (VideoFormat::I420, _) => (x / 2, y / 2),
+            (VideoFormat::Yuy2, _) => (x * 2, y),
+            _ => (x * 4, y),
+        };
+        row as usize * stride as usize + column as usize
+    }
+
+    /// The minimum stride for the first plane of a frame `width` pixels wide.
+    pub fn default_stride(self, width: u32) -> u32 {
+        match self {
+            VideoFormat::Nv12 | VideoFormat::I420 => width,
+            VideoFormat::Yuy2 => round_up_2(width) * 2,
+            _ => width * 4,
+        }
+    }
+
+    /// Get the layout of each plane for a frame of the given size. The
+    /// `stride` is the stride of the first plane, as reported by the buffer.
+    /// The strides of any subsequent planes are derived from it.
+    pub fn planes(self, stride: u32, height: u32) -> Vec<PlaneLayout> {
+        let chroma_height = (height + 1) / 2;
+        let main = PlaneLayout { stride, height };
+        match self {
+            VideoFormat::Nv12 => vec![
+                main,
+                PlaneLayout {
+                    stride,
+                    height: chroma_height,
+                },
+            ],
+            VideoFormat::I420 => {
+                let chroma = PlaneLayout {
+                    stride: (stride + 1) / 2,
+                    height: chroma_height,
+                };
+                vec![main, chroma, chroma]
+            }
+            _ => vec![main],
+        }
+    }
+
+    /// Get the OBS video format that matches this format.
+    ///
+    /// OBS has no format with a padding byte in place of alpha in RGB order,
+    /// so `Rgbx` is handed over as `RGBA`. Compositors fill the padding byte
+    /// with `0xff` in practice.
+    ///
+    /// OBS's only 10-bit RGB format is packed as `Xrgb210Le`, so `Xbgr210Le`
+    /// frames must have their red and blue swapped with `swap_red_blue_210`
+    /// before being handed over.
+    pub fn obs_video_format(self) -> obs_sys::video_format {
+        match self {
+            VideoFormat::Rgba | VideoFormat::Rgbx => obs_sys::video_format_VIDEO_FORMAT_RGBA,
+            VideoFormat::Bgrx => obs_sys::video_format_VIDEO_FORMAT_BGRX,
+            VideoFormat::Bgra => obs_sys::video_format_VIDEO_FORMAT_BGRA,
+            VideoFormat::Nv12 => obs_sys::video_format_VIDEO_FORMAT_NV12,
+            VideoFormat::I420 => obs_sys::video_format_VIDEO_FORMAT_I420,
+            VideoFormat::Yuy2 => obs_sys::video_format_VIDEO_FORMAT_YUY2,
+            VideoFormat::Xrgb210Le | VideoFormat::Xbgr210Le => {
+                obs_sys::video_format_VIDEO_FORMAT_R10L
+            }
+        }
+    }
+}
+
+/// Swap the red and blue channels of packed 10-bit RGB, converting between
+/// `Xbgr210Le` and `Xrgb210Le` in place.
+pub fn swap_red_blue_210(data: &mut [u8]) {
+    for pixel in data.chunks_exact_mut(4) {
+        let value = u32::from_le_bytes([pixel[0], pixel[1], pixel[2], pixel[3]]);
+        let low = value & 0x3ff;
+        let high = (value >> 20) & 0x3ff;
+        let swapped = (value & !(0x3ff | (0x3ff << 20))) | high | (low << 20);
+        pixel.copy_from_slice(&swapped.to_le_bytes());
+    }
+}
+
+/// The transfer function video was encoded with. HDR video uses either PQ or
+/// HLG, everything else is treated as SDR.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferFunction {
+    Unknown,
+    Srgb,
+    /// The BT.709 curve, which BT.601 and SDR BT.2020 share.
+    Bt709,
+    /// SMPTE ST 2084, the perceptual quantizer.
+    Pq,
+    /// ARIB STD-B67, hybrid log-gamma.
+    Hlg,
+}
+
+impl TransferFunction {
+    /// Convert a raw `spa_video_transfer_function`.
+    pub fn from_spa(transfer: u32) -> Self {
+        match transfer {
+            libspa_sys::spa_video_transfer_function_SPA_VIDEO_TRANSFER_SRGB => {
+                TransferFunction::Srgb
+            }
+            libspa_sys::spa_video_transfer_function_SPA_VIDEO_TRANSFER_BT709
+            | libspa_sys::spa_video_transfer_function_SPA_VIDEO_TRANSFER_BT601
+            | libspa_sys::spa_video_transfer_function_SPA_VIDEO_TRANSFER_BT2020_10
+            | libspa_sys::spa_video_transfer_function_SPA_VIDEO_TRANSFER_BT2020_12 => {
+                TransferFunction::Bt709
+            }
+            libspa_sys::spa_video_transfer_function_SPA_VIDEO_TRANSFER_SMPTE2084 => {
+                TransferFunction::Pq
+            }
+            libspa_sys::spa_video_transfer_function_SPA_VIDEO_TRANSFER_ARIB_STD_B67 => {
+                TransferFunction::Hlg
+            }
+            _ => TransferFunction::Unknown,
+        }
+    }
+
+    pub fn is_hdr(self) -> bool {
+        matches!(self, TransferFunction::Pq | TransferFunction::Hlg)
+    }
+}
+
+impl Default for TransferFunction {
+    fn default() -> Self {
+        TransferFunction::Unknown
+    }
+}
+
+/// The primaries video's colors are relative to, which bound the volume of
+/// colors it can hold. OBS only takes a colorspace, so the primaries are just
+/// a hint at the matrix when the producer doesn't give one, and only those
+/// with a matching OBS colorspace are told apart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorPrimaries {
+    Unknown,
+    Bt709,
+    Bt601,
+}
+
+impl ColorPrimaries {
+    /// Convert a raw `spa_video_color_primaries`.
+    pub fn from_spa(primaries: u32) -> Self {
+        match primaries {
+            libspa_sys::spa_video_color_primaries_SPA_VIDEO_COLOR_PRIMARIES_BT709 => {
+                ColorPrimaries::Bt709
+            }
+            libspa_sys::spa_video_color_primaries_SPA_VIDEO_COLOR_PRIMARIES_BT470BG
+            | libspa_sys::spa_video_color_primaries_SPA_VIDEO_COLOR_PRIMARIES_SMPTE170M => {
+                ColorPrimaries::Bt601
+            }
+            _ => ColorPrimaries::Unknown,
+        }
+    }
+}
+
+impl Default for ColorPrimaries {
+    fn default() -> Self {
+        ColorPrimaries::Unknown
+    }
+}
+
+/// The range of values YUV video uses. Limited range video leaves headroom
+/// below black and above white, which full range video uses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorRange {
+    Unknown,
+    Full,
+    Limited,
+}
+
+impl ColorRange {
+    /// Convert a raw `spa_video_color_range`.
+    pub fn from_spa(range: u32) -> Self {
+        match range {
+            libspa_sys::spa_video_color_range_SPA_VIDEO_COLOR_RANGE_0_255 => ColorRange::Full,
+            libspa_sys::spa_video_color_range_SPA_VIDEO_COLOR_RANGE_16_235 => ColorRange::Limited,
+            _ => ColorRange::Unknown,
+        }
+    }
+}
+
+impl Default for ColorRange {
+    fn default() -> Self {
+        ColorRange::Unknown
+    }
+}
+
+/// The matrix YUV video was converted from RGB with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorMatrix {
+    Unknown,
+    Rgb,
+    Bt601,
+    Bt709,
+    Bt2020,
+}
+
+impl ColorMatrix {
+    /// Convert a raw `spa_video_color_matrix`.
+    pub fn from_spa(matrix: u32) -> Self {
+        match matrix {
+            libspa_sys::spa_video_color_matrix_SPA_VIDEO_COLOR_MATRIX_RGB => ColorMatrix::Rgb,
+            libspa_sys::spa_video_color_matrix_SPA_VIDEO_COLOR_MATRIX_BT601
+            | libspa_sys::spa_video_color_matrix_SPA_VIDEO_COLOR_MATRIX_FCC
+            | libspa_sys::spa_video_color_matrix_SPA_VIDEO_COLOR_MATRIX_SMPTE240M => {
+                ColorMatrix::Bt601
+            }
+            libspa_sys::spa_video_color_matrix_SPA_VIDEO_COLOR_MATRIX_BT709 => ColorMatrix::Bt709,
+            libspa_sys::spa_video_color_matrix_SPA_VIDEO_COLOR_MATRIX_BT2020 => ColorMatrix::Bt2020,
+            _ => ColorMatrix::Unknown,
+        }
+    }
+}
+
+impl Default for ColorMatrix {
+    fn default() -> Self {
+        ColorMatrix::Unknown
+    }
+}
+
+/// How the colors of negotiated video are to be interpreted. Producers need
+/// not say, in which case everything is `Unknown` and treated as sRGB.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Colorimetry {
+    pub range: ColorRange,
+    pub matrix: ColorMatrix,
+    pub transfer: TransferFunction,
+    pub primaries: ColorPrimaries,
+}
+
+impl Colorimetry {
+    /// Is this high dynamic range video?
+    pub fn is_hdr(&self) -> bool {
+        self.transfer.is_hdr()
+    }
+
+    /// Get the OBS transfer characteristics for video with this colorimetry.
+    pub fn obs_trc(&self) -> obs_sys::video_trc {
+        match self.transfer {
+            TransferFunction::Pq => obs_sys::video_trc_VIDEO_TRC_PQ,
+            TransferFunction::Hlg => obs_sys::video_trc_VIDEO_TRC_HLG,
+            TransferFunction::Srgb => obs_sys::video_trc_VIDEO_TRC_SRGB,
+            TransferFunction::Bt709 | TransferFunction::Unknown => {
+                obs_sys::video_trc_VIDEO_TRC_DEFAULT
+            }
+        }
+    }
+
+    /// Get the OBS colorspace for YUV video with this colorimetry. HDR video
+    /// is BT.2100 whatever its matrix says. Otherwise, when the producer
+    /// doesn't say which matrix it used, the primaries are a good guess.
+    pub fn obs_colorspace(&self) -> obs_sys::video_colorspace {
+        match (self.transfer, self.matrix, self.primaries) {
+            (TransferFunction::Pq, _, _) => obs_sys::video_colorspace_VIDEO_CS_2100_PQ,
+            (TransferFunction::Hlg, _, _) => obs_sys::video_colorspace_VIDEO_CS_2100_HLG,
+            (_, ColorMatrix::Bt709, _) | (_, ColorMatrix::Unknown, ColorPrimaries::Bt709) => {
+                obs_sys::video_colorspace_VIDEO_CS_709
+            }
+            (_, ColorMatrix::Bt601, _) | (_, ColorMatrix::Unknown, ColorPrimaries::Bt601) => {
+                obs_sys::video_colorspace_VIDEO_CS_601
+            }
+            _ => obs_sys::video_colorspace_VIDEO_CS_DEFAULT,
+        }
+    }
+
+    /// Get the OBS range type for YUV video with this colorimetry.
+    pub fn obs_range(&self) -> obs_sys::video_range_type {
+        match self.range {
+            ColorRange::Full => obs_sys::video_range_type_VIDEO_RANGE_FULL,
+            ColorRange::Limited => obs_sys::video_range_type_VIDEO_RANGE_PARTIAL,
+            ColorRange::Unknown => obs_sys::video_range_type_VIDEO_RANGE_DEFAULT,
+        }
+    }
+}
+
+/// The video format agreed with the PipeWire node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NegotiatedFormat {
+    pub format: VideoFormat,
+    pub width: u32,
+    pub height: u32,
+    /// Framerate as a `(numerator, denominator)` pair. A numerator of `0`
+    /// denotes a variable framerate.
+    pub framerate: (u32, u32),
+    pub colorimetry: Colorimetry,
+}
+
+impl NegotiatedFormat {
+    /// Parse the negotiated format from an `SPA_PARAM_Format` POD. Returns
+    /// `None` if the POD isn't raw video in one of our supported formats.
+    ///
+    /// # Safety
+    ///
+    /// The `param` must point to a valid SPA POD.
+    pub unsafe fn from_pod(param: *const libspa_sys::spa_pod) -> Option<Self> {
+        let mut media_type = 0;
+        let mut media_subtype = 0;
+        if native_shims::spa_format_parse_rs(param, &mut media_type, &mut media_subtype) < 0
+            || media_type != libspa_sys::spa_media_type_SPA_MEDIA_TYPE_video
+            || media_subtype != libspa_sys::spa_media_subtype_SPA_MEDIA_SUBTYPE_raw
+        {
+            return None;
+        }
+
+        let mut info: libspa_sys::spa_video_info_raw = mem::zeroed();
+        if native_shims::spa_format_video_raw_parse_rs(param, &mut info) < 0 {
+            return None;
+        }
+
+        Some(NegotiatedFormat {
+            format: VideoFormat::from_spa(info.format)?,
+            width: info.size.width,
+            height: info.size.height,
+            framerate: (info.framerate.num, info.framerate.denom),
+            colorimetry: Colorimetry {
+                range: ColorRange::from_spa(info.color_range),
+                matrix: ColorMatrix::from_spa(info.color_matrix),
+                transfer: TransferFunction::from_spa(info.transfer_function),
+                primaries: ColorPrimaries::from_spa(info.color_primaries),
+            },
+        })
+    }
+
+    /// The stride of the first plane, with rows packed without padding.
+    pub fn stride(&self) -> u32 {
+        self.format.default_stride(self.width)
+    }
+
+    /// The layout of each plane, with rows packed without padding.
+    pub fn planes(&self) -> Vec<PlaneLayout> {
+        self.format.planes(self.stride(), self.height)
+    }
+
+    /// The size of a whole frame with its planes packed one after another.
+    pub fn frame_size(&self) -> usize {
+        self.planes().iter().map(PlaneLayout::size).sum()
+    }
+
+    /// The size of the largest plane in a frame.
+    pub fn largest_plane_size(&self) -> usize {
+        self.planes()
+            .iter()
+            .map(PlaneLayout::size)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+/// A raw audio sample format that we are able to accept from a PipeWire
+/// stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioFormat {
+    /// 32-bit float samples, with one plane per channel.
+    F32Planar,
+    /// Native endian signed 16-bit samples, with channels interleaved.
+    S16,
+}
+
+impl AudioFormat {
+    /// Convert a raw `spa_audio_format` into one of our supported formats.
+    pub fn from_spa(format: u32) -> Option<Self> {
+        Some(match format {
+            libspa_sys::spa_audio_format_SPA_AUDIO_FORMAT_F32P => AudioFormat::F32Planar,
+            libspa_sys::spa_audio_format_SPA_AUDIO_FORMAT_S16 => AudioFormat::S16,
+            _ => return None,
+        })
+    }
+
+    /// The size of a single sample of a single channel, in bytes.
+    pub fn sample_size(self) -> usize {
+        match self {
+            AudioFormat::F32Planar => 4,
+            AudioFormat::S16 => 2,
+        }
+    }
+
+    /// Does each channel have its own plane?
+    pub fn is_planar(self) -> bool {
+        matches!(self, AudioFormat::F32Planar)
+    }
+
+    /// Get the OBS audio format that matches this format.
+    pub fn obs_audio_format(self) -> obs_sys::audio_format {
+        match self {
+            AudioFormat::F32Planar => obs_sys::audio_format_AUDIO_FORMAT_FLOAT_PLANAR,
+            AudioFormat::S16 => obs_sys::audio_format_AUDIO_FORMAT_16BIT,
+        }
+    }
+}
+
+/// The audio format agreed with the PipeWire node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NegotiatedAudio {
+    pub format: AudioFormat,
+    /// Samples per second.
+    pub rate: u32,
+    pub channels: u32,
+}
+
+impl NegotiatedAudio {
+    /// The number of planes each buffer holds.
+    pub fn plane_count(&self) -> usize {
+        if self.format.is_planar() {
+            self.channels as usize
+        } else {
+            1
+        }
+    }
+
+    /// The number of bytes taken by one sample of every channel, within a
+    /// single plane.
+    pub fn frame_size(&self) -> usize {
+        if self.format.is_planar() {
+            self.format.sample_size()
+        } else {
+            self.format.sample_size() * self.channels as usize
+        }
+    }
+
+    /// Parse the negotiated format from an `SPA_PARAM_Format` POD. Returns
+    /// `None` if the POD isn't raw audio in one of our supported formats.
+    ///
+    /// # Safety
+    ///
+    /// The `param` must point to a valid SPA POD.
+    pub unsafe fn from_pod(param: *const libspa_sys::spa_pod) -> Option<Self> {
+        let mut media_type = 0;
+        let mut media_subtype = 0;
+        if native_shims::spa_format_parse_rs(param, &mut media_type, &mut media_subtype) < 0
+            || media_type != libspa_sys::spa_media_type_SPA_MEDIA_TYPE_audio
+            || media_subtype != libspa_sys::spa_media_subtype_SPA_MEDIA_SUBTYPE_raw
+        {
+            return None;
+        }
+
+        let mut info: libspa_sys::spa_audio_info_raw = mem::zeroed();
+        if native_shims::spa_format_audio_raw_parse_rs(param, &mut info) < 0
+            || info.rate == 0
+            || info.channels == 0
+        {
+            return None;
+        }
+
+        Some(NegotiatedAudio {
+            format: AudioFormat::from_spa(info.format)?,
+            rate: info.rate,
+            channels: info.channels,
+        })
+    }
+}
+
+fn round_up_2(value: u32) -> u32 {
+    (value + 1) & !1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packed_formats_have_one_plane() {
+        for format in &[VideoFormat::Rgba, VideoFormat::Bgrx, VideoFormat::Yuy2] {
+            assert_eq!(1, format.plane_count());
+            assert_eq!(1, format.planes(format.default_stride(64), 48).len());
+        }
+        assert_eq!(256, VideoFormat::Bgrx.default_stride(64));
+        assert_eq!(132, VideoFormat::Yuy2.default_stride(65));
+    }
+
+    #[test]
+    fn nv12_planes() {
+        let planes = VideoFormat::Nv12.planes(1920, 1080);
+        assert_eq!(
+            vec![
+                PlaneLayout {
+                    stride: 1920,
+                    height: 1080
+                },
+                PlaneLayout {
+                    stride: 1920,
+                    height: 540
+                },
+            ],
+            planes
+        );
+    }
+
+    #[test]
+    fn i420_planes_with_odd_size() {
+        let planes = VideoFormat::I420.planes(641, 481);
+        assert_eq!(3, planes.len());
+        assert_eq!(641 * 481, planes[0].size());
+        assert_eq!(
+            PlaneLayout {
+                stride: 321,
+                height: 241
+            },
+            planes[1]
+        );
+        assert_eq!(planes[1], planes[2]);
+    }
+
+    #[test]
+    fn offsets_account_for_subsampling() {
+        assert_eq!(2 * 100 + 8, VideoFormat::Bgrx.offset(0, 2, 2, 100));
+        assert_eq!(4 * 100 + 4, VideoFormat::Yuy2.offset(0, 2, 4, 100));
+        assert_eq!(2 * 64 + 4, VideoFormat::Nv12.offset(1, 4, 4, 64));
+        assert_eq!(2 * 32 + 2, VideoFormat::I420.offset(2, 4, 4, 32));
+    }
+
+    #[test]
+    fn frame_sizes() {
+        let nv12 = NegotiatedFormat {
+            format: VideoFormat::Nv12,
+            width: 1920,
+            height: 1080,
+            framerate: (60, 1),
+            colorimetry: Colorimetry::default(),
+        };
+        assert_eq!(1920, nv12.stride());
+        assert_eq!(1920 * 1080 * 3 / 2, nv12.frame_size());
+        assert_eq!(1920 * 1080, nv12.largest_plane_size());
+
+        let bgrx = NegotiatedFormat {
+            format: VideoFormat::Bgrx,
+            width: 641,
+            height: 480,
+            framerate: (0, 1),
+            colorimetry: Colorimetry::default(),
+        };
+        assert_eq!(641 * 4, bgrx.stride());
+        assert_eq!(641 * 4 * 480, bgrx.frame_size());
+    }
+
+    #[test]
+    fn spa_formats_round_trip() {
+        for format in &[
+            VideoFormat::Rgba,
+            VideoFormat::Rgbx,
+            VideoFormat::Bgrx,
+            VideoFormat::Bgra,
+            VideoFormat::Nv12,
+            VideoFormat::I420,
+            VideoFormat::Yuy2,
+            VideoFormat::Xrgb210Le,
+            VideoFormat::Xbgr210Le,
+        ] {
+            assert_eq!(Some(*format), VideoFormat::from_spa(format.to_spa()));
+        }
+    }
+
+    #[test]
+    fn audio_plane_layout() {
+        let planar = NegotiatedAudio {
+            format: AudioFormat::F32Planar,
+            rate: 48000,
+            channels: 6,
+        };
+        assert_eq!(6, planar.plane_count());
+        assert_eq!(4, planar.frame_size());
+
+        let interleaved = NegotiatedAudio {
+            format: AudioFormat::S16,
+            rate: 44100,
+            channels: 2,
+        };
+        assert_eq!(1, interleaved.plane_count());
+        assert_eq!(4, interleaved.frame_size());
+    }
+
+    #[test]
+    fn swapping_red_and_blue_keeps_green_and_padding() {
+        let xbgr: u32 = (0b11 << 30) | (0x155 << 20) | (0x2aa << 10) | 0x3ff;
+        let mut data = xbgr.to_le_bytes().to_vec();
+        swap_red_blue_210(&mut data);
+
+        let xrgb: u32 = (0b11 << 30) | (0x3ff << 20) | (0x2aa << 10) | 0x155;
+        assert_eq!(xrgb.to_le_bytes().to_vec(), data);
+    }
+
+    #[test]
+    fn only_pq_and_hlg_are_hdr() {
+        assert!(Colorimetry {
+            transfer: TransferFunction::Pq,
+            matrix: ColorMatrix::Bt2020,
+            ..Colorimetry::default()
+        }
+        .is_hdr());
+        assert!(TransferFunction::Hlg.is_hdr());
+        assert!(!TransferFunction::Bt709.is_hdr());
+        assert!(!Colorimetry::default().is_hdr());
+    }
+
+    #[test]
+    fn colorimetry_picks_obs_colorspace_and_range() {
+        let limited_709 = Colorimetry {
+            range: ColorRange::Limited,
+            matrix: ColorMatrix::Bt709,
+            ..Colorimetry::default()
+        };
+        assert_eq!(
+            obs_sys::video_colorspace_VIDEO_CS_709,
+            limited_709.obs_colorspace()
+        );
+        assert_eq!(
+            obs_sys::video_range_type_VIDEO_RANGE_PARTIAL,
+            limited_709.obs_range()
+        );
+
+        let full_601 = Colorimetry {
+            range: ColorRange::Full,
+            primaries: ColorPrimaries::Bt601,
+            ..Colorimetry::default()
+        };
+        assert_eq!(
+            obs_sys::video_colorspace_VIDEO_CS_601,
+            full_601.obs_colorspace()
+        );
+        assert_eq!(
+            obs_sys::video_range_type_VIDEO_RANGE_FULL,
+            full_601.obs_range()
+        );
+
+        let unknown_matrix_709 = Colorimetry {
+            primaries: ColorPrimaries::Bt709,
+            ..Colorimetry::default()
+        };
+        assert_eq!(
+            obs_sys::video_colorspace_VIDEO_CS_709,
+            unknown_matrix_709.obs_colorspace()
+        );
+
+        let pq = Colorimetry {
+            matrix: ColorMatrix::Bt2020,
+            transfer: TransferFunction::Pq,
+            ..Colorimetry::default()
+        };
+        assert_eq!(
+            obs_sys::video_colorspace_VIDEO_CS_2100_PQ,
+            pq.obs_colorspace()
+        );
+
+        let unknown = Colorimetry::default();
+        assert_eq!(
+            obs_sys::video_colorspace_VIDEO_CS_DEFAULT,
+            unknown.obs_colorspace()
+        );
+        assert_eq!(
+            obs_sys::video_range_type_VIDEO_RANGE_DEFAULT,
+            unknown.obs_range()
+        );
+    }
+}
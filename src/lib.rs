@@ -3,37 +3,27 @@ use obs_wrapper::{
     obs_register_module,
     // Macro for creating strings
     obs_string,
+    // Raw bindings to libobs
+    obs_sys,
     // Everything required for modules
     prelude::*,
     // Everything required for creating a source
     source::*,
 };
 
-pub mod native_shims;
-
-/// The state of the source that is managed by OBS and used in each trait method.
-struct SourceData;
-
-/// Screen Cast Source
-///
-/// The struct that represents our source.
-struct ScreenCastSource;
+use log::{error, LevelFilter};
 
-impl Sourceable for ScreenCastSource {
-    fn get_id() -> ObsString {
-        obs_string!("portal_screencast_source")
-    }
-
-    fn get_type() -> SourceType {
-        SourceType::INPUT
-    }
-}
+pub mod capture;
+pub mod format;
+mod logger;
+pub mod native_shims;
+mod output;
+pub mod registry;
+pub mod runtime;
+mod sessions;
+mod source;
 
-impl GetNameSource<SourceData> for ScreenCastSource {
-    fn get_name() -> ObsString {
-        obs_string!("Portal ScreenCast")
-    }
-}
+use source::{ScreenCastSource, SourceData};
 
 /// Screen Cast OBS Module
 ///
@@ -52,20 +42,52 @@ impl Module for PortalScreenCastModule {
 
     /// Module Load Callback
     ///
-    /// Called by OBS when the module is loaded. We register our source type
-    /// with OBS here.
+    /// Called by OBS when the module is loaded. We install our logger,
+    /// initialise PipeWire, and register our source and output types with OBS
+    /// here.
     fn load(&mut self, load_context: &mut LoadContext) -> bool {
+        logger::init(LevelFilter::Info);
+        runtime::init();
+        // Not being able to list nodes only leaves the properties' lists
+        // empty, so the module still loads.
+        if let Err(err) = registry::start_watching() {
+            error!("could not watch PipeWire nodes: {0}", err);
+        }
 
         let source = load_context
             .create_source_builder::<ScreenCastSource, SourceData>()
             .enable_get_name()
+            .enable_create()
+            .enable_get_properties()
+            .enable_update()
+            .enable_get_width()
+            .enable_get_height()
+            .enable_video_tick()
+            .enable_activate()
+            .enable_deactivate()
+            .enable_show()
+            .enable_hide()
+            .with_output_flags(obs_sys::OBS_SOURCE_ASYNC_VIDEO | obs_sys::OBS_SOURCE_AUDIO)
             .build();
 
         load_context.register_source(source);
+        output::register();
 
         true
     }
 
+    /// Module Unload Callback
+    ///
+    /// Called by OBS when the module is unloaded, once our sources have been
+    /// destroyed. PipeWire is left initialised if anything is somehow still
+    /// using it.
+    fn unload(&mut self) {
+        registry::stop_watching();
+        if let Err(err) = runtime::deinit() {
+            error!("could not deinitialise PipeWire: {0}", err);
+        }
+    }
+
     fn description() -> ObsString {
         obs_string!("Access to the ScreenCast portal to capture windows and monitors.")
     }
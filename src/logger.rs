@@ -0,0 +1,65 @@
+//! Forwards `log` records to the OBS log. Everything the module, and the
+//! `portal-screencast` crate, logs ends up in OBS's log files, which is what
+//! users attach to bug reports.
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use obs_wrapper::obs_sys;
+use std::{ffi::CString, os::raw::c_int};
+
+static LOGGER: ObsLogger = ObsLogger;
+
+/// A logger writing each record to the OBS log, prefixed with the module
+/// that logged it.
+struct ObsLogger;
+
+/// Install the OBS logger. Records more verbose than `level` are discarded.
+/// Does nothing if a logger is already installed.
+pub fn init(level: LevelFilter) {
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(level);
+    }
+}
+
+/// Get the OBS log level for a record logged at `level`. OBS has no trace
+/// level, so trace records are logged as debug.
+fn obs_level(level: Level) -> c_int {
+    let level = match level {
+        Level::Error => obs_sys::LOG_ERROR,
+        Level::Warn => obs_sys::LOG_WARNING,
+        Level::Info => obs_sys::LOG_INFO,
+        Level::Debug | Level::Trace => obs_sys::LOG_DEBUG,
+    };
+    level as c_int
+}
+
+impl Log for ObsLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let message = format!("[{0}] {1}", record.target(), record.args());
+        // Messages can't contain nulls, but there's no reason to lose them.
+        let message = match CString::new(message) {
+            Ok(message) => message,
+            Err(err) => {
+                let mut message = err.into_vec();
+                message.retain(|&b| b != 0);
+                CString::new(message).unwrap()
+            }
+        };
+        unsafe {
+            obs_sys::blog(
+                obs_level(record.level()),
+                b"%s\0".as_ptr() as *const _,
+                message.as_ptr(),
+            );
+        }
+    }
+
+    fn flush(&self) {}
+}
@@ -1,10 +1,139 @@
+#include <pipewire/pipewire.h>
 #include <spa/debug/types.h>
+#include <spa/param/audio/format-utils.h>
 #include <spa/param/video/format-utils.h>
 #include <spa/param/video/type-info.h>
 
-static char params_buffer[1024] = {0};
+/* Each capture runs on its own thread, so each needs its own buffer to build
+ * PODs into. */
+static _Thread_local char params_buffer[1024] = {0};
 
-extern const struct spa_pod *build_video_params() {
+/* Buffer for the meta param. This is sent alongside the stream params so
+ * can't share their buffer. */
+static _Thread_local char meta_buffer[256] = {0};
+
+/* Limits on the video we will accept. Mirrors `VideoParams` in Rust. */
+struct video_params {
+  struct spa_rectangle default_size;
+  struct spa_rectangle min_size;
+  struct spa_rectangle max_size;
+  struct spa_fraction default_framerate;
+  struct spa_fraction min_framerate;
+  struct spa_fraction max_framerate;
+  uint32_t high_bit_depth;
+};
+
+extern const struct spa_pod *
+build_video_params(const struct video_params *params) {
+
+  struct spa_pod_builder pod_builder;
+  struct spa_pod_frame frame;
+
+  pod_builder = SPA_POD_BUILDER_INIT(params_buffer, sizeof(params_buffer));
+  spa_pod_builder_push_object(&pod_builder, &frame, SPA_TYPE_OBJECT_Format,
+                              SPA_PARAM_EnumFormat);
+  spa_pod_builder_add(&pod_builder, SPA_FORMAT_mediaType,
+                      SPA_POD_Id(SPA_MEDIA_TYPE_video), SPA_FORMAT_mediaSubtype,
+                      SPA_POD_Id(SPA_MEDIA_SUBTYPE_raw), 0);
+
+  /* 10-bit formats are only offered when asked for, and are then preferred
+   * so that HDR desktops aren't clipped to 8 bits. */
+  if (params->high_bit_depth) {
+    spa_pod_builder_add(
+        &pod_builder, SPA_FORMAT_VIDEO_format,
+        SPA_POD_CHOICE_ENUM_Id(10, SPA_VIDEO_FORMAT_xRGB_210LE,
+                               SPA_VIDEO_FORMAT_xRGB_210LE,
+                               SPA_VIDEO_FORMAT_xBGR_210LE,
+                               SPA_VIDEO_FORMAT_RGBA, SPA_VIDEO_FORMAT_RGBx,
+                               SPA_VIDEO_FORMAT_BGRx, SPA_VIDEO_FORMAT_BGRA,
+                               SPA_VIDEO_FORMAT_NV12, SPA_VIDEO_FORMAT_I420,
+                               SPA_VIDEO_FORMAT_YUY2),
+        0);
+  } else {
+    spa_pod_builder_add(
+        &pod_builder, SPA_FORMAT_VIDEO_format,
+        SPA_POD_CHOICE_ENUM_Id(7, SPA_VIDEO_FORMAT_RGBA, SPA_VIDEO_FORMAT_RGBx,
+                               SPA_VIDEO_FORMAT_BGRx, SPA_VIDEO_FORMAT_BGRA,
+                               SPA_VIDEO_FORMAT_NV12, SPA_VIDEO_FORMAT_I420,
+                               SPA_VIDEO_FORMAT_YUY2),
+        0);
+  }
+
+  spa_pod_builder_add(
+      &pod_builder, SPA_FORMAT_VIDEO_size,
+      SPA_POD_CHOICE_RANGE_Rectangle(&params->default_size, &params->min_size,
+                                     &params->max_size),
+      SPA_FORMAT_VIDEO_framerate,
+      SPA_POD_CHOICE_RANGE_Fraction(&params->default_framerate,
+                                    &params->min_framerate,
+                                    &params->max_framerate),
+      0);
+  return spa_pod_builder_pop(&pod_builder, &frame);
+}
+
+extern const struct spa_pod *build_audio_params() {
+
+  struct spa_pod_builder pod_builder;
+
+  pod_builder = SPA_POD_BUILDER_INIT(params_buffer, sizeof(params_buffer));
+  return spa_pod_builder_add_object(
+      &pod_builder, SPA_TYPE_OBJECT_Format, SPA_PARAM_EnumFormat,
+      SPA_FORMAT_mediaType, SPA_POD_Id(SPA_MEDIA_TYPE_audio),
+      SPA_FORMAT_mediaSubtype, SPA_POD_Id(SPA_MEDIA_SUBTYPE_raw),
+      SPA_FORMAT_AUDIO_format,
+      SPA_POD_CHOICE_ENUM_Id(3, SPA_AUDIO_FORMAT_F32P, SPA_AUDIO_FORMAT_F32P,
+                             SPA_AUDIO_FORMAT_S16),
+      SPA_FORMAT_AUDIO_rate, SPA_POD_CHOICE_RANGE_Int(48000, 1, 192000),
+      // Only the channel counts OBS has a speaker layout for.
+      SPA_FORMAT_AUDIO_channels,
+      SPA_POD_CHOICE_ENUM_Int(8, 2, 1, 2, 3, 4, 5, 6, 8));
+}
+
+extern const struct spa_pod *build_stream_param(uint32_t blocks) {
+
+  struct spa_pod_builder pod_builder;
+
+  pod_builder = SPA_POD_BUILDER_INIT(params_buffer, sizeof(params_buffer));
+  return spa_pod_builder_add_object(
+      &pod_builder, SPA_TYPE_OBJECT_ParamBuffers, SPA_PARAM_Buffers,
+      SPA_PARAM_BUFFERS_blocks, SPA_POD_CHOICE_RANGE_Int(blocks, 1, blocks),
+      SPA_PARAM_BUFFERS_dataType,
+      SPA_POD_Int((1 << SPA_DATA_MemPtr) | (1 << SPA_DATA_MemFd)));
+}
+
+extern const struct spa_pod *build_video_buffers_param(uint32_t blocks,
+                                                       uint32_t size,
+                                                       uint32_t min_size,
+                                                       uint32_t stride) {
+
+  struct spa_pod_builder pod_builder;
+
+  pod_builder = SPA_POD_BUILDER_INIT(params_buffer, sizeof(params_buffer));
+  return spa_pod_builder_add_object(
+      &pod_builder, SPA_TYPE_OBJECT_ParamBuffers, SPA_PARAM_Buffers,
+      SPA_PARAM_BUFFERS_blocks, SPA_POD_CHOICE_RANGE_Int(blocks, 1, blocks),
+      SPA_PARAM_BUFFERS_size,
+      SPA_POD_CHOICE_RANGE_Int(size, min_size, INT32_MAX),
+      SPA_PARAM_BUFFERS_stride,
+      SPA_POD_CHOICE_RANGE_Int(stride, stride, INT32_MAX),
+      SPA_PARAM_BUFFERS_dataType,
+      SPA_POD_Int((1 << SPA_DATA_MemPtr) | (1 << SPA_DATA_MemFd)));
+}
+
+extern const struct spa_pod *build_meta_header_param() {
+
+  struct spa_pod_builder pod_builder;
+
+  pod_builder = SPA_POD_BUILDER_INIT(meta_buffer, sizeof(meta_buffer));
+  return spa_pod_builder_add_object(
+      &pod_builder, SPA_TYPE_OBJECT_ParamMeta, SPA_PARAM_Meta,
+      SPA_PARAM_META_type, SPA_POD_Id(SPA_META_Header), SPA_PARAM_META_size,
+      SPA_POD_Int(sizeof(struct spa_meta_header)));
+}
+
+extern const struct spa_pod *
+build_output_format(uint32_t format, const struct spa_rectangle *size,
+                    const struct spa_fraction *framerate) {
 
   struct spa_pod_builder pod_builder;
 
@@ -13,27 +142,30 @@ extern const struct spa_pod *build_video_params() {
       &pod_builder, SPA_TYPE_OBJECT_Format, SPA_PARAM_EnumFormat,
       SPA_FORMAT_mediaType, SPA_POD_Id(SPA_MEDIA_TYPE_video),
       SPA_FORMAT_mediaSubtype, SPA_POD_Id(SPA_MEDIA_SUBTYPE_raw),
-      SPA_FORMAT_VIDEO_format,
-      SPA_POD_CHOICE_ENUM_Id(4, SPA_VIDEO_FORMAT_RGBA, SPA_VIDEO_FORMAT_RGBx,
-                             SPA_VIDEO_FORMAT_BGRx, SPA_VIDEO_FORMAT_BGRA),
-      SPA_FORMAT_VIDEO_size,
-      SPA_POD_CHOICE_RANGE_Rectangle(&SPA_RECTANGLE(1920, 1080),
-                                     &SPA_RECTANGLE(1, 1),
-                                     &SPA_RECTANGLE(4096, 4096)),
-      SPA_FORMAT_VIDEO_framerate,
-      SPA_POD_CHOICE_RANGE_Fraction(&SPA_FRACTION(60, 1), &SPA_FRACTION(0, 1),
-                                    &SPA_FRACTION(144, 1)));
+      SPA_FORMAT_VIDEO_format, SPA_POD_Id(format), SPA_FORMAT_VIDEO_size,
+      SPA_POD_Rectangle(size), SPA_FORMAT_VIDEO_framerate,
+      SPA_POD_Fraction(framerate));
 }
 
-extern const struct spa_pod *build_stream_param() {
+extern const struct spa_pod *build_output_buffers_param(uint32_t size,
+                                                        uint32_t stride) {
 
   struct spa_pod_builder pod_builder;
 
   pod_builder = SPA_POD_BUILDER_INIT(params_buffer, sizeof(params_buffer));
   return spa_pod_builder_add_object(
       &pod_builder, SPA_TYPE_OBJECT_ParamBuffers, SPA_PARAM_Buffers,
+      SPA_PARAM_BUFFERS_buffers, SPA_POD_CHOICE_RANGE_Int(4, 2, 16),
+      SPA_PARAM_BUFFERS_blocks, SPA_POD_Int(1), SPA_PARAM_BUFFERS_size,
+      SPA_POD_Int(size), SPA_PARAM_BUFFERS_stride, SPA_POD_Int(stride),
       SPA_PARAM_BUFFERS_dataType,
-      SPA_POD_Int((1 << SPA_DATA_MemPtr) | (1 << SPA_DATA_DmaBuf)));
+      SPA_POD_Int((1 << SPA_DATA_MemPtr) | (1 << SPA_DATA_MemFd)));
+}
+
+extern struct spa_meta_header *
+spa_buffer_find_meta_header_rs(const struct spa_buffer *buffer) {
+  return spa_buffer_find_meta_data(buffer, SPA_META_Header,
+                                   sizeof(struct spa_meta_header));
 }
 
 extern const int spa_format_parse_rs(const struct spa_pod *format,
@@ -47,3 +179,21 @@ spa_format_video_raw_parse_rs(const struct spa_pod *format,
                               struct spa_video_info_raw *info) {
   return spa_format_video_raw_parse(format, info);
 }
+
+extern const int
+spa_format_audio_raw_parse_rs(const struct spa_pod *format,
+                              struct spa_audio_info_raw *info) {
+  return spa_format_audio_raw_parse(format, info);
+}
+
+extern int pw_main_loop_iterate_rs(struct pw_main_loop *main_loop,
+                                   int timeout_ms) {
+  struct pw_loop *loop = pw_main_loop_get_loop(main_loop);
+  int res;
+
+  pw_loop_enter(loop);
+  res = pw_loop_iterate(loop, timeout_ms);
+  pw_loop_leave(loop);
+
+  return res;
+}
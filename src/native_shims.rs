@@ -3,18 +3,85 @@
 
 use std::os::raw;
 
+use libspa_sys::{spa_fraction, spa_rectangle};
+
+/// Limits on the size, framerate and depth of video to negotiate. The layout
+/// of this must match `struct video_params` in `native-shims.c`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct VideoParams {
+    pub default_size: spa_rectangle,
+    pub min_size: spa_rectangle,
+    pub max_size: spa_rectangle,
+    pub default_framerate: spa_fraction,
+    pub min_framerate: spa_fraction,
+    pub max_framerate: spa_fraction,
+    /// Non-zero to offer 10-bit formats.
+    pub high_bit_depth: u32,
+}
+
 extern "C" {
     /// Build the video parameters strucure
     ///
     /// This POD should be an object defining our supported video formats. It
     /// is used when connecting to a pipewire node to begin the negotiations.
-    pub fn build_video_params() -> *const ::libspa_sys::spa_pod;
+    /// Sizes and framerates are limited to the ranges in `params`.
+    pub fn build_video_params(params: *const VideoParams) -> *const ::libspa_sys::spa_pod;
+
+    /// Build the audio parameters structure
+    ///
+    /// Like `build_video_params`, but for audio streams. We accept planar
+    /// 32-bit float or interleaved signed 16-bit samples, both of which OBS
+    /// can take directly.
+    pub fn build_audio_params() -> *const ::libspa_sys::spa_pod;
 
     /// Build the stream parameters
     ///
     /// Called when we are finishing the format negotiation. This produces the
-    /// stream parameters we need to set to complete negotiation.
-    pub fn build_stream_param() -> *const ::libspa_sys::spa_pod;
+    /// stream parameters we need to set to complete negotiation. The `blocks`
+    /// is the number of planes in the negotiated format. Producers can choose
+    /// to send all planes in a single block instead.
+    pub fn build_stream_param(blocks: u32) -> *const ::libspa_sys::spa_pod;
+
+    /// Build the buffer parameters for a video stream
+    ///
+    /// Like `build_stream_param`, but also sizes the buffers for the
+    /// negotiated format. A frame sent as a single block needs `size` bytes,
+    /// and no block can be smaller than `min_size`, the largest plane. The
+    /// first plane's rows must be at least `stride` bytes apart.
+    pub fn build_video_buffers_param(
+        blocks: u32,
+        size: u32,
+        min_size: u32,
+        stride: u32,
+    ) -> *const ::libspa_sys::spa_pod;
+
+    /// Build the meta parameters
+    ///
+    /// Requests that the producer attach an `spa_meta_header` to each buffer.
+    /// Sent along with the stream parameters.
+    pub fn build_meta_header_param() -> *const ::libspa_sys::spa_pod;
+
+    /// Build the format of an output stream
+    ///
+    /// Unlike `build_video_params` this offers a single, fixed, format. We
+    /// are the producer so the format is whatever OBS is rendering.
+    pub fn build_output_format(
+        format: u32,
+        size: *const spa_rectangle,
+        framerate: *const spa_fraction,
+    ) -> *const ::libspa_sys::spa_pod;
+
+    /// Build the buffer parameters for an output stream
+    ///
+    /// Each buffer has a single data block of `size` bytes holding all the
+    /// planes of a frame, the first with the given `stride`.
+    pub fn build_output_buffers_param(size: u32, stride: u32) -> *const ::libspa_sys::spa_pod;
+
+    /// Find the `spa_meta_header` attached to a buffer, if there is one.
+    pub fn spa_buffer_find_meta_header_rs(
+        buffer: *const ::libspa_sys::spa_buffer,
+    ) -> *mut ::libspa_sys::spa_meta_header;
 
     /// Shim to parse a format from an SPA POD.
     pub fn spa_format_parse_rs(
@@ -27,4 +94,17 @@ extern "C" {
         format: *const ::libspa_sys::spa_pod,
         info: *mut ::libspa_sys::spa_video_info_raw,
     ) -> raw::c_int;
+
+    pub fn spa_format_audio_raw_parse_rs(
+        format: *const ::libspa_sys::spa_pod,
+        info: *mut ::libspa_sys::spa_audio_info_raw,
+    ) -> raw::c_int;
+
+    /// Run a single iteration of the main loop, waiting at most `timeout_ms`
+    /// for events. Allows a loop to be driven from a thread that also needs
+    /// to check for other work, rather than blocking in `pw_main_loop_run`.
+    pub fn pw_main_loop_iterate_rs(
+        main_loop: *mut ::pipewire_sys::pw_main_loop,
+        timeout_ms: raw::c_int,
+    ) -> raw::c_int;
 }
@@ -0,0 +1,169 @@
+//! The OBS output. This publishes OBS's program video as a PipeWire node, so
+//! that other applications, such as browsers and conferencing apps, can use
+//! it like a camera. OBS converts each frame to BGRA for us, which we publish
+//! as BGRx.
+//!
+//! The version of `obs-wrapper` we build against has no support for outputs,
+//! so this registers the output type with libobs directly.
+
+use log::{error, info, warn};
+use obs_wrapper::obs_sys;
+use std::{
+    ffi::CStr,
+    mem,
+    os::raw::{c_char, c_void},
+    slice,
+    sync::Mutex,
+    time::Instant,
+};
+
+use crate::{
+    capture::{self, OutputHandle, Plane, RateLimit, LOG_INTERVAL},
+    format::{Colorimetry, NegotiatedFormat, VideoFormat},
+};
+
+const OUTPUT_ID: &[u8] = b"portal_screencast_pipewire_output\0";
+const OUTPUT_NAME: &[u8] = b"PipeWire Video Output\0";
+
+/// The name of the published node, unless the `node_name` setting says
+/// otherwise.
+const DEFAULT_NODE_NAME: &str = "OBS Studio";
+
+/// The state of an output, owned by OBS between `create` and `destroy`.
+struct OutputData {
+    output: *mut obs_sys::obs_output_t,
+    node_name: String,
+    handle: Option<OutputHandle>,
+    /// Limits how often frames which can't be published are logged.
+    publish_failures: Mutex<RateLimit>,
+}
+
+/// Register the output type with OBS.
+pub fn register() {
+    let mut info: obs_sys::obs_output_info = unsafe { mem::zeroed() };
+    info.id = OUTPUT_ID.as_ptr() as *const c_char;
+    info.flags = obs_sys::OBS_OUTPUT_VIDEO;
+    info.get_name = Some(get_name);
+    info.create = Some(create);
+    info.destroy = Some(destroy);
+    info.start = Some(start);
+    info.stop = Some(stop);
+    info.raw_video = Some(raw_video);
+
+    unsafe {
+        obs_sys::obs_register_output_s(&info, mem::size_of::<obs_sys::obs_output_info>() as _);
+    }
+}
+
+unsafe extern "C" fn get_name(_type_data: *mut c_void) -> *const c_char {
+    OUTPUT_NAME.as_ptr() as *const c_char
+}
+
+unsafe extern "C" fn create(
+    settings: *mut obs_sys::obs_data_t,
+    output: *mut obs_sys::obs_output_t,
+) -> *mut c_void {
+    let node_name = match obs_sys::obs_data_get_string(settings, b"node_name\0".as_ptr() as _) {
+        name if name.is_null() => String::new(),
+        name => CStr::from_ptr(name).to_string_lossy().into_owned(),
+    };
+    let node_name = if node_name.is_empty() {
+        DEFAULT_NODE_NAME.to_owned()
+    } else {
+        node_name
+    };
+
+    Box::into_raw(Box::new(OutputData {
+        output,
+        node_name,
+        handle: None,
+        publish_failures: Mutex::new(RateLimit::new(LOG_INTERVAL)),
+    })) as *mut c_void
+}
+
+unsafe extern "C" fn destroy(data: *mut c_void) {
+    drop(Box::from_raw(data as *mut OutputData));
+}
+
+/// Ask OBS for frames the size of its output, converted to BGRA, and start
+/// publishing them.
+unsafe extern "C" fn start(data: *mut c_void) -> bool {
+    let data = &mut *(data as *mut OutputData);
+
+    let mut video_info: obs_sys::obs_video_info = mem::zeroed();
+    if !obs_sys::obs_get_video_info(&mut video_info) {
+        warn!("output not started: video is not initialised");
+        return false;
+    }
+
+    let format = NegotiatedFormat {
+        format: VideoFormat::Bgrx,
+        width: video_info.output_width,
+        height: video_info.output_height,
+        framerate: (video_info.fps_num, video_info.fps_den),
+        colorimetry: Colorimetry::default(),
+    };
+    let conversion = obs_sys::video_scale_info {
+        format: obs_sys::video_format_VIDEO_FORMAT_BGRA,
+        width: format.width,
+        height: format.height,
+        range: obs_sys::video_range_type_VIDEO_RANGE_DEFAULT,
+        colorspace: obs_sys::video_colorspace_VIDEO_CS_DEFAULT,
+    };
+    obs_sys::obs_output_set_video_conversion(data.output, &conversion);
+    if !obs_sys::obs_output_can_begin_data_capture(data.output, 0) {
+        return false;
+    }
+
+    info!(
+        "publishing {0}x{1} video as PipeWire node \"{2}\"",
+        format.width, format.height, data.node_name
+    );
+    match capture::spawn_output(data.node_name.clone(), format) {
+        Ok(handle) => data.handle = Some(handle),
+        Err(err) => {
+            error!("output not started: {0}", err);
+            return false;
+        }
+    }
+    obs_sys::obs_output_begin_data_capture(data.output, 0)
+}
+
+/// Stop receiving frames, then remove the node.
+unsafe extern "C" fn stop(data: *mut c_void, _ts: u64) {
+    let data = &mut *(data as *mut OutputData);
+    obs_sys::obs_output_end_data_capture(data.output);
+    data.handle = None;
+}
+
+unsafe extern "C" fn raw_video(data: *mut c_void, frame: *mut obs_sys::video_data) {
+    let data = &*(data as *const OutputData);
+    let (handle, frame) = match (&data.handle, frame.as_ref()) {
+        (Some(handle), Some(frame)) => (handle, frame),
+        _ => return,
+    };
+
+    let planes = handle
+        .format()
+        .planes()
+        .iter()
+        .enumerate()
+        .map(|(i, layout)| Plane {
+            data: slice::from_raw_parts(
+                frame.data[i],
+                frame.linesize[i] as usize * layout.height as usize,
+            ),
+            stride: frame.linesize[i],
+        })
+        .collect::<Vec<_>>();
+
+    if let Err(err) = handle.send(&planes, frame.timestamp as i64) {
+        let allowed = data.publish_failures.lock().unwrap().allow(Instant::now());
+        if let Some(suppressed) = allowed {
+            warn!(
+                "could not publish frame: {0} ({1} more not logged)",
+                err, suppressed
+            );
+        }
+    }
+}
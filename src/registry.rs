@@ -0,0 +1,497 @@
+//! Browsing the PipeWire registry for video and audio nodes we can capture
+//! directly. This lets a node be chosen by name, rather than relying on the
+//! node id the portal hands back.
+//!
+//! The nodes can be listed once, waiting for the daemon, or watched on a
+//! background thread so that the latest list can be read without waiting.
+
+use lazy_static::lazy_static;
+use log::{info, warn};
+use pipewire::{Context, Core, MainLoop};
+use std::{
+    cell::{Cell, RefCell},
+    error::Error,
+    os::unix::io::RawFd,
+    rc::Rc,
+    sync::{
+        mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    capture::NodeTarget,
+    native_shims,
+    runtime::{RuntimeError, RuntimeGuard},
+};
+
+/// How long the watcher waits before reconnecting to PipeWire.
+const WATCH_RETRY: Duration = Duration::from_secs(5);
+
+lazy_static! {
+    static ref WATCHER: Mutex<Option<NodeWatcher>> = Mutex::new(None);
+}
+
+/// The media classes of nodes which produce video. Devices such as cameras
+/// are `Video/Source`, while applications' output streams are
+/// `Stream/Output/Video`.
+const VIDEO_CLASSES: &[&str] = &["Video/Source", "Stream/Output/Video"];
+
+/// The media classes of nodes which we can capture audio from. Capturing an
+/// `Audio/Sink` records what is being played through that output device.
+const AUDIO_CLASSES: &[&str] = &[AUDIO_SINK_CLASS, "Stream/Output/Audio"];
+
+/// The media class of output devices.
+const AUDIO_SINK_CLASS: &str = "Audio/Sink";
+
+/// A node in the PipeWire graph which produces media.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MediaNode {
+    /// The node's global id. This is only valid for the lifetime of the node.
+    pub id: u32,
+    /// The node's `node.name`.
+    pub name: Option<String>,
+    /// The node's `media.class`.
+    pub media_class: String,
+    /// A human readable description, from `node.description` or `node.nick`.
+    pub description: Option<String>,
+    /// The node's `object.serial`. Unlike the id, this is never reused.
+    pub serial: Option<u64>,
+}
+
+impl MediaNode {
+    /// Build a node from a global object's id and properties. Returns `None`
+    /// if the object isn't one of the media `classes`.
+    fn from_global<F>(id: u32, classes: &[&str], prop: F) -> Option<Self>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let media_class = prop("media.class")?;
+        if !classes.contains(&media_class.as_str()) {
+            return None;
+        }
+        Some(MediaNode {
+            id,
+            name: prop("node.name"),
+            media_class,
+            description: prop("node.description").or_else(|| prop("node.nick")),
+            serial: prop("object.serial").and_then(|serial| serial.parse().ok()),
+        })
+    }
+
+    /// The target to capture this node. Names are preferred as they stay the
+    /// same if the node is recreated.
+    pub fn target(&self) -> NodeTarget {
+        match &self.name {
+            Some(name) => NodeTarget::Name(name.clone()),
+            None => NodeTarget::Id(self.id),
+        }
+    }
+
+    /// Is this the node `target` refers to?
+    pub fn is_target(&self, target: &NodeTarget) -> bool {
+        match target {
+            NodeTarget::Id(id) => self.id == *id,
+            NodeTarget::Name(name) => self.name.as_ref() == Some(name),
+        }
+    }
+
+    /// Is this an output device? Capturing one records what is played
+    /// through it, rather than what it produces.
+    pub fn is_audio_sink(&self) -> bool {
+        self.media_class == AUDIO_SINK_CLASS
+    }
+
+    /// A label to show for this node when choosing one to capture.
+    pub fn label(&self) -> String {
+        match (&self.description, &self.name) {
+            (Some(description), Some(name)) => format!("{0} ({1})", description, name),
+            (Some(label), None) | (None, Some(label)) => label.clone(),
+            (None, None) => format!("Node {0}", self.id),
+        }
+    }
+}
+
+/// List the video nodes currently in the PipeWire graph. If `fd` is given then
+/// the connection is made through that remote, otherwise to the default
+/// PipeWire daemon. Waits at most `timeout` for the daemon to list the
+/// registry's objects.
+pub fn video_nodes(fd: Option<RawFd>, timeout: Duration) -> Result<Vec<MediaNode>, Box<dyn Error>> {
+    list_nodes(fd, timeout, VIDEO_CLASSES)
+}
+
+/// List the output devices and application streams currently in the
+/// PipeWire graph which we can capture audio from. Takes the same arguments
+/// as `video_nodes`.
+pub fn audio_nodes(fd: Option<RawFd>, timeout: Duration) -> Result<Vec<MediaNode>, Box<dyn Error>> {
+    list_nodes(fd, timeout, AUDIO_CLASSES)
+}
+
+fn list_nodes(
+    fd: Option<RawFd>,
+    timeout: Duration,
+    classes: &'static [&'static str],
+) -> Result<Vec<MediaNode>, Box<dyn Error>> {
+    let _guard = RuntimeGuard::new()?;
+
+    let pw_loop = MainLoop::new()?;
+    let pw_context = Context::new(&pw_loop)?;
+    let core = match fd {
+        Some(fd) => pw_context.connect_fd(fd, None)?,
+        None => pw_context.connect(None)?,
+    };
+    let registry = core.get_registry()?;
+
+    let nodes = Rc::new(RefCell::new(Vec::new()));
+    let global_nodes = nodes.clone();
+    let removed_nodes = nodes.clone();
+    let _registry_listener = registry
+        .add_listener_local()
+        .global(move |global| {
+            let props = match &global.props {
+                Some(props) => props,
+                None => return,
+            };
+            let node =
+                MediaNode::from_global(global.id, classes, |key| props.get(key).map(str::to_owned));
+            if let Some(node) = node {
+                global_nodes.borrow_mut().push(node);
+            }
+        })
+        .global_remove(move |id| {
+            removed_nodes
+                .borrow_mut()
+                .retain(|node: &MediaNode| node.id != id)
+        })
+        .register();
+
+    // The registry sends all the existing globals before it replies to a
+    // sync, so once we see our sync completed we have seen every node.
+    sync(&pw_loop, &core, timeout, "listing PipeWire nodes")?;
+
+    let mut nodes = nodes.replace(Vec::new());
+    nodes.sort_by_key(|node| node.id);
+    Ok(nodes)
+}
+
+/// Start watching the PipeWire graph for nodes on a background thread. Does
+/// nothing if the nodes are already being watched. PipeWire must have been
+/// initialised with `runtime::init`, and stays in use until `stop_watching`.
+pub fn start_watching() -> Result<(), RuntimeError> {
+    let mut watcher = WATCHER.lock().unwrap();
+    if watcher.is_none() {
+        *watcher = Some(NodeWatcher::start()?);
+    }
+    Ok(())
+}
+
+/// Stop watching the PipeWire graph, waiting for the watcher's thread to end.
+pub fn stop_watching() {
+    let watcher = WATCHER.lock().unwrap().take();
+    drop(watcher);
+}
+
+/// The video nodes last seen by the watcher. Returns `None` if the nodes
+/// aren't being watched, or the watcher hasn't listed them yet.
+pub fn watched_video_nodes() -> Option<Vec<MediaNode>> {
+    watched_nodes(VIDEO_CLASSES)
+}
+
+/// The audio nodes last seen by the watcher. Returns `None` in the same cases
+/// as `watched_video_nodes`.
+pub fn watched_audio_nodes() -> Option<Vec<MediaNode>> {
+    watched_nodes(AUDIO_CLASSES)
+}
+
+fn watched_nodes(classes: &[&str]) -> Option<Vec<MediaNode>> {
+    let watcher = WATCHER.lock().unwrap();
+    let nodes = watcher.as_ref()?.nodes.lock().unwrap();
+    let nodes = nodes.as_ref()?;
+    Some(
+        nodes
+            .iter()
+            .filter(|node| classes.contains(&node.media_class.as_str()))
+            .cloned()
+            .collect(),
+    )
+}
+
+/// Keeps the list of media nodes up to date from a background thread, which
+/// reconnects if the daemon goes away. The thread is stopped when this is
+/// dropped.
+struct NodeWatcher {
+    /// Every video and audio node, or `None` while the thread isn't
+    /// connected.
+    nodes: Arc<Mutex<Option<Vec<MediaNode>>>>,
+    stop: Sender<()>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl NodeWatcher {
+    fn start() -> Result<Self, RuntimeError> {
+        let guard = RuntimeGuard::new()?;
+        let nodes = Arc::new(Mutex::new(None));
+        let (stop, stop_receiver) = mpsc::channel();
+        let thread_nodes = nodes.clone();
+        let thread = thread::spawn(move || {
+            let _guard = guard;
+            loop {
+                match watch_nodes(&thread_nodes, &stop_receiver) {
+                    Ok(()) => return,
+                    Err(err) => warn!(
+                        "lost the PipeWire node list: {0}, retrying in {1:?}",
+                        err, WATCH_RETRY
+                    ),
+                }
+                *thread_nodes.lock().unwrap() = None;
+                match stop_receiver.recv_timeout(WATCH_RETRY) {
+                    Err(RecvTimeoutError::Timeout) => (),
+                    Ok(()) | Err(RecvTimeoutError::Disconnected) => return,
+                }
+            }
+        });
+        Ok(NodeWatcher {
+            nodes,
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl std::ops::Drop for NodeWatcher {
+    fn drop(&mut self) {
+        let _ = self.stop.send(());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Connect to the default PipeWire daemon and publish its video and audio
+/// nodes to `shared` as they come and go, until told to `stop`. Only returns
+/// an error if the connection fails.
+fn watch_nodes(
+    shared: &Mutex<Option<Vec<MediaNode>>>,
+    stop: &Receiver<()>,
+) -> Result<(), Box<dyn Error>> {
+    let pw_loop = MainLoop::new()?;
+    let pw_context = Context::new(&pw_loop)?;
+    let core = pw_context.connect(None)?;
+    let registry = core.get_registry()?;
+
+    let nodes = Rc::new(RefCell::new(Vec::new()));
+    let changed = Rc::new(Cell::new(false));
+    let global_nodes = nodes.clone();
+    let global_changed = changed.clone();
+    let removed_nodes = nodes.clone();
+    let removed_changed = changed.clone();
+    let _registry_listener = registry
+        .add_listener_local()
+        .global(move |global| {
+            let props = match &global.props {
+                Some(props) => props,
+                None => return,
+            };
+            let prop = |key: &str| props.get(key).map(str::to_owned);
+            let node = MediaNode::from_global(global.id, VIDEO_CLASSES, prop)
+                .or_else(|| MediaNode::from_global(global.id, AUDIO_CLASSES, prop));
+            if let Some(node) = node {
+                global_nodes.borrow_mut().push(node);
+                global_changed.set(true);
+            }
+        })
+        .global_remove(move |id| {
+            removed_nodes
+                .borrow_mut()
+                .retain(|node: &MediaNode| node.id != id);
+            removed_changed.set(true);
+        })
+        .register();
+
+    let error = Rc::new(RefCell::new(None));
+    let listener_error = error.clone();
+    let _core_listener = core
+        .add_listener_local()
+        .error(move |id, _seq, res, message| {
+            if id == pipewire_sys::PW_ID_CORE {
+                *listener_error.borrow_mut() =
+                    Some(format!("PipeWire error: {0} ({1})", message, res));
+            }
+        })
+        .register();
+
+    // Nothing is published until the registry has sent every existing node,
+    // so a half-listed graph is never shown.
+    sync(&pw_loop, &core, WATCH_RETRY, "listing PipeWire nodes")?;
+    info!("watching PipeWire nodes");
+    changed.set(true);
+    loop {
+        if changed.replace(false) {
+            let mut listed = nodes.borrow().clone();
+            listed.sort_by_key(|node| node.id);
+            *shared.lock().unwrap() = Some(listed);
+        }
+        if let Some(err) = error.borrow_mut().take() {
+            return Err(err.into());
+        }
+        match stop.try_recv() {
+            Ok(()) | Err(TryRecvError::Disconnected) => return Ok(()),
+            Err(TryRecvError::Empty) => unsafe {
+                native_shims::pw_main_loop_iterate_rs(pw_loop.as_ptr(), 100);
+            },
+        }
+    }
+}
+
+/// Get the version of the PipeWire daemon. Takes the same arguments as
+/// `video_nodes`.
+pub fn daemon_version(fd: Option<RawFd>, timeout: Duration) -> Result<String, Box<dyn Error>> {
+    let _guard = RuntimeGuard::new()?;
+
+    let pw_loop = MainLoop::new()?;
+    let pw_context = Context::new(&pw_loop)?;
+    let core = match fd {
+        Some(fd) => pw_context.connect_fd(fd, None)?,
+        None => pw_context.connect(None)?,
+    };
+
+    let version = Rc::new(RefCell::new(None));
+    let info_version = version.clone();
+    let _info_listener = core
+        .add_listener_local()
+        .info(move |info| *info_version.borrow_mut() = Some(info.version().to_owned()))
+        .register();
+
+    // The daemon sends its info as soon as we connect, so it has arrived by
+    // the time our sync completes.
+    sync(&pw_loop, &core, timeout, "asking for the PipeWire version")?;
+
+    let version = version.borrow_mut().take();
+    version.ok_or_else(|| "the PipeWire daemon did not send its version".into())
+}
+
+/// Wait for the daemon to reply to a sync, and so to have sent everything
+/// asked of it before now. `what` describes what is being waited for.
+fn sync(
+    pw_loop: &MainLoop,
+    core: &Core,
+    timeout: Duration,
+    what: &str,
+) -> Result<(), Box<dyn Error>> {
+    let done = Rc::new(RefCell::new(false));
+    let error = Rc::new(RefCell::new(None));
+    let listener_done = done.clone();
+    let listener_error = error.clone();
+    let pending = core.sync(0);
+    let _core_listener = core
+        .add_listener_local()
+        .done(move |id, seq| {
+            if id == pipewire_sys::PW_ID_CORE && seq == pending {
+                *listener_done.borrow_mut() = true;
+            }
+        })
+        .error(move |id, seq, res, message| {
+            *listener_error.borrow_mut() = Some(format!("{0},{1},{2},{3}", id, seq, res, message))
+        })
+        .register();
+
+    let started = Instant::now();
+    while !*done.borrow() {
+        if let Some(err) = error.borrow_mut().take() {
+            return Err(format!("PipeWire error: {0}", err).into());
+        }
+        if started.elapsed() >= timeout {
+            return Err(format!("timed out {0}", what).into());
+        }
+        unsafe {
+            native_shims::pw_main_loop_iterate_rs(pw_loop.as_ptr(), 10);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn node(id: u32, classes: &[&str], props: &[(&str, &str)]) -> Option<MediaNode> {
+        let props: HashMap<_, _> = props.iter().cloned().collect();
+        MediaNode::from_global(id, classes, |key| {
+            props.get(key).map(|value| value.to_string())
+        })
+    }
+
+    #[test]
+    fn only_video_sources_are_listed() {
+        assert_eq!(
+            None,
+            node(30, VIDEO_CLASSES, &[("media.class", "Audio/Sink")])
+        );
+        assert_eq!(None, node(31, VIDEO_CLASSES, &[("node.name", "no-class")]));
+
+        let camera = node(
+            42,
+            VIDEO_CLASSES,
+            &[
+                ("media.class", "Video/Source"),
+                ("node.name", "v4l2_input.usb-cam"),
+                ("node.nick", "HD Webcam"),
+                ("object.serial", "1234"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            MediaNode {
+                id: 42,
+                name: Some("v4l2_input.usb-cam".into()),
+                media_class: "Video/Source".into(),
+                description: Some("HD Webcam".into()),
+                serial: Some(1234),
+            },
+            camera
+        );
+        assert_eq!("HD Webcam (v4l2_input.usb-cam)", camera.label());
+        assert_eq!(
+            NodeTarget::Name("v4l2_input.usb-cam".into()),
+            camera.target()
+        );
+    }
+
+    #[test]
+    fn unnamed_nodes_are_targeted_by_id() {
+        let stream = node(57, VIDEO_CLASSES, &[("media.class", "Stream/Output/Video")]).unwrap();
+        assert_eq!(NodeTarget::Id(57), stream.target());
+        assert_eq!("Node 57", stream.label());
+    }
+
+    #[test]
+    fn audio_outputs_and_app_streams_are_listed() {
+        let sink = [("media.class", "Audio/Sink")];
+        let app = [("media.class", "Stream/Output/Audio")];
+        let mic = [("media.class", "Audio/Source")];
+        assert!(node(60, AUDIO_CLASSES, &sink).is_some());
+        assert!(node(61, AUDIO_CLASSES, &app).is_some());
+        assert_eq!(None, node(62, AUDIO_CLASSES, &mic));
+        assert_eq!(None, node(63, VIDEO_CLASSES, &app));
+
+        assert!(node(60, AUDIO_CLASSES, &sink).unwrap().is_audio_sink());
+        assert!(!node(61, AUDIO_CLASSES, &app).unwrap().is_audio_sink());
+    }
+
+    #[test]
+    fn nodes_are_found_by_id_or_name() {
+        let speakers = node(
+            70,
+            AUDIO_CLASSES,
+            &[("media.class", "Audio/Sink"), ("node.name", "speakers")],
+        )
+        .unwrap();
+        assert!(speakers.is_target(&NodeTarget::Id(70)));
+        assert!(speakers.is_target(&NodeTarget::Name("speakers".into())));
+        assert!(!speakers.is_target(&NodeTarget::Id(71)));
+        assert!(!speakers.is_target(&NodeTarget::Name("headphones".into())));
+    }
+}
@@ -0,0 +1,131 @@
+//! The lifetime of the PipeWire library. PipeWire is initialised once, when the
+//! OBS module is loaded, and deinitialised when the module is unloaded. Each
+//! capture, output and node listing runs its own loop and context.
+//!
+//! Anything using PipeWire holds a `RuntimeGuard` for as long as it does, and
+//! `deinit` refuses to pull PipeWire out from under any of them.
+
+use lazy_static::lazy_static;
+use std::{error::Error, ffi::CStr, fmt, sync::Mutex};
+
+lazy_static! {
+    /// Whether PipeWire is initialised, and the number of `RuntimeGuard`s
+    /// alive. Guards are only taken and counted with this held, so `deinit`
+    /// can't race with a new one.
+    static ref RUNTIME: Mutex<Runtime> = Mutex::new(Runtime::default());
+}
+
+#[derive(Debug, Default)]
+struct Runtime {
+    initialised: bool,
+    guards: usize,
+}
+
+/// The reasons PipeWire couldn't be used or deinitialised.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuntimeError {
+    /// This many guards are still alive.
+    InUse(usize),
+    /// PipeWire was used before `init`, or after `deinit`.
+    NotInitialised,
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RuntimeError::InUse(count) => {
+                write!(f, "PipeWire is still in use in {0} places", count)
+            }
+            RuntimeError::NotInitialised => write!(f, "PipeWire is not initialised"),
+        }
+    }
+}
+
+impl Error for RuntimeError {}
+
+/// Initialise PipeWire. Does nothing if PipeWire is already initialised.
+pub fn init() {
+    let mut runtime = RUNTIME.lock().unwrap();
+    if !runtime.initialised {
+        pipewire::init();
+        runtime.initialised = true;
+    }
+}
+
+/// Deinitialise PipeWire. Fails, leaving it initialised, if anything still
+/// holds a `RuntimeGuard`.
+pub fn deinit() -> Result<(), RuntimeError> {
+    let mut runtime = RUNTIME.lock().unwrap();
+    if runtime.guards > 0 {
+        return Err(RuntimeError::InUse(runtime.guards));
+    }
+
+    if runtime.initialised {
+        unsafe { pipewire::deinit() };
+        runtime.initialised = false;
+    }
+    Ok(())
+}
+
+/// Is PipeWire initialised?
+pub fn is_initialised() -> bool {
+    RUNTIME.lock().unwrap().initialised
+}
+
+/// The version of the PipeWire library we are running against, which may be
+/// newer than the one we were built with.
+pub fn library_version() -> String {
+    unsafe { CStr::from_ptr(pipewire_sys::pw_get_library_version()) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// The number of `RuntimeGuard`s currently alive.
+pub fn live_guards() -> usize {
+    RUNTIME.lock().unwrap().guards
+}
+
+/// Marks PipeWire as in use, preventing `deinit` until it is dropped. Take
+/// one before creating any loop, context or stream, and drop it after them.
+#[derive(Debug)]
+pub struct RuntimeGuard(());
+
+impl RuntimeGuard {
+    /// Take a guard, if PipeWire is initialised.
+    pub fn new() -> Result<Self, RuntimeError> {
+        let mut runtime = RUNTIME.lock().unwrap();
+        if !runtime.initialised {
+            return Err(RuntimeError::NotInitialised);
+        }
+        runtime.guards += 1;
+        Ok(RuntimeGuard(()))
+    }
+}
+
+impl std::ops::Drop for RuntimeGuard {
+    fn drop(&mut self) {
+        RUNTIME.lock().unwrap().guards -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn guards_follow_the_runtime() {
+        assert_eq!(
+            Err(RuntimeError::NotInitialised),
+            RuntimeGuard::new().map(|_| ())
+        );
+
+        init();
+        let guard = RuntimeGuard::new().unwrap();
+        assert_eq!(Err(RuntimeError::InUse(1)), deinit());
+
+        drop(guard);
+        assert_eq!(0, live_guards());
+        assert_eq!(Ok(()), deinit());
+        assert!(!is_initialised());
+    }
+}
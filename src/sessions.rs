@@ -0,0 +1,445 @@
+//! Captures shared between OBS sources. Sources capturing the same thing, such
+//! as the same monitor appearing in several scenes, subscribe to one shared
+//! capture instead of each opening their own screen cast and PipeWire stream.
+//! The capture is stopped once its last subscription is dropped.
+//!
+//! Subscribers only share a capture while they ask the node for the same
+//! video, with the same size, framerate, scale and bit depth. Each crops the
+//! frames it is handed itself. A subscriber which changes the rest of its
+//! limits while sharing moves to a capture of its own. The capture is only
+//! paused once every subscriber has asked for it to be.
+
+use lazy_static::lazy_static;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex, Weak},
+};
+
+use crate::{
+    capture::{
+        self, CaptureEvent, CaptureHandle, CaptureLimits, CaptureSource, CaptureStats, Crop,
+        Fanout, Frame, FrameSink, NodeTarget,
+    },
+    format::NegotiatedFormat,
+};
+
+lazy_static! {
+    /// The running shared captures. Entries are weak so that a capture ends
+    /// with its last subscription, and are pruned on the next lookup.
+    static ref SESSIONS: Mutex<HashMap<SessionKey, Weak<Session>>> = Mutex::new(HashMap::new());
+}
+
+/// Identifies a capture which can be shared.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum SessionKey {
+    /// A portal screen cast of the selection saved under a restore token.
+    RestoreToken(String),
+    /// A node on the default PipeWire daemon.
+    Node(NodeTarget),
+}
+
+impl SessionKey {
+    /// Get the key a capture from `source` is shared under, if it can be.
+    /// Portal captures can only be shared once the user's selection has been
+    /// saved, and captures through a particular remote not at all.
+    pub fn for_source(source: &CaptureSource) -> Option<Self> {
+        match source {
+            CaptureSource::Portal {
+                restore_token: Some(token),
+            } => Some(SessionKey::RestoreToken(token.clone())),
+            CaptureSource::Node { fd: None, target } => Some(SessionKey::Node(target.clone())),
+            _ => None,
+        }
+    }
+}
+
+/// Subscribe to the capture from `source`, starting it unless another
+/// subscriber shares it with the same `limits`, with its frames handed to
+/// `sink` after cropping them by `limits.crop`.
+pub fn subscribe<S>(source: CaptureSource, limits: CaptureLimits, sink: S) -> Subscription
+where
+    S: FrameSink + 'static,
+{
+    let session = find_or_start(source, &limits);
+    let id = session.add(Subscriber::new(Box::new(sink), limits.crop));
+    Subscription { session, id }
+}
+
+/// The limits a shared capture negotiates with the node, which are all of
+/// them but the crop.
+fn negotiation_limits(limits: &CaptureLimits) -> CaptureLimits {
+    CaptureLimits {
+        crop: Crop::default(),
+        ..*limits
+    }
+}
+
+/// Find the shared capture from `source` negotiating with the same `limits`,
+/// or start one.
+fn find_or_start(source: CaptureSource, limits: &CaptureLimits) -> Arc<Session> {
+    let limits = negotiation_limits(limits);
+    let key = SessionKey::for_source(&source);
+    let mut sessions = SESSIONS.lock().unwrap();
+    sessions.retain(|_, session| session.strong_count() > 0);
+    let existing = key
+        .as_ref()
+        .and_then(|key| sessions.get(key))
+        .and_then(Weak::upgrade)
+        .filter(|session| *session.limits.lock().unwrap() == limits);
+    match existing {
+        Some(session) => session,
+        None => {
+            // A capture with other limits keeps running for its subscribers,
+            // but new ones with these limits share this one instead.
+            let session = Arc::new(Session::start(source, limits));
+            if let Some(key) = key {
+                sessions.insert(key, Arc::downgrade(&session));
+            }
+            session
+        }
+    }
+}
+
+/// Share `session` under `alias` as well as its original key. Used when the
+/// portal hands out a new restore token for a running screen cast.
+fn alias(session: &Arc<Session>, alias: SessionKey) {
+    SESSIONS
+        .lock()
+        .unwrap()
+        .insert(alias, Arc::downgrade(session));
+}
+
+/// A subscription to a shared capture. The subscriber's sink is removed when
+/// this is dropped, without being told the stream has ended, and the capture
+/// stopped if it was the last.
+pub struct Subscription {
+    session: Arc<Session>,
+    id: u64,
+}
+
+impl Subscription {
+    /// Change the limits on this subscriber's video. The crop only affects
+    /// this subscriber. Changes to the rest of the limits apply to the
+    /// capture if nobody else shares it, otherwise this subscriber moves to a
+    /// capture with the new limits.
+    pub fn set_limits(&mut self, limits: CaptureLimits) {
+        let negotiation = negotiation_limits(&limits);
+        let mut state = self.session.state.lock().unwrap();
+        let shared = state.subscribers.len() > 1;
+        match state.subscribers.get_mut(&self.id) {
+            Some(subscriber) => subscriber.crop = limits.crop,
+            None => return,
+        }
+        {
+            let mut session_limits = self.session.limits.lock().unwrap();
+            if *session_limits == negotiation {
+                return;
+            }
+            if !shared {
+                *session_limits = negotiation;
+                self.session.capture.set_limits(negotiation);
+                return;
+            }
+        }
+
+        let subscriber = match state.subscribers.remove(&self.id) {
+            Some(subscriber) => subscriber,
+            None => return,
+        };
+        self.session.update_active(&mut state);
+        let source = state.source(&self.session.source);
+        drop(state);
+        let session = find_or_start(source, &limits);
+        self.id = session.add(subscriber);
+        self.session = session;
+    }
+
+    /// Ask for the capture to be paused or resumed. The shared capture keeps
+    /// running while any subscriber wants it active.
+    pub fn set_active(&self, active: bool) {
+        let mut state = self.session.state.lock().unwrap();
+        if let Some(subscriber) = state.subscribers.get_mut(&self.id) {
+            subscriber.active = active;
+        }
+        self.session.update_active(&mut state);
+    }
+
+    /// Get the most recent stats published by the shared capture.
+    pub fn stats(&self) -> CaptureStats {
+        self.session.capture.stats()
+    }
+
+    /// Take the next event from the shared capture which this subscriber
+    /// hasn't seen yet.
+    pub fn try_event(&self) -> Option<CaptureEvent> {
+        let mut state = self.session.state.lock().unwrap();
+        while let Some(event) = self.session.capture.try_event() {
+            if let CaptureEvent::RestoreToken(token) = &event {
+                alias(&self.session, SessionKey::RestoreToken(token.clone()));
+            }
+            state.publish(event);
+        }
+        state
+            .subscribers
+            .get_mut(&self.id)
+            .and_then(|subscriber| subscriber.events.pop_front())
+    }
+}
+
+impl std::ops::Drop for Subscription {
+    fn drop(&mut self) {
+        let mut state = self.session.state.lock().unwrap();
+        state.subscribers.remove(&self.id);
+        if !state.subscribers.is_empty() {
+            self.session.update_active(&mut state);
+        }
+    }
+}
+
+struct Subscriber {
+    /// The subscriber's sink, told about the format of its frames after they
+    /// have been cropped.
+    sink: Fanout,
+    /// The crop to apply to this subscriber's frames.
+    crop: Crop,
+    active: bool,
+    /// Events not yet taken by this subscriber.
+    events: VecDeque<CaptureEvent>,
+}
+
+impl Subscriber {
+    fn new(sink: Box<dyn FrameSink>, crop: Crop) -> Self {
+        Subscriber {
+            sink: Fanout::new(vec![sink]),
+            crop,
+            active: true,
+            events: VecDeque::new(),
+        }
+    }
+}
+
+/// The state of a shared capture, shared with its capture thread.
+struct SessionState {
+    next_id: u64,
+    subscribers: HashMap<u64, Subscriber>,
+    /// Whether the capture was last told to be active.
+    active: bool,
+    /// The most recent status event, restore token, and format and source
+    /// size of the frames, to catch new subscribers up with.
+    status: Option<CaptureEvent>,
+    restore_token: Option<CaptureEvent>,
+    format: Option<(NegotiatedFormat, (u32, u32))>,
+}
+
+impl SessionState {
+    /// Hand `event` to every subscriber.
+    fn publish(&mut self, event: CaptureEvent) {
+        for subscriber in self.subscribers.values_mut() {
+            subscriber.events.push_back(event.clone());
+        }
+        match event {
+            CaptureEvent::RestoreToken(_) => self.restore_token = Some(event),
+            _ => self.status = Some(event),
+        }
+    }
+
+    /// Where to capture the same thing as a capture from `original`. Portal
+    /// selections are restored with the latest restore token.
+    fn source(&self, original: &CaptureSource) -> CaptureSource {
+        match (original, &self.restore_token) {
+            (CaptureSource::Portal { .. }, Some(CaptureEvent::RestoreToken(token))) => {
+                CaptureSource::Portal {
+                    restore_token: Some(token.clone()),
+                }
+            }
+            _ => original.clone(),
+        }
+    }
+}
+
+/// The format of frames in `format` once cropped by `crop`, which is given in
+/// pixels of the frames' `source_size`.
+fn cropped_format(
+    format: NegotiatedFormat,
+    source_size: (u32, u32),
+    crop: &Crop,
+) -> NegotiatedFormat {
+    let size = (format.width, format.height);
+    let crop = crop.scaled(source_size, size);
+    let (_, _, width, height) = crop.region(size.0, size.1, format.format.alignment());
+    NegotiatedFormat {
+        width,
+        height,
+        ..format
+    }
+}
+
+/// A capture and the subscribers sharing it.
+struct Session {
+    capture: CaptureHandle,
+    /// Where the capture was started from.
+    source: CaptureSource,
+    /// The limits the capture negotiates with. Nothing else is locked while
+    /// this is held.
+    limits: Mutex<CaptureLimits>,
+    state: Arc<Mutex<SessionState>>,
+}
+
+impl Session {
+    /// Start capturing from `source`, handing each frame to every subscriber.
+    /// The `limits` shouldn't have a crop, as subscribers crop the frames.
+    fn start(source: CaptureSource, limits: CaptureLimits) -> Self {
+        let state = Arc::new(Mutex::new(SessionState {
+            next_id: 0,
+            subscribers: HashMap::new(),
+            active: true,
+            status: None,
+            restore_token: None,
+            format: None,
+        }));
+        let sink = SessionSink(state.clone());
+        let capture = capture::spawn(source.clone(), limits, vec![Box::new(sink)]);
+        Session {
+            capture,
+            source,
+            limits: Mutex::new(limits),
+            state,
+        }
+    }
+
+    /// Add a subscriber, returning its id.
+    fn add(&self, mut subscriber: Subscriber) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        let events: Vec<_> = state
+            .status
+            .iter()
+            .chain(state.restore_token.iter())
+            .cloned()
+            .collect();
+        subscriber.events.extend(events);
+        if let Some((format, source_size)) = state.format {
+            let format = cropped_format(format, source_size, &subscriber.crop);
+            subscriber.sink.format_changed(format);
+        }
+        state.subscribers.insert(id, subscriber);
+        self.update_active(&mut state);
+        id
+    }
+
+    /// Pause the capture if no subscriber wants it, or resume it if any do.
+    fn update_active(&self, state: &mut SessionState) {
+        let active = state
+            .subscribers
+            .values()
+            .any(|subscriber| subscriber.active);
+        if active != state.active {
+            state.active = active;
+            self.capture.set_active(active);
+        }
+    }
+}
+
+/// The capture's sink, handing everything on to the session's subscribers.
+struct SessionSink(Arc<Mutex<SessionState>>);
+
+impl FrameSink for SessionSink {
+    /// Subscribers are told about formats as their cropped frames arrive.
+    fn format_changed(&mut self, _format: NegotiatedFormat) {}
+
+    fn frame(&mut self, frame: &Frame) {
+        let mut state = self.0.lock().unwrap();
+        state.format = Some((frame.negotiated(), frame.source_size()));
+        for subscriber in state.subscribers.values_mut() {
+            if subscriber.crop.is_empty() {
+                subscriber.sink.frame(frame);
+            } else {
+                subscriber
+                    .sink
+                    .frame(&frame.cropped_from_source(&subscriber.crop));
+            }
+        }
+    }
+
+    fn end_of_stream(&mut self) {
+        for subscriber in self.0.lock().unwrap().subscribers.values_mut() {
+            subscriber.sink.end_of_stream();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{Colorimetry, VideoFormat};
+
+    #[test]
+    fn new_subscribers_are_told_their_cropped_format() {
+        let format = NegotiatedFormat {
+            width: 960,
+            height: 540,
+            format: VideoFormat::Bgrx,
+            framerate: (60, 1),
+            colorimetry: Colorimetry::default(),
+        };
+        let crop = Crop {
+            left: 100,
+            top: 0,
+            right: 100,
+            bottom: 80,
+        };
+        let cropped = cropped_format(format, (1920, 1080), &crop);
+        assert_eq!((860, 500), (cropped.width, cropped.height));
+        assert_eq!(format, cropped_format(format, (960, 540), &Crop::default()));
+    }
+
+    #[test]
+    fn only_the_crop_is_left_out_of_the_negotiation() {
+        let limits = CaptureLimits {
+            crop: Crop {
+                left: 10,
+                ..Crop::default()
+            },
+            scale: 50,
+            ..CaptureLimits::default()
+        };
+        assert_eq!(
+            CaptureLimits {
+                scale: 50,
+                ..CaptureLimits::default()
+            },
+            negotiation_limits(&limits)
+        );
+    }
+
+    #[test]
+    fn only_saved_selections_and_default_daemon_nodes_are_shared() {
+        assert_eq!(
+            None,
+            SessionKey::for_source(&CaptureSource::Portal {
+                restore_token: None
+            })
+        );
+        assert_eq!(
+            Some(SessionKey::RestoreToken("token".into())),
+            SessionKey::for_source(&CaptureSource::Portal {
+                restore_token: Some("token".into())
+            })
+        );
+        assert_eq!(
+            Some(SessionKey::Node(NodeTarget::Id(42))),
+            SessionKey::for_source(&CaptureSource::Node {
+                fd: None,
+                target: NodeTarget::Id(42)
+            })
+        );
+        assert_eq!(
+            None,
+            SessionKey::for_source(&CaptureSource::Node {
+                fd: Some(3),
+                target: NodeTarget::Id(42)
+            })
+        );
+    }
+}
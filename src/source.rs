@@ -0,0 +1,652 @@
+//! The OBS source. Each source captures a screen cast, or a PipeWire node
+//! directly, and hands the frames it receives to OBS as asynchronous video.
+//! Frames are copied into a mailbox on the capture thread and output on OBS's
+//! graphics thread, so only the latest frame is output when they arrive
+//! faster than OBS renders. Audio from another node, such as the application
+//! being captured, can optionally be output alongside.
+//!
+//! Each frame output is copied twice: into the mailbox, and by OBS into its
+//! async frame cache. Outputting straight from the capture thread would save
+//! the first copy, but would hold PipeWire's buffer while OBS takes its async
+//! lock and copies, and would queue every frame in OBS's cache rather than
+//! only the latest. Uploading to a texture ourselves would mean doing OBS's
+//! format conversion in shaders of our own. A copy of a frame in memory is
+//! cheap next to either, so we live with it.
+//!
+//! The capture is paused while the source isn't shown anywhere, keeping any
+//! screen cast open so that showing the source again doesn't prompt the user.
+//!
+//! Sources capturing the same node, or the same saved portal selection, share
+//! a single capture through the `sessions` registry.
+
+use log::{info, warn};
+use obs_wrapper::{obs_string, obs_sys, prelude::*, source::*};
+use std::{
+    ffi::{CStr, CString},
+    mem, ptr,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+};
+
+use crate::{
+    capture::{
+        self, AudioFrame, AudioHandle, CaptureEvent, CaptureLimits, CaptureSource, Crop, Frame,
+        FrameSink, MailboxReader, MailboxSink, NodeTarget, Plane, ReceivedFrame,
+    },
+    format::{swap_red_blue_210, ColorRange, NegotiatedFormat, VideoFormat},
+    registry,
+    sessions::{self, Subscription},
+};
+
+/// Values of the `capture_mode` setting.
+const MODE_PORTAL: i64 = 0;
+const MODE_NODE: i64 = 1;
+
+/// How often each source writes its capture stats to the OBS log, in seconds.
+const STATS_LOG_INTERVAL: f32 = 30.0;
+
+/// The state of the source that is managed by OBS and used in each trait method.
+pub struct SourceData {
+    source: RawSource,
+    /// Where to capture from, or `None` while node capture has no node
+    /// chosen.
+    capture_source: Option<CaptureSource>,
+    capture: Option<Subscription>,
+    /// The latest frame from the capture, waiting to be output.
+    frames: MailboxReader<ReceivedFrame>,
+    /// Space for frames which have to be converted before OBS can take them.
+    converted: Vec<u8>,
+    /// The size of the frames most recently output, after cropping and
+    /// scaling.
+    size: Arc<FrameSize>,
+    audio_target: Option<NodeTarget>,
+    audio: Option<AudioHandle>,
+    /// Text describing the capture's most recent event, shown in the
+    /// source's properties.
+    status: CString,
+    /// Seconds since the stats were last logged.
+    since_stats: f32,
+    /// Whether the source is shown anywhere, such as in the preview.
+    shown: bool,
+    /// Whether the source is shown on the program output.
+    active: bool,
+    /// Whether to keep capturing while the source is hidden.
+    keep_capturing: bool,
+    /// Whether the capture was last told to be active.
+    capturing: bool,
+}
+
+impl SourceData {
+    /// Pause or resume the capture to match whether the source is visible.
+    fn update_capturing(&mut self) {
+        let capturing = self.shown || self.active || self.keep_capturing;
+        if capturing != self.capturing {
+            if let Some(capture) = &self.capture {
+                capture.set_active(capturing);
+            }
+            self.capturing = capturing;
+        }
+    }
+}
+
+/// Screen Cast Source
+///
+/// The struct that represents our source.
+pub struct ScreenCastSource;
+
+impl Sourceable for ScreenCastSource {
+    fn get_id() -> ObsString {
+        obs_string!("portal_screencast_source")
+    }
+
+    fn get_type() -> SourceType {
+        SourceType::INPUT
+    }
+}
+
+impl GetNameSource<SourceData> for ScreenCastSource {
+    fn get_name() -> ObsString {
+        obs_string!("Portal ScreenCast")
+    }
+}
+
+impl GetPropertiesSource<SourceData> for ScreenCastSource {
+    fn get_properties(data: &mut Option<SourceData>, properties: &mut Properties) {
+        if let Some(data) = data.as_ref().filter(|d| !d.status.as_bytes().is_empty()) {
+            add_status_text(properties, &data.status);
+        }
+        properties
+            .add_list::<i64>(obs_string!("capture_mode"), obs_string!("Capture"), false)
+            .push(obs_string!("Desktop portal"), MODE_PORTAL)
+            .push(obs_string!("PipeWire node"), MODE_NODE);
+        // The list is editable so that a node which isn't running right now
+        // can still be entered by name.
+        let mut nodes = properties.add_list::<ObsString>(
+            obs_string!("node_target"),
+            obs_string!("PipeWire node"),
+            true,
+        );
+        // The nodes are listed from the watcher's cache, as waiting for the
+        // daemon here would stall the UI.
+        for node in registry::watched_video_nodes().unwrap_or_default() {
+            nodes.push(
+                ObsString::from(node.label()),
+                ObsString::from(node.target().to_string()),
+            );
+        }
+        let mut audio_nodes = properties.add_list::<ObsString>(
+            obs_string!("audio_target"),
+            obs_string!("Audio"),
+            true,
+        );
+        audio_nodes.push(obs_string!("No audio"), obs_string!(""));
+        for node in registry::watched_audio_nodes().unwrap_or_default() {
+            audio_nodes.push(
+                ObsString::from(node.label()),
+                ObsString::from(node.target().to_string()),
+            );
+        }
+        properties
+            .add_int(
+                obs_string!("max_fps"),
+                obs_string!("Maximum FPS"),
+                1,
+                240,
+                1,
+            )
+            .add_int(
+                obs_string!("max_width"),
+                obs_string!("Maximum width"),
+                1,
+                16384,
+                1,
+            )
+            .add_int(
+                obs_string!("max_height"),
+                obs_string!("Maximum height"),
+                1,
+                16384,
+                1,
+            )
+            .add_int(
+                obs_string!("crop_left"),
+                obs_string!("Crop left"),
+                0,
+                16384,
+                1,
+            )
+            .add_int(
+                obs_string!("crop_top"),
+                obs_string!("Crop top"),
+                0,
+                16384,
+                1,
+            )
+            .add_int(
+                obs_string!("crop_right"),
+                obs_string!("Crop right"),
+                0,
+                16384,
+                1,
+            )
+            .add_int(
+                obs_string!("crop_bottom"),
+                obs_string!("Crop bottom"),
+                0,
+                16384,
+                1,
+            )
+            .add_int(obs_string!("scale"), obs_string!("Scale (%)"), 1, 100, 1)
+            .add_bool(
+                obs_string!("high_bit_depth"),
+                obs_string!("Capture 10-bit and HDR video when offered"),
+            )
+            .add_bool(
+                obs_string!("keep_capturing"),
+                obs_string!("Keep capturing when hidden"),
+            );
+    }
+}
+
+impl CreatableSource<SourceData> for ScreenCastSource {
+    fn create(
+        settings: &mut SettingsContext,
+        source: SourceContext,
+        _context: &mut GlobalContext,
+    ) -> SourceData {
+        let source = RawSource(source.as_ptr());
+        let capture_source = source_from_settings(settings);
+        let size = Arc::new(FrameSize::default());
+        let (capture, frames) = start_capture(source, &size, capture_source.clone(), settings);
+        let status = idle_status(&capture_source);
+        let audio_target = audio_target_from_settings(settings);
+        let audio = audio_target
+            .clone()
+            .map(|target| start_audio(source, target));
+        let mut data = SourceData {
+            source,
+            capture_source,
+            capture,
+            frames,
+            converted: Vec::new(),
+            size,
+            audio_target,
+            audio,
+            status,
+            since_stats: 0.0,
+            shown: false,
+            active: false,
+            keep_capturing: keep_capturing_from_settings(settings),
+            capturing: true,
+        };
+        data.update_capturing();
+        data
+    }
+}
+
+impl UpdateSource<SourceData> for ScreenCastSource {
+    fn update(
+        data: &mut Option<SourceData>,
+        settings: &mut SettingsContext,
+        _context: &mut GlobalContext,
+    ) {
+        if let Some(data) = data {
+            let capture_source = source_from_settings(settings);
+            if capture_source != data.capture_source {
+                let (capture, frames) =
+                    start_capture(data.source, &data.size, capture_source.clone(), settings);
+                data.capture = capture;
+                data.frames = frames;
+                data.capture_source = capture_source;
+                data.capturing = true;
+                data.status = idle_status(&data.capture_source);
+            } else if let Some(capture) = &mut data.capture {
+                capture.set_limits(limits_from_settings(settings));
+            }
+            data.keep_capturing = keep_capturing_from_settings(settings);
+            data.update_capturing();
+
+            let audio_target = audio_target_from_settings(settings);
+            if audio_target != data.audio_target {
+                // Stop any previous audio capture before starting the new one.
+                data.audio = None;
+                data.audio = audio_target
+                    .clone()
+                    .map(|target| start_audio(data.source, target));
+                data.audio_target = audio_target;
+            }
+        }
+    }
+}
+
+impl VideoTickSource<SourceData> for ScreenCastSource {
+    fn video_tick(data: &mut Option<SourceData>, seconds: f32) {
+        if let Some(data) = data {
+            if let Some(latest) = data.frames.take() {
+                let mut format = latest.frame.format;
+                let frame = if format.format == VideoFormat::Xbgr210Le {
+                    data.converted.clear();
+                    data.converted.extend_from_slice(&latest.frame.data);
+                    swap_red_blue_210(&mut data.converted);
+                    format.format = VideoFormat::Xrgb210Le;
+                    let plane = Plane {
+                        data: &data.converted,
+                        stride: format.stride(),
+                    };
+                    Frame::from_planes(format, vec![plane])
+                } else {
+                    Frame::from_planes(format, latest.frame.planes())
+                };
+                output_frame(&data.source, &frame, latest.received as u64);
+            }
+
+            while let Some(event) = data.capture.as_ref().and_then(Subscription::try_event) {
+                if let CaptureEvent::RestoreToken(token) = event {
+                    save_restore_token(data.source, &token);
+                    if let Some(CaptureSource::Portal { restore_token }) = &mut data.capture_source
+                    {
+                        *restore_token = Some(token);
+                    }
+                    continue;
+                }
+                let status = event.to_string();
+                match event {
+                    CaptureEvent::Disconnected { .. } | CaptureEvent::Failed(_) => {
+                        warn!("{0}", status)
+                    }
+                    _ => info!("{0}", status),
+                }
+                data.status = CString::new(status).unwrap_or_default();
+            }
+
+            data.since_stats += seconds;
+            if data.since_stats >= STATS_LOG_INTERVAL {
+                data.since_stats = 0.0;
+                if let Some(capture) = &data.capture {
+                    let mut stats = capture.stats();
+                    stats.superseded_frames = data.frames.superseded();
+                    info!("capture stats: {0}", stats);
+                }
+            }
+        }
+    }
+}
+
+impl GetWidthSource<SourceData> for ScreenCastSource {
+    fn get_width(data: &mut Option<SourceData>) -> u32 {
+        data.as_ref()
+            .map(|data| data.size.width.load(Ordering::Relaxed))
+            .unwrap_or(0)
+    }
+}
+
+impl GetHeightSource<SourceData> for ScreenCastSource {
+    fn get_height(data: &mut Option<SourceData>) -> u32 {
+        data.as_ref()
+            .map(|data| data.size.height.load(Ordering::Relaxed))
+            .unwrap_or(0)
+    }
+}
+
+impl ActivateSource<SourceData> for ScreenCastSource {
+    fn activate(data: &mut Option<SourceData>) {
+        if let Some(data) = data {
+            data.active = true;
+            data.update_capturing();
+        }
+    }
+}
+
+impl DeactivateSource<SourceData> for ScreenCastSource {
+    fn deactivate(data: &mut Option<SourceData>) {
+        if let Some(data) = data {
+            data.active = false;
+            data.update_capturing();
+        }
+    }
+}
+
+impl ShowSource<SourceData> for ScreenCastSource {
+    fn show(data: &mut Option<SourceData>) {
+        if let Some(data) = data {
+            data.shown = true;
+            data.update_capturing();
+        }
+    }
+}
+
+impl HideSource<SourceData> for ScreenCastSource {
+    fn hide(data: &mut Option<SourceData>) {
+        if let Some(data) = data {
+            data.shown = false;
+            data.update_capturing();
+        }
+    }
+}
+
+/// Show the capture's `status` at the top of the source's properties.
+fn add_status_text(properties: &mut Properties, status: &CStr) {
+    unsafe {
+        obs_sys::obs_properties_add_text(
+            properties.as_ptr(),
+            obs_string!("status").as_ptr(),
+            status.as_ptr(),
+            obs_sys::obs_text_type_OBS_TEXT_INFO,
+        );
+    }
+}
+
+/// Subscribe to the capture from `capture_source`, starting it on a background
+/// thread if it isn't already running. Frames are copied into the returned
+/// mailbox, and their sizes recorded in `size`. With no `capture_source` the
+/// source is idle, and the mailbox stays empty.
+fn start_capture(
+    source: RawSource,
+    size: &Arc<FrameSize>,
+    capture_source: Option<CaptureSource>,
+    settings: &mut SettingsContext,
+) -> (Option<Subscription>, MailboxReader<ReceivedFrame>) {
+    let (mailbox, frames) = MailboxSink::new();
+    let capture_source = match capture_source {
+        Some(capture_source) => capture_source,
+        None => {
+            size.width.store(0, Ordering::Relaxed);
+            size.height.store(0, Ordering::Relaxed);
+            return (None, frames);
+        }
+    };
+    let sink = ObsSink {
+        source,
+        size: size.clone(),
+        mailbox,
+    };
+    let capture = sessions::subscribe(capture_source, limits_from_settings(settings), sink);
+    (Some(capture), frames)
+}
+
+/// The status shown before the capture has any events of its own.
+fn idle_status(capture_source: &Option<CaptureSource>) -> CString {
+    match capture_source {
+        Some(_) => CString::default(),
+        None => CString::new("Choose a PipeWire node to capture").unwrap_or_default(),
+    }
+}
+
+/// Read where to capture from out of the source's settings. Node capture
+/// without a node chosen captures nothing, rather than asking the portal.
+fn source_from_settings(settings: &mut SettingsContext) -> Option<CaptureSource> {
+    let mode = settings
+        .get_int(obs_string!("capture_mode"))
+        .unwrap_or(MODE_PORTAL);
+    let target = settings
+        .get_str(obs_string!("node_target"))
+        .map(str::trim)
+        .filter(|target| !target.is_empty());
+    match (mode, target) {
+        (MODE_NODE, Some(target)) => Some(CaptureSource::Node {
+            fd: None,
+            target: NodeTarget::from(target),
+        }),
+        (MODE_NODE, None) => None,
+        _ => Some(CaptureSource::Portal {
+            restore_token: settings
+                .get_str(obs_string!("restore_token"))
+                .filter(|token| !token.is_empty())
+                .map(String::from),
+        }),
+    }
+}
+
+/// Save the token for the portal selection in the source's settings, so that
+/// the same selection is captured without prompting next time.
+fn save_restore_token(source: RawSource, token: &str) {
+    if let Ok(token) = CString::new(token) {
+        unsafe {
+            let settings = obs_sys::obs_source_get_settings(source.0);
+            obs_sys::obs_data_set_string(
+                settings,
+                obs_string!("restore_token").as_ptr(),
+                token.as_ptr(),
+            );
+            obs_sys::obs_data_release(settings);
+        }
+    }
+}
+
+/// Start capturing audio from the `target` node on a background thread, with
+/// the audio sent to the OBS `source`.
+fn start_audio(source: RawSource, target: NodeTarget) -> AudioHandle {
+    capture::spawn_audio(target, move |audio| output_audio(&source, audio))
+}
+
+/// Read which node to capture audio from out of the source's settings.
+fn audio_target_from_settings(settings: &mut SettingsContext) -> Option<NodeTarget> {
+    settings
+        .get_str(obs_string!("audio_target"))
+        .map(str::trim)
+        .filter(|target| !target.is_empty())
+        .map(NodeTarget::from)
+}
+
+/// Read whether hidden sources keep capturing out of the source's settings.
+fn keep_capturing_from_settings(settings: &mut SettingsContext) -> bool {
+    settings
+        .get_bool(obs_string!("keep_capturing"))
+        .unwrap_or(false)
+}
+
+/// Read the capture limits, crop and scale from the source's settings. Any
+/// setting which hasn't been set falls back to the default.
+fn limits_from_settings(settings: &mut SettingsContext) -> CaptureLimits {
+    let defaults = CaptureLimits::default();
+    let get = |settings: &mut SettingsContext, name: ObsString, default: u32| {
+        settings
+            .get_int(name)
+            .filter(|value| *value > 0)
+            .map(|value| value as u32)
+            .unwrap_or(default)
+    };
+    CaptureLimits {
+        max_size: (
+            get(settings, obs_string!("max_width"), defaults.max_size.0),
+            get(settings, obs_string!("max_height"), defaults.max_size.1),
+        ),
+        max_framerate: get(settings, obs_string!("max_fps"), defaults.max_framerate),
+        crop: Crop {
+            left: get(settings, obs_string!("crop_left"), 0),
+            top: get(settings, obs_string!("crop_top"), 0),
+            right: get(settings, obs_string!("crop_right"), 0),
+            bottom: get(settings, obs_string!("crop_bottom"), 0),
+        },
+        scale: get(settings, obs_string!("scale"), defaults.scale).min(100),
+        high_bit_depth: settings
+            .get_bool(obs_string!("high_bit_depth"))
+            .unwrap_or(defaults.high_bit_depth),
+    }
+}
+
+/// The size of a source's frames, shared with its capture thread.
+#[derive(Default)]
+struct FrameSize {
+    width: AtomicU32,
+    height: AtomicU32,
+}
+
+/// Raw pointer to the OBS source. OBS allows async video and audio to be
+/// output from any thread, so we can send this to our capture threads.
+#[derive(Clone, Copy)]
+struct RawSource(*mut obs_sys::obs_source_t);
+
+unsafe impl Send for RawSource {}
+
+/// The sink for a source's capture, copying frames into the source's mailbox
+/// for `video_tick` to hand to OBS as async video.
+struct ObsSink {
+    source: RawSource,
+    size: Arc<FrameSize>,
+    mailbox: MailboxSink,
+}
+
+impl FrameSink for ObsSink {
+    fn format_changed(&mut self, format: NegotiatedFormat) {
+        self.size.width.store(format.width, Ordering::Relaxed);
+        self.size.height.store(format.height, Ordering::Relaxed);
+    }
+
+    fn frame(&mut self, frame: &Frame) {
+        self.mailbox.frame(frame)
+    }
+
+    /// Clear the source's video, rather than leave the last frame showing.
+    fn end_of_stream(&mut self) {
+        unsafe {
+            obs_sys::obs_source_output_video(self.source.0, ptr::null());
+        }
+    }
+}
+
+/// Hand a single frame to OBS. OBS copies the frame data before returning,
+/// so the frame's memory can be reused for the next one straight away.
+///
+/// Frames are timestamped with when they arrived from PipeWire, on the
+/// monotonic clock OBS's own clock reads. Audio is timestamped against the
+/// same clock, so the two stay in sync.
+fn output_frame(source: &RawSource, frame: &Frame, timestamp: u64) {
+    let mut obs_frame: obs_sys::obs_source_frame = unsafe { mem::zeroed() };
+
+    for (i, plane) in frame.planes().iter().enumerate() {
+        obs_frame.data[i] = plane.data.as_ptr() as *mut u8;
+        obs_frame.linesize[i] = plane.stride;
+    }
+    obs_frame.width = frame.width();
+    obs_frame.height = frame.height();
+    obs_frame.format = frame.format().obs_video_format();
+    let colorimetry = frame.negotiated().colorimetry;
+    obs_frame.trc = colorimetry.obs_trc();
+    obs_frame.timestamp = timestamp;
+
+    if frame.format().is_yuv() {
+        // OBS only expands the range when told the frame is limited, whatever
+        // the range in its matrix parameters.
+        obs_frame.full_range = colorimetry.range == ColorRange::Full;
+        unsafe {
+            obs_sys::video_format_get_parameters(
+                colorimetry.obs_colorspace(),
+                colorimetry.obs_range(),
+                obs_frame.color_matrix.as_mut_ptr(),
+                obs_frame.color_range_min.as_mut_ptr(),
+                obs_frame.color_range_max.as_mut_ptr(),
+            );
+        }
+    } else {
+        // RGB has no limited range, so is never expanded.
+        obs_frame.full_range = true;
+    }
+
+    unsafe {
+        obs_sys::obs_source_output_video(source.0, &obs_frame);
+    }
+}
+
+/// Hand a buffer of audio to OBS. As with video, OBS copies the data.
+///
+/// The buffer has just finished being filled, so its first sample was
+/// captured one buffer's duration ago.
+fn output_audio(source: &RawSource, audio: &AudioFrame) {
+    let speakers = match speaker_layout(audio.channels()) {
+        Some(speakers) => speakers,
+        None => return,
+    };
+
+    let mut obs_audio: obs_sys::obs_source_audio = unsafe { mem::zeroed() };
+    for (i, plane) in audio.planes().iter().enumerate() {
+        obs_audio.data[i] = plane.as_ptr();
+    }
+    obs_audio.frames = audio.frames();
+    obs_audio.speakers = speakers;
+    obs_audio.format = audio.format().obs_audio_format();
+    obs_audio.samples_per_sec = audio.rate();
+    obs_audio.timestamp =
+        unsafe { obs_sys::os_gettime_ns() }.saturating_sub(audio.duration().as_nanos() as u64);
+
+    unsafe {
+        obs_sys::obs_source_output_audio(source.0, &obs_audio);
+    }
+}
+
+/// Get the OBS speaker layout for the given number of channels. OBS has no
+/// layout for seven channels.
+fn speaker_layout(channels: u32) -> Option<obs_sys::speaker_layout> {
+    Some(match channels {
+        1 => obs_sys::speaker_layout_SPEAKERS_MONO,
+        2 => obs_sys::speaker_layout_SPEAKERS_STEREO,
+        3 => obs_sys::speaker_layout_SPEAKERS_2POINT1,
+        4 => obs_sys::speaker_layout_SPEAKERS_4POINT0,
+        5 => obs_sys::speaker_layout_SPEAKERS_4POINT1,
+        6 => obs_sys::speaker_layout_SPEAKERS_5POINT1,
+        8 => obs_sys::speaker_layout_SPEAKERS_7POINT1,
+        _ => return None,
+    })
+}